@@ -4,83 +4,75 @@
 with a 'cfg::Config'. This type is used to call other functions from
 'rlib', 'tenex', and internal modules.
 */
-use crate::Config;
+use crate::{
+  archive,
+  clean::Plan,
+  config::{check, expand::expand_path, layer, Format, Layer, Layers, Level, Location, Project},
+  download::Download,
+  edit::{self, Editor},
+  init::{self, Prompt},
+  list,
+  output::Output,
+  plugin,
+  script::{self, Script},
+  shell::{self, Listing, Vars},
+  status::{self, Host, Repo, Status},
+  sync::{self, Outcome, Target},
+  uri::ResourceUri,
+  vc::{self, Direction, Vc},
+  web::{
+    files::FileServer,
+    packages::{Package, PackageServer},
+  },
+  Config, Error, Result,
+};
 
 use rlib::{
-  db::{registry::Registry, Error as DbErr},
-  flate,
+  db::registry::Registry,
   kala::{
     cmd::{
-      hg::{hg, hgweb},
-      midi::list_midi_ports,
-      shell::{emacsclient, make},
-      sys::{describe_host, usb_devices},
+      hg::hgweb,
+      shell::make,
     },
-    Error as KErr,
-  },
-  logger::log::{error, info},
-  net::{
-    reqwest::{Client, Url},
-    Error as NetErr,
   },
-  obj::Error,
-  util::{cli::ArgMatches, Result},
+  logger::log::{error, info, warn},
+  net::reqwest::Client,
+  obj::MercurialConfig,
+  util::cli::ArgMatches,
 };
 
-use tenex::{ipapi::get_ip, nws::weather_report};
-
 use std::{
   env,
-  fs::{create_dir, remove_file, File},
+  fs::{self, create_dir_all},
+  net::{SocketAddr, ToSocketAddrs},
   path::{Path, PathBuf},
-  str::FromStr,
+  time::Duration,
 };
 
-/// HTTP file download client
-async fn download<P: AsRef<Path>>(client: &Client, url: Url, path: P) -> Result<(), NetErr> {
-  let res = client.get(url).send().await?;
-  let mut dst = {
-    let fname = res
-      .url()
-      .path_segments()
-      .and_then(|segments| segments.last())
-      .and_then(|name| if name.is_empty() { None } else { Some(name) });
-    let fname = path
-      .as_ref()
-      .join(fname.expect("failed to parse path from url"));
-    File::create(fname)?
-  };
-  let content = res.text().await?;
-  std::io::copy(&mut content.as_bytes(), &mut dst)?;
-  Ok(())
-}
-
 /// shc application
 pub struct App<'a> {
   /// User configuration
   pub cfg: Config,
+  /// Configuration layers `cfg` was resolved from
+  pub layers: Layers,
   /// CLI args
   pub cli: &'a ArgMatches,
+  /// Format of reports printed to stdout
+  pub out: Output,
 }
 
 impl<'a> App<'a> {
   /// Generate a new `App` instance from CLI args
-  pub fn new(cli: &'a ArgMatches) -> Result<Self, KErr> {
-    // set config
-    let cfg = match cli.value_of("config") {
-      Some(cfg) => {
-        info!("custom cfg: {}", cfg);
-        Config::load(cfg)?
-      }
-      None => {
-        let env = Path::new(&std::env::var("HOME").unwrap()).join(".config/shed/shed.cfg");
-        if env.is_file() {
-          Config::load(env)?
-        } else {
-          Config::new()
-        }
-      }
+  pub fn new(cli: &'a ArgMatches) -> Result<Self> {
+    let checking = matches!(cli.subcommand(),
+                            Some(("config", o)) if o.subcommand_name() == Some("check"));
+    let layers = match Layers::load(cli.value_of("config")) {
+      Ok(l) => l,
+      // 'config check' reports broken files itself
+      Err(_) if checking => Layers::new()?,
+      Err(e) => return Err(e),
     };
+    let cfg = layers.config()?.expanded();
 
     info!("App Config: {:?}", cfg);
 
@@ -95,12 +87,27 @@ impl<'a> App<'a> {
     let shed_path: PathBuf = cfg.path.to_path_buf();
     match shed_path.join("data/log").to_str() {
       Some(p) => {
-        rlib::logger::file(lvl, p, "shc").expect("logger init failed");
+        rlib::logger::file(lvl, p, "shc").map_err(Error::other)?;
       }
-      None => rlib::logger::flexi(lvl).expect("logger init failed"),
+      None => rlib::logger::flexi(lvl).map_err(Error::other)?,
     };
 
-    Ok(App { cfg, cli })
+    // only reports take an output format, pack and unpack have their
+    // own --format
+    let out = match cli.subcommand() {
+      Some(("status", o)) | Some(("run", o)) => o.value_of("format"),
+      _ => None,
+    }
+    .unwrap_or("text")
+    .parse()
+    .map_err(Error::Usage)?;
+
+    Ok(App {
+      cfg,
+      layers,
+      cli,
+      out,
+    })
   }
 
   /// Matches on any subcommands and execute additional methods
@@ -112,280 +119,873 @@ impl<'a> App<'a> {
           if opt.is_present("db") {
             self.init_db()?;
           } else {
-            self.init_cfg()?;
+            self.init_cfg(opt)?;
           }
         }
-        ("build", _) => {
+        ("build", opt) => {
           println!("starting build...");
-          self.build_src().await?
+          self.build_src(opt).await?
         }
         // Status
-        ("status", opt) => {
-          if opt.is_present("sys") {
-            describe_host();
-          }
-          if opt.is_present("usb") {
-            usb_devices(None)?;
-          }
-          if opt.is_present("ip") {
-            get_ip().await?;
-          }
-          if opt.is_present("midi") {
-            list_midi_ports()?;
-          }
-          if opt.is_present("weather") {
-            weather_report(41.3557, -72.0995).await?;
-          }
-          if opt.is_present("vc") {
-            match opt.value_of("input") {
-              Some(i) => {
-                let cd = env::current_dir()?;
-                env::set_current_dir(&Path::new(i))?;
-                if opt.is_present("remote") {
-                  hg(&["summary", "--remote"]).await?;
-                  hg(&["status"]).await?;
-                } else {
-                  hg(&["summary"]).await?;
-                  hg(&["status"]).await?;
-                }
-                env::set_current_dir(cd)?;
-              }
-              None => {
-                if opt.is_present("remote") {
-                  hg(&["summary", "--remote"]).await?;
-                  hg(&["status"]).await?;
-                } else {
-                  hg(&["summary"]).await?;
-                  hg(&["status"]).await?;
-                }
-              }
-            }
-          }
-        }
+        ("status", opt) => self.status(opt).await?,
         // Version Control
-        ("push", _opt) => {
-          hg(&["push"]).await?;
-        }
-        ("pull", _opt) => {
-          hg(&["pull"]).await?;
+        ("push", opt) => self.sync(opt, Direction::Push, opt.value_of("to")).await?,
+        ("pull", opt) => {
+          self
+            .sync(opt, Direction::Pull, opt.value_of("input"))
+            .await?
         }
         // Networking
         ("download", opt) => {
-          match opt.value_of("input") {
-            Some(i) => {
-	      // NOTE: splitting on ':' generally isn't a good idea,
-	      // it splits common values like 'https://site.com' into
-	      // ['https', '//site.com']. Don't forget to account for
-	      // this in the parsing function!
-              let s: Vec<&str> = i.split(":").collect(); 
-              info!("downloading {} from {}...", s[1], s[0]);
-              self.dl(s[0], s[1]).await?;
-            }
-            None => {
-              error!("an object URI is required!");
-            }
-          };
+          let uri: ResourceUri = opt.value_of("input").unwrap_or_default().parse()?;
+          info!("downloading {}...", uri);
+          self.dl(&uri, opt.value_of("sha256")).await?;
         }
         ("serve", opt) => {
           println!("starting server...");
-          if let Some(p) = opt.value_of("package") {
-            println!("{:#?}", p);
-          }
-          self.serve().await?;
+          self.serve(opt).await?;
         }
         // Compression
-        ("pack", opt) => {
-          let (i, o) = (
-            opt.value_of("input").unwrap(),
-            opt.value_of("output").unwrap(),
-          );
-          if Path::new(i).is_dir() {
-            let o = if o.eq(".") {
-              format!("{}.{}", i, "tz")
-            } else {
-              o.to_owned()
-            };
-            info!("packing: {} => {} ", i, o);
-            flate::pack(i, o, None);
-          } else if Path::new(i).is_file() {
-            let o = if o.eq(".") {
-              format!("{}.{}", i, "z")
-            } else {
-              o.to_owned()
-            };
-            info!("compressing file: {} => {} ", i, o);
-            flate::compress(i, o)?;
-          }
-        }
-        ("unpack", opt) => {
-          let (i, o) = (
-            &opt.value_of("input").unwrap(),
-            &opt.value_of("output").unwrap(),
-          );
-          if Path::new(i).is_file() {
-            println!("unpacking: {} => {} ", i, o);
-            if opt.is_present("replace") {
-              flate::unpack_replace(i, o);
-            } else {
-              flate::unpack(i, o);
-            }
-          }
-        }
-        ("edit", _) => self.edit().await?,
-        ("clean", _opt) => {}
-        (&_, _) => {
-          error!("cmd not found");
-        }
+        ("pack", opt) => self.pack(opt)?,
+        ("unpack", opt) => self.unpack(opt)?,
+        ("list", opt) => self.list(opt)?,
+        ("config", opt) => self.config(opt)?,
+        ("edit", opt) => self.edit(opt)?,
+        ("clean", opt) => self.clean(opt)?,
+        ("test", opt) => self.test(opt)?,
+        ("run", opt) => self.run(opt)?,
+        (name, opt) => self.plugin(name, opt)?,
       }
     }
     Ok(())
   }
 
   /// Create the `shed` directory tree
-  pub fn build_dirs(self) -> Result<()> {
-    let base = &self.cfg.path;
-    create_dir(base)?;
-    for i in ["stash", "store", "src", "lab", "data", "data/log"] {
-      create_dir(base.join(i))?;
+  pub fn build_dirs(&self) -> Result<()> {
+    for d in init::build_tree(&self.cfg.path)? {
+      println!("created {}", d.display());
     }
     Ok(())
   }
 
-  /// Build source code
-  pub async fn build_src(&'a self) -> Result<(), KErr> {
-    make(vec![self.cli.value_of("target").unwrap()]).await?;
-    Ok(())
+  /// Build source code. Each package given with `-p` (or the package
+  /// containing CWD) runs its build script with `target` as extra
+  /// args, otherwise `target` is passed to `make`.
+  pub async fn build_src(&'a self, opt: &ArgMatches) -> Result<()> {
+    let cfg = &self.cfg;
+    let targets: Vec<&str> = opt
+      .values_of("target")
+      .map(|v| v.collect())
+      .unwrap_or_default();
+    let pkgs: Vec<Project> = match opt.values_of("pkg") {
+      Some(names) => names
+        .map(|n| {
+          cfg
+            .src
+            .iter()
+            .find(|p| p.name == n)
+            .map(Project::Src)
+            .ok_or_else(|| Error::Usage(format!("package '{}' not found", n)))
+        })
+        .collect::<Result<_, _>>()?,
+      None => {
+        let cwd = env::current_dir()?;
+        cfg
+          .projects()
+          .find(|p| cwd.starts_with(p.dir(&cfg.path)))
+          .into_iter()
+          .collect()
+      }
+    };
+    if pkgs.is_empty() {
+      make(targets).await.map_err(Error::script)?;
+      return Ok(());
+    }
+    let logs = cfg.path.join("data/log/build");
+    create_dir_all(&logs)?;
+    let reports: Vec<_> = pkgs
+      .iter()
+      .map(|p| {
+        let log = logs.join(format!("{}.log", p.name()));
+        script::run_logged(cfg, p, Script::Build, &targets, log)
+      })
+      .collect();
+    script::summary(&reports);
+    match reports.iter().filter(|r| r.failed()).count() {
+      0 => Ok(()),
+      n => Err(Error::Script(format!(
+        "{} of {} packages failed to build",
+        n,
+        reports.len()
+      ))),
+    }
   }
 
   /// Initialize a configuration from cli
-  pub fn init_cfg(&'a self) -> Result<()> {
-    let p: PathBuf = match self.cli.value_of("path") {
-      Some(p) => p.into(),
-      None => "~/.config/shed.cfg".into(),
-    };
-    if !p.exists() {
-      println!("writing shed.cfg to {}...", p.display());
-      match self.cli.value_of("fmt") {
-        Some("ron") | None => self.cfg.write(&p, None)?,
-        Some("json") => self.cfg.write(&p, Some("json"))?,
-        Some("bin") => self.cfg.write(&p, Some("bin"))?,
-        Some(_) => error!("unknown configuration type"),
+  pub fn init_cfg(&'a self, opt: &ArgMatches) -> Result<()> {
+    let p = opt
+      .value_of("path")
+      .map(expand_path)
+      .unwrap_or_else(layer::user_cfg);
+    if p.exists() && !opt.is_present("force") {
+      println!("{} already exists, use -f to override", p.display());
+      return self.build_dirs();
+    }
+    let prompt = Prompt::new(opt.is_present("yes"));
+    // start from the file being replaced, not the merged layers, so
+    // system values and SHED_* overrides stay out of it. Paths are
+    // prompted for as written, not as expanded.
+    let seed = match Config::load(&p) {
+      Ok(c) => c,
+      Err(e) => {
+        if p.exists() {
+          warn!("{}, starting from the defaults", e);
+        }
+        Config::new()
       }
-    } else if self.cli.is_present("force") {
-      //  TODO 2021-11-04: overwrite existing config : requires --auto prompt
-    } else {
-      error!("{} already exists, use -f to override", p.display());
+    };
+    let cfg = init::configure(&prompt, seed)?;
+    if !prompt.confirm(&format!("write {}?", p.display()), true)? {
+      return Ok(());
+    }
+    let fmt = opt
+      .value_of("fmt")
+      .map(str::parse)
+      .transpose()
+      .map_err(Error::Usage)?;
+    cfg.write(&p, fmt)?;
+    for d in init::build_tree(expand_path(&cfg.path))? {
+      println!("created {}", d.display());
     }
     Ok(())
   }
 
   /// Initialize the database
-  pub fn init_db(&self) -> Result<(), DbErr> {
+  pub fn init_db(&self) -> Result<()> {
     let db_path: PathBuf = self.cfg.path.clone().join("data/db");
-    std::fs::remove_dir_all(&db_path)?;
-    Registry::new(&db_path)?;
+    if db_path.exists() {
+      std::fs::remove_dir_all(&db_path)?;
+    }
+    Registry::new(&db_path).map_err(Error::ipc)?;
     Ok(())
   }
 
-  /// Open $EDITOR
-  pub async fn edit(&'a self) -> Result<(), Error> {
-    let input = self.cli.value_of("input").unwrap();
-    if input.eq("cfg") {
-      let cfg = option_env!("SHED_CFG").unwrap();
-      emacsclient(vec!["-t", cfg]).await.unwrap();
+  /// Open a path or named target in the configured editor
+  pub fn edit(&self, opt: &ArgMatches) -> Result<()> {
+    let input = opt.value_of("input").unwrap_or(".");
+    let path = edit::target(&self.cfg, self.layers.target(), input);
+    Editor::resolve(&self.cfg.usr.shell.env).open(&path)
+  }
+
+  /// List shed resources, or summarize every section when no input
+  /// is given
+  pub fn list(&self, opt: &ArgMatches) -> Result<()> {
+    match opt.value_of("input") {
+      Some(i) => list::print(&list::list(&self.cfg, i.parse().map_err(Error::Usage)?)?),
+      None => list::print_stats(&self.cfg)?,
+    }
+    Ok(())
+  }
+
+  /// Collect and print the requested status sections
+  pub async fn status(&self, opt: &ArgMatches) -> Result<()> {
+    let mut st = Status::default();
+    if opt.is_present("sys") {
+      st.host = Some(Host::collect());
+    }
+    if opt.is_present("usb") {
+      st.usb = Some(status::usb_devices()?);
+    }
+    if opt.is_present("ip") {
+      st.ip = Some(status::ip_info().await?);
+    }
+    if opt.is_present("midi") {
+      st.midi = Some(status::midi_ports()?);
+    }
+    if opt.is_present("weather") {
+      let at = self
+        .location(opt.value_of("at"), opt.is_present("save"))
+        .await?;
+      let fetch = status::weather(at.lat, at.lon);
+      st.weather = Some(if opt.is_present("cached") {
+        status::weather_cached(
+          at.lat,
+          at.lon,
+          &self.cfg.path.join("data"),
+          Duration::from_secs(self.cfg.weather.ttl),
+          fetch,
+        )
+        .await?
+      } else {
+        fetch.await?
+      });
+    }
+    if opt.is_present("vc") {
+      let path = match opt.value_of("input") {
+        Some(i) => PathBuf::from(i),
+        None => env::current_dir()?,
+      };
+      st.vc = Some(Repo::collect(path, opt.is_present("remote"))?);
+    }
+    if opt.is_present("all") {
+      // --behind needs to know about incoming changes
+      let remote = opt.is_present("remote") || opt.is_present("behind");
+      let jobs = opt
+        .value_of("jobs")
+        .unwrap_or("8")
+        .parse()
+        .map_err(Error::usage)?;
+      let repos: Vec<(String, PathBuf)> = self
+        .cfg
+        .projects()
+        .map(|p| (p.name().to_owned(), p.dir(&self.cfg.path)))
+        .filter(|(_, d)| d.is_dir())
+        .collect();
+      let repos = status::collect_all(repos, remote, jobs)
+        .await
+        .into_iter()
+        .filter(|r| !opt.is_present("dirty") || r.is_dirty())
+        .filter(|r| !opt.is_present("behind") || r.is_behind())
+        .collect();
+      st.repos = Some(repos);
+    }
+    println!("{}", self.out.render(&st)?.trim_end());
+    Ok(())
+  }
+
+  /// Resolve the weather location from `at` (a `lat,lon` pair or a
+  /// name from `weather.locations`), `usr.geo` or `weather.home`, in
+  /// that order. Without any of them the location of our public IP is
+  /// used, and saved as `weather.home` with `save`.
+  async fn location(&self, at: Option<&str>, save: bool) -> Result<Location> {
+    let w = &self.cfg.weather;
+    if let Some(at) = at {
+      return match w.locations.get(at) {
+        Some(l) => Ok(*l),
+        None => at.parse().map_err(Error::Usage),
+      };
+    }
+    if let Some(l) = Location::of_user(&self.cfg.usr).or(w.home) {
+      return Ok(l);
+    }
+    let ip = status::ip_info().await?;
+    let l = match (ip.number("latitude"), ip.number("longitude")) {
+      (Some(lat), Some(lon)) => Location { lat, lon },
+      _ => return Err(Error::net("no location for our public IP")),
+    };
+    if save {
+      self.set_field("weather.home", serde_json::to_value(l)?)?;
+    } else {
+      eprintln!("using {} from our public IP, pass --save to keep it", l);
+    }
+    Ok(l)
+  }
+
+  /// Push or pull the selected projects, or the repo we're in, and
+  /// print a summary. Fails if any repo did, with `Error::Dirty` when
+  /// the only trouble was uncommitted changes.
+  pub async fn sync(&self, opt: &ArgMatches, dir: Direction, dest: Option<&str>) -> Result<()> {
+    let cfg = &self.cfg;
+    let jobs = opt
+      .value_of("jobs")
+      .unwrap_or("8")
+      .parse()
+      .map_err(Error::usage)?;
+    // push has no --force, uncommitted changes never stop it
+    let force = dir == Direction::Pull && opt.is_present("force");
+    let target = |name: &str, path| Target {
+      force,
+      ..Target::new(cfg, name, path, dest)
+    };
+    let targets: Vec<Target> = if opt.is_present("all") {
+      cfg
+        .projects()
+        .map(|p| (p.name(), p.dir(&cfg.path)))
+        .filter(|(_, d)| d.is_dir())
+        .map(|(n, d)| target(n, d))
+        .collect()
+    } else if let Some(names) = opt.values_of("pkg") {
+      names
+        .map(|n| match cfg.project(n) {
+          Some(p) => Ok(target(n, p.dir(&cfg.path))),
+          None => Err(Error::Usage(format!("project '{}' not found", n))),
+        })
+        .collect::<Result<_>>()?
     } else {
-      emacsclient(vec!["-t", input]).await.unwrap();
+      let cwd = env::current_dir()?;
+      let found = cfg
+        .projects()
+        .map(|p| (p.name(), p.dir(&cfg.path)))
+        .find(|(_, d)| cwd.starts_with(d));
+      match found {
+        Some((n, d)) => vec![target(n, d)],
+        None => {
+          let name = cwd
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+          vec![target(&name, cwd)]
+        }
+      }
+    };
+    let reports = sync::sync_all(targets, dir, jobs).await;
+    sync::summary(&reports);
+    let failed = reports.iter().filter(|r| r.outcome.failed()).count();
+    let dirty: Vec<&PathBuf> = reports
+      .iter()
+      .filter_map(|r| match &r.outcome {
+        Outcome::Dirty(p) => Some(p),
+        _ => None,
+      })
+      .collect();
+    if failed > 0 && failed == dirty.len() {
+      return Err(Error::Dirty(dirty[0].clone()));
+    }
+    if failed > 0 {
+      return Err(Error::Vc(format!(
+        "{} of {} repos failed to {}",
+        failed,
+        reports.len(),
+        dir
+      )));
     }
     Ok(())
   }
 
   /// Clean up shed resources
-  pub async fn clean(&'a self) -> Result<()> {
-    match self.cli.value_of("input") {
-      Some("cfg") => remove_file("~/.config/shed.cfg")?,
-      Some("log") => remove_file(self.cfg.path.join("data/log/shed.log"))?,
-      _ => {
-        for i in self.cfg.src.iter() {
-          println!("not actually removing {}, silly", i.name);
+  pub fn clean(&self, opt: &ArgMatches) -> Result<()> {
+    let cfg = &self.cfg;
+    let mut plan = Plan::new(cfg);
+    let all = opt.is_present("all");
+    let unknown = |kind: &str, i: &str| Error::Usage(format!("{} '{}' not found", kind, i));
+    if all || opt.is_present("project") {
+      match opt.value_of("project") {
+        Some(i) => match cfg.src.iter().find(|p| p.name == i) {
+          Some(p) => {
+            plan.project(Project::Src(p));
+          }
+          None => return Err(unknown("project", i)),
+        },
+        None => cfg.src.iter().for_each(|p| {
+          plan.project(Project::Src(p));
+        }),
+      }
+    }
+    if all || opt.is_present("lab") {
+      match opt.value_of("lab") {
+        Some(i) => match cfg.lab.iter().find(|p| p.name == i) {
+          Some(p) => {
+            plan.project(Project::Lab(p));
+          }
+          None => return Err(unknown("lab", i)),
+        },
+        None => cfg.lab.iter().for_each(|p| {
+          plan.project(Project::Lab(p));
+        }),
+      }
+    }
+    if all || opt.is_present("stash") {
+      plan.contents(cfg.path.join("stash"))?;
+    }
+    if all || opt.is_present("store") {
+      plan.contents(cfg.path.join("store"))?;
+    }
+    let scoped = ["all", "project", "lab", "stash", "store"]
+      .iter()
+      .any(|i| opt.is_present(i));
+    if !scoped {
+      match opt.value_of("input") {
+        Some(i) => {
+          plan.project(cfg.project(i).ok_or_else(|| unknown("project", i))?);
+        }
+        None => {
+          let cwd = env::current_dir()?;
+          match cfg.projects().find(|p| cwd.starts_with(p.dir(&cfg.path))) {
+            Some(p) => {
+              plan.project(p);
+            }
+            None => {
+              plan.contents(cfg.path.join("stash/tmp"))?;
+            }
+          }
+        }
+      }
+    }
+    if plan.is_empty() {
+      println!("nothing to clean");
+    } else if opt.is_present("dry_run") {
+      plan.print();
+    } else {
+      if plan.removes() && !opt.is_present("yes") {
+        plan.print();
+        if !Prompt::new(false).confirm("delete these?", false)? {
+          return Err(Error::Usage(
+            "nothing deleted, pass --yes to skip this question".to_owned(),
+          ));
         }
       }
+      plan.execute()?;
     }
     Ok(())
   }
 
-  /// Start an external server
-  pub async fn serve(&'a self) -> Result<()> {
-    match self.cli.value_of("engine") {
+  /// Run project test scripts and fail if any of them fail
+  pub fn test(&self, opt: &ArgMatches) -> Result<()> {
+    let cfg = &self.cfg;
+    let projects: Vec<Project> = if opt.is_present("all") {
+      cfg.projects().collect()
+    } else {
+      match opt.value_of("input") {
+        Some(i) => vec![cfg
+          .project(i)
+          .ok_or_else(|| Error::Usage(format!("project '{}' not found", i)))?],
+        None => {
+          let cwd = env::current_dir()?;
+          cfg
+            .projects()
+            .find(|p| cwd.starts_with(p.dir(&cfg.path)))
+            .into_iter()
+            .collect()
+        }
+      }
+    };
+    if projects.is_empty() {
+      println!("nothing to test");
+      return Ok(());
+    }
+    let reports: Vec<_> = projects
+      .iter()
+      .map(|p| script::run(cfg, p, Script::Test))
+      .collect();
+    script::summary(&reports);
+    match reports.iter().filter(|r| r.failed()).count() {
+      0 => Ok(()),
+      n => Err(Error::Script(format!(
+        "{} of {} projects failed",
+        n,
+        reports.len()
+      ))),
+    }
+  }
+
+  /// Run a command from `usr.shell.cmds`, or list them without a name
+  pub fn run(&self, opt: &ArgMatches) -> Result<()> {
+    let cfg = &self.cfg;
+    let cmds = &cfg.usr.shell.cmds;
+    let name = match opt.value_of("name") {
+      Some(n) => n,
+      None => {
+        let ls = Listing(cmds.iter().map(|(k, v)| (k.clone(), v.clone())).collect());
+        println!("{}", self.out.render(&ls)?.trim_end());
+        return Ok(());
+      }
+    };
+    let cmd = cmds
+      .get(name)
+      .ok_or_else(|| Error::Usage(format!("no command '{}' in usr.shell.cmds", name)))?;
+    let project = match opt.value_of("project") {
+      Some(p) => Some(
+        cfg
+          .project(p)
+          .ok_or_else(|| Error::Usage(format!("project '{}' not found", p)))?,
+      ),
+      None => {
+        let cwd = env::current_dir()?;
+        cfg.projects().find(|p| cwd.starts_with(p.dir(&cfg.path)))
+      }
+    };
+    let dir = project.map(|p| p.dir(&cfg.path));
+    let args: Vec<&str> = opt
+      .values_of("args")
+      .map(|v| v.collect())
+      .unwrap_or_default();
+    let vars = Vars {
+      project: dir.as_deref(),
+      shed: Some(&cfg.path),
+    };
+    let line = shell::expand(cmd, &args, vars)?;
+    let sh = shell::program(cfg);
+    info!("{}: {}", name, line);
+    let status = shell::command(sh, &line)
+      .envs(&cfg.usr.shell.env)
+      .status()
+      .map_err(|e| Error::Script(format!("{}: can't run {}: {}", name, sh, e)))?;
+    match status.code() {
+      Some(0) => Ok(()),
+      Some(c) => Err(Error::Script(format!("{} exited with {}", name, c))),
+      None => Err(Error::Script(format!("{} was killed", name))),
+    }
+  }
+
+  /// Hand an unknown subcommand to the `shc-<name>` plugin
+  pub fn plugin(&self, name: &str, opt: &ArgMatches) -> Result<()> {
+    let dirs = plugin::dirs(Some(&self.cfg.path), env::var_os("PATH").as_deref());
+    let path = plugin::which(name, &dirs)
+      .ok_or_else(|| Error::Usage(format!("unknown command '{}'", name)))?;
+    let args: Vec<&str> = opt
+      .values_of("args")
+      .or_else(|| opt.values_of(""))
+      .map(|v| v.collect())
+      .unwrap_or_default();
+    info!("running plugin {}", path.display());
+    plugin::exec(&path, &args, self.layers.target(), &self.cfg.path)
+  }
+
+  /// Inspect and change individual configuration fields
+  pub fn config(&self, opt: &ArgMatches) -> Result<()> {
+    match opt.subcommand() {
+      Some(("get", opt)) => match opt.value_of("key") {
+        Some(k) => {
+          let (v, ls) = self
+            .layers
+            .get(k)
+            .ok_or_else(|| Error::Usage(format!("no such config field: {}", k)))?;
+          let ls: Vec<String> = ls.iter().map(|l| l.to_string()).collect();
+          println!("{} = {} ({})", k, v, ls.join(", "));
+        }
+        None => {
+          for (k, v, l) in self.layers.leaves() {
+            println!("{} = {} ({})", k, v, l);
+          }
+        }
+      },
+      Some(("set", opt)) => {
+        let (k, v) = (opt.value_of("key").unwrap(), opt.value_of("value").unwrap());
+        let cur = self.layers.get(k).map(|(v, _)| v);
+        self.set_field(k, layer::parse_value(v, cur))?;
+      }
+      Some(("check", opt)) => self.check(opt.value_of("input"))?,
+      Some(("convert", opt)) => {
+        let i = opt.value_of("input").unwrap();
+        let o = opt.value_of("output").unwrap_or(i);
+        let fmt: Format = opt.value_of("fmt").unwrap().parse().map_err(Error::Usage)?;
+        match Format::from_ext(o) {
+          Some(f) if f != fmt => {
+            return Err(Error::Usage(format!(
+              "{} has a .{} extension, not .{}",
+              o, f, fmt
+            )));
+          }
+          _ => Config::load(i)?.write(o, Some(fmt))?,
+        }
+      }
+      _ => (),
+    }
+    Ok(())
+  }
+
+  /// Set the dotted `key` to `v` in the config file written by
+  /// `config set`, creating it when missing. The result must still
+  /// deserialize into a `Config`, and only the fields the file had
+  /// plus the one set are written back.
+  fn set_field(&self, key: &str, v: serde_json::Value) -> Result<()> {
+    let path = self.layers.target();
+    let (cfg, mut fields) = if path.is_file() {
+      (Config::load(path)?, layer::fields(path)?)
+    } else {
+      (Config::new(), vec![])
+    };
+    let mut tree = serde_json::to_value(cfg)?;
+    layer::set(&mut tree, self.layers.defaults(), key, v)?;
+    let cfg: Config = serde_json::from_value(tree)
+      .map_err(|e| Error::Config(format!("invalid value for {}: {}", key, e)))?;
+    // fields the defaults can't vouch for are dropped when unknown
+    if layer::lookup(&serde_json::to_value(&cfg)?, key).is_none() {
+      return Err(Error::Usage(format!("no such config field: {}", key)));
+    }
+    let fmt = if path.is_file() {
+      Some(Format::detect(path)?)
+    } else {
+      None
+    };
+    let top = key.split('.').next().unwrap_or(key);
+    if !fields.iter().any(|f| f == top) {
+      fields.push(top.to_owned());
+    }
+    cfg.write_fields(path, fmt, &fields)
+  }
+
+  /// Validate the config files in `input`, or every file layer and
+  /// the effective config, printing all problems found
+  pub fn check(&self, input: Option<&str>) -> Result<()> {
+    let files: Vec<PathBuf> = match input {
+      Some(i) => vec![i.into()],
+      None => layer::files(self.cli.value_of("config"))
+        .iter()
+        .filter_map(|l| l.path().map(Path::to_path_buf))
+        .collect(),
+    };
+    let mut parsed = vec![];
+    let mut broken = 0;
+    for f in &files {
+      match Config::load(f) {
+        Ok(c) => parsed.push(c),
+        Err(e) => {
+          println!("error: {}", e);
+          broken += 1;
+        }
+      }
+    }
+    let problems = match (input, broken) {
+      (Some(_), 0) => check::check(&parsed.remove(0).expanded()),
+      // semantic checks only make sense once every layer parses
+      (None, 0) => check::check(&self.cfg),
+      _ => vec![],
+    };
+    for p in &problems {
+      match self.layers.get(&p.field) {
+        Some((_, ls)) if input.is_none() && ls != [&Layer::Default] => {
+          let ls: Vec<String> = ls.iter().map(|l| l.to_string()).collect();
+          println!("{} ({})", p, ls.join(", "))
+        }
+        _ => println!("{}", p),
+      }
+    }
+    let errors = broken + problems.iter().filter(|p| p.level == Level::Error).count();
+    let warnings = problems.len() + broken - errors;
+    match errors {
+      0 => {
+        println!("config ok, {} warnings", warnings);
+        Ok(())
+      }
+      n => Err(Error::Config(format!("{} errors, {} warnings", n, warnings))),
+    }
+  }
+
+  /// Start the server selected with `--engine`
+  pub async fn serve(&'a self, opt: &ArgMatches) -> Result<()> {
+    if let Some(names) = opt.values_of("package") {
+      return self.serve_packages(names.collect()).await;
+    }
+    match opt.value_of("engine") {
       Some("hg") => {
-        hgweb(&self.cfg.hg).await?;
+        hgweb(&self.cfg.hg).await.map_err(Error::net)?;
         Ok(())
       }
       Some("dm") => Ok(println!("waiting for dm...")),
+      Some("ftp") => {
+        let mut files = FileServer::new(&self.cfg.path);
+        if opt.is_present("upload") {
+          files = files.upload(self.cfg.path.join("stash/inbox"));
+        }
+        if let Some(mb) = opt.value_of("max-upload") {
+          let mb: u64 = mb.parse().map_err(Error::usage)?;
+          files = files.limit(mb << 20);
+        }
+        files.serve(self.socket()?).await
+      }
       Some(_) | None => Ok(error!("unrecognized server type!")),
     }
   }
+  /// Serve only the `src` packages in `names`. Mercurial packages are
+  /// also served by hgweb on `hg.web.socket`, with no other repos.
+  async fn serve_packages(&self, names: Vec<&str>) -> Result<()> {
+    let mut pkgs = vec![];
+    for n in names {
+      let p = self
+        .cfg
+        .src
+        .iter()
+        .find(|p| p.name == n)
+        .ok_or_else(|| Error::Usage(format!("unknown package {}", n)))?;
+      let dir = Project::Src(p).dir(&self.cfg.path);
+      if !dir.is_dir() {
+        return Err(Error::Usage(format!(
+          "package {} not found at {}",
+          n,
+          dir.display()
+        )));
+      }
+      pkgs.push(Package {
+        name: n.to_owned(),
+        dir,
+      });
+    }
+    let repos: serde_json::Map<String, serde_json::Value> = pkgs
+      .iter()
+      .filter(|p| vc::detect(&p.dir).map(|b| b.vc()) == Some(Vc::Hg))
+      .map(|p| (p.name.clone(), p.dir.to_string_lossy().into()))
+      .collect();
+    let server = PackageServer::new(pkgs, self.cfg.path.join("stash/tmp"));
+    if repos.is_empty() {
+      return server.serve(self.socket()?).await;
+    }
+    let mut hg = serde_json::to_value(&self.cfg.hg)?;
+    hg["web"]["paths"] = repos.into();
+    let hg: MercurialConfig = serde_json::from_value(hg).map_err(Error::config)?;
+    let port = hg
+      .web
+      .socket
+      .to_string()
+      .to_socket_addrs()
+      .ok()
+      .and_then(|mut a| a.next())
+      .map_or(0, |a| a.port());
+    let server = if port == 0 {
+      info!("hg.web.socket has no fixed port, not linking to hgweb");
+      server
+    } else {
+      server.hgweb(port)
+    };
+    futures::try_join!(
+      async { hgweb(&hg).await.map_err(Error::net) },
+      server.serve(self.socket()?)
+    )?;
+    Ok(())
+  }
+  /// The address `net.socket` names
+  fn socket(&self) -> Result<SocketAddr> {
+    let socket = self.cfg.net.socket.to_string();
+    socket
+      .to_socket_addrs()
+      .ok()
+      .and_then(|mut a| a.next())
+      .ok_or_else(|| Error::Config(format!("net.socket {} is not an address", socket)))
+  }
+  /// Pack a file or directory, in the format given by `--format`, the
+  /// output extension, or `tz` for directories and `z` for files
+  pub fn pack(&self, opt: &ArgMatches) -> Result<()> {
+    let (i, o) = (
+      opt
+        .value_of("input")
+        .ok_or_else(|| Error::Usage("nothing to pack".to_owned()))?,
+      opt.value_of("output").unwrap(),
+    );
+    let input = Path::new(i);
+    if !input.exists() {
+      return Err(Error::Usage(format!("{} not found", i)));
+    }
+    let fmt = match opt.value_of("format") {
+      Some(f) => f.parse().map_err(Error::Usage)?,
+      None => archive::Format::from_ext(o).unwrap_or(if input.is_dir() {
+        archive::Format::Tz
+      } else {
+        archive::Format::Z
+      }),
+    };
+    let o = if o.eq(".") {
+      format!("{}.{}", i.trim_end_matches('/'), fmt)
+    } else {
+      o.to_owned()
+    };
+    let filter = archive::Filter {
+      exclude: opt
+        .values_of("exclude")
+        .map(|v| v.map(str::to_owned).collect())
+        .unwrap_or_default(),
+      vc_ignore: !opt.is_present("no_ignore"),
+    };
+    if !filter.exclude.is_empty() && fmt == archive::Format::Z {
+      return Err(Error::Usage(format!(
+        "{} archives can't exclude files",
+        fmt
+      )));
+    }
+    info!("packing: {} => {} ", i, o);
+    archive::pack(input, Path::new(&o), fmt, &filter)
+  }
+  /// Unpack an archive, detecting its format unless `--format` is given
+  pub fn unpack(&self, opt: &ArgMatches) -> Result<()> {
+    let (i, o) = (
+      opt
+        .value_of("input")
+        .ok_or_else(|| Error::Usage("nothing to unpack".to_owned()))?,
+      opt.value_of("output").unwrap(),
+    );
+    let input = Path::new(i);
+    if !input.is_file() {
+      return Err(Error::Usage(format!("{} is not a file", i)));
+    }
+    let fmt = match opt.value_of("format") {
+      Some(f) => f.parse().map_err(Error::Usage)?,
+      None => archive::Format::detect(input)?
+        .ok_or_else(|| Error::Archive(format!("{} is not a known archive format", i)))?,
+    };
+    if opt.is_present("list") {
+      for item in archive::list(input, fmt)? {
+        match item.kind {
+          archive::Kind::Dir => println!("{:>8} {}/", "", item.name),
+          archive::Kind::Link(to) => println!("{:>8} {} -> {}", "", item.name, to.display()),
+          archive::Kind::File { .. } => println!("{:>8} {}", list::fmt_size(item.size), item.name),
+        }
+      }
+      return Ok(());
+    }
+    let replace = opt.is_present("replace");
+    if replace && fmt == archive::Format::Z {
+      return Err(Error::Usage(format!(
+        "can't check a {} extraction, remove {} yourself",
+        fmt, i
+      )));
+    }
+    if opt.occurrences_of("overwrite") > 0 && fmt == archive::Format::Z {
+      return Err(Error::Usage(format!(
+        "{} files always replace what is there, drop --overwrite",
+        fmt
+      )));
+    }
+    if opt.is_present("verify") {
+      let n = archive::verify(input, fmt)?;
+      println!("verified {} files", n);
+    }
+    let policy = opt
+      .value_of("overwrite")
+      .unwrap_or("never")
+      .parse()
+      .map_err(Error::Usage)?;
+    println!("unpacking: {} => {} ", i, o);
+    let done = archive::unpack(input, Path::new(o), fmt, policy)?;
+    for k in done.kept.iter() {
+      warn!("kept existing {}", k);
+    }
+    if replace {
+      // only consume the input once everything in it is on disk
+      archive::complete(input, Path::new(o), fmt)
+        .map_err(|e| Error::Archive(format!("{}, keeping {}", e, i)))?;
+      fs::remove_file(input)?;
+    }
+    Ok(())
+  }
   /// Download a remote resource
-  pub async fn dl(&self, t: &str, resource: &str) -> Result<(), NetErr> {
+  pub async fn dl(&self, uri: &ResourceUri, sha256: Option<&str>) -> Result<()> {
     let dst = self.cfg.path.join("stash/tmp/");
-    let client = Client::new();
-    match t {
-      "hg" => {
-        let u = format!("https://hg.rwest.io/{}", &resource);
-        if resource.eq(".") {
-          hg(&["pull"]).await?;
-        } else {
-          hg(&["clone", &u, dst.to_str().unwrap()]).await?;
-          println!("repo created at {}", dst.display());
+    match uri {
+      ResourceUri::Hg(r) if r == "." => {
+        let cwd = env::current_dir()?;
+        let b = vc::detect(&cwd)
+          .ok_or_else(|| Error::Vc(format!("{} is not a repository", cwd.display())))?;
+        if b.is_dirty(&cwd) == Some(true) {
+          return Err(Error::Dirty(cwd));
         }
-      }
-      "dm" => println!("sending message to: {}", resource),
-      "drive" => {
-        let auth = &self.cfg.usr.auth;
-        if auth.is_empty() {
-          error!("no AuthConfig!");
-        } else {
-          for i in auth.into_iter() {
-            if i.provider.starts_with("google") || i.oauth.is_some() {
-              println!("found auth: {:?}", i);
-              todo!("handle oauth and send to tenex");
-            }
-          }
+        let status = b.sync(&cwd, Direction::Pull, None).status()?;
+        if !status.success() {
+          return Err(Error::Vc(format!("{} pull failed with {}", b.vc(), status)));
         }
-        todo!("see drive handler above");
-      }
-      "a" => {
-        let u = format!("https://rwest.io/a/{}", &resource);
-        download(&client, Url::from_str(&u).unwrap(), &dst)
-          .await
-          .unwrap();
-      }
-      "y" => {
-        let u = format!("https://rwest.io/y/{}", &resource);
-        download(&client, Url::from_str(&u).unwrap(), &dst)
-          .await
-          .unwrap();
       }
-      "http" => {
-          let u = if &resource[..2] == "//" {
-	    format!("http:{}", &resource)
-	  } else {
-	    format!("http://{}", &resource)
-	  };
-	
-        download(&client, Url::from_str(&u).unwrap(), &dst)
-          .await
-          .unwrap();
+      ResourceUri::Hg(r) => {
+        let dst = dst.join(r);
+        let u = uri
+          .url()
+          .ok_or_else(|| Error::Usage(format!("{} has no URL", uri)))?;
+        Vc::Hg.backend().clone_repo(u.as_str(), &dst)?;
+        println!("repo created at {}", dst.display());
       }
-      "https" => {
-        let u = format!("https:{}", &resource);
-        download(&client, Url::from_str(&u).unwrap(), &dst)
-          .await
-          .unwrap();
+      ResourceUri::Dm(p) => println!("sending message to: {}", p),
+      ResourceUri::Drive(_) => return Err(Error::usage("drive: downloads are not supported yet")),
+      ResourceUri::A(_) | ResourceUri::Y(_) | ResourceUri::Http(_) => {
+        let u = uri
+          .url()
+          .ok_or_else(|| Error::Usage(format!("{} has no URL", uri)))?;
+        let out = Download::new(u, dst, self.cfg.path.join("stash"))
+          .sha256(sha256)
+          .run(&Client::new())
+          .await?;
+        println!("saved {}", out.display());
       }
-      "ssh" => {
-        println!("requesting resource over ssh: {}", resource);
+      ResourceUri::Ssh(u) => {
+        println!("requesting resource over ssh: {}", u);
       }
-      _ => error!("unrecognized server type {:?}", t),
     }
     Ok(())
   }
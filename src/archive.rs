@@ -0,0 +1,535 @@
+//! archive.rs --- shc pack and unpack
+/*!
+Besides `rlib::flate`'s own `.z` (one file) and `.tz` (a directory)
+formats we read and write the archives other people send us:
+`.tar.gz`, `.tar.zst`, `.tar.xz` and `.zip`.
+
+`pack` takes the format from `--format` or the output extension.
+`unpack` takes it from `--format` or the magic bytes at the start of
+the input. `.z` and `.tz` files are only recognized by their
+extension, since their leading bytes can't be told apart from the
+formats above.
+A `.tz` is a gzipped tarball underneath, so it is written and read
+like a `.tar.gz`, manifest and checks included. Only `.z` files go
+through `rlib::flate` itself.
+
+Tarballs and zips we write are reproducible: entries are sorted by
+name, every mtime is 1980-01-01 (the earliest a zip can hold), owners
+are root and permissions are 644 or 755. The same tree packs to the
+same bytes. The first entry is a `sha256sum` style `SHA256SUMS`
+manifest which `verify` checks the rest against.
+*/
+use crate::{Error, Result};
+
+use flate2::{write::GzEncoder, Compression};
+use rlib::{flate, logger::log::warn};
+use sha2::{Digest, Sha256};
+use xz2::write::XzEncoder;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use std::{
+  collections::BTreeMap,
+  fmt,
+  fs::File,
+  io::{self, Read, Write},
+  path::Path,
+  str::FromStr,
+};
+
+mod extract;
+mod filter;
+pub use extract::{complete, each, list, Extracted, Item, Overwrite};
+pub use filter::{Entry, Filter, Kind, MANIFEST};
+
+/// mtime of every entry in a tarball, 1980-01-01T00:00:00Z
+const MTIME: u64 = 315_532_800;
+
+/// Archive formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+  /// `rlib::flate` single file
+  Z,
+  /// `rlib::flate` directory
+  Tz,
+  TarGz,
+  TarZst,
+  TarXz,
+  Zip,
+}
+
+impl Format {
+  /// The file extension, without the leading dot
+  pub fn ext(self) -> &'static str {
+    match self {
+      Format::Z => "z",
+      Format::Tz => "tz",
+      Format::TarGz => "tar.gz",
+      Format::TarZst => "tar.zst",
+      Format::TarXz => "tar.xz",
+      Format::Zip => "zip",
+    }
+  }
+
+  /// Guess the format from the extension of `path`, including the
+  /// short `.tgz`, `.tzst` and `.txz` forms
+  pub fn from_ext<P: AsRef<Path>>(path: P) -> Option<Format> {
+    let name = path.as_ref().file_name()?.to_str()?.to_ascii_lowercase();
+    [
+      ("tar.gz", Format::TarGz),
+      ("tgz", Format::TarGz),
+      ("tar.zst", Format::TarZst),
+      ("tzst", Format::TarZst),
+      ("tar.xz", Format::TarXz),
+      ("txz", Format::TarXz),
+      ("zip", Format::Zip),
+      ("tz", Format::Tz),
+      ("z", Format::Z),
+    ]
+    .iter()
+    .find(|(ext, _)| {
+      let stem = name.strip_suffix(ext).and_then(|n| n.strip_suffix('.'));
+      matches!(stem, Some(n) if !n.is_empty())
+    })
+    .map(|(_, f)| *f)
+  }
+
+  /// Guess the format from the first bytes of a file
+  pub fn sniff(buf: &[u8]) -> Option<Format> {
+    if buf.starts_with(&[0x1f, 0x8b]) {
+      Some(Format::TarGz)
+    } else if buf.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+      Some(Format::TarZst)
+    } else if buf.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0]) {
+      Some(Format::TarXz)
+    } else if buf.starts_with(b"PK\x03\x04") || buf.starts_with(b"PK\x05\x06") {
+      Some(Format::Zip)
+    } else {
+      None
+    }
+  }
+
+  /// Detect the format of the archive at `path`: `.z` and `.tz` by
+  /// extension, anything else by `Format::sniff` and then extension
+  pub fn detect<P: AsRef<Path>>(path: P) -> io::Result<Option<Format>> {
+    let path = path.as_ref();
+    let ext = Format::from_ext(path);
+    if let Some(f @ (Format::Z | Format::Tz)) = ext {
+      return Ok(Some(f));
+    }
+    let mut buf = Vec::with_capacity(8);
+    File::open(path)?.take(8).read_to_end(&mut buf)?;
+    Ok(Format::sniff(&buf).or(ext))
+  }
+}
+
+impl FromStr for Format {
+  type Err = String;
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    match s {
+      "z" => Ok(Format::Z),
+      "tz" => Ok(Format::Tz),
+      "tar.gz" | "tgz" => Ok(Format::TarGz),
+      "tar.zst" | "tzst" => Ok(Format::TarZst),
+      "tar.xz" | "txz" => Ok(Format::TarXz),
+      "zip" => Ok(Format::Zip),
+      e => Err(format!("unknown archive format {}", e)),
+    }
+  }
+}
+
+impl fmt::Display for Format {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(self.ext())
+  }
+}
+
+/// Pack the file or directory `input` into `output`, skipping what
+/// `filter` leaves out. Tarballs and zips hold `input` under its own
+/// name and carry a `SHA256SUMS` manifest of every file next to them.
+pub fn pack(input: &Path, output: &Path, fmt: Format, filter: &Filter) -> Result<()> {
+  let (i, o) = (
+    input.to_string_lossy().into_owned(),
+    output.to_string_lossy().into_owned(),
+  );
+  match fmt {
+    Format::Z if input.is_file() => return flate::compress(&i, o).map_err(Error::archive),
+    Format::Z => return Err(Error::Usage("z only holds a single file".to_owned())),
+    Format::Tz if !input.is_dir() => return Err(Error::usage("tz only holds a directory")),
+    _ => (),
+  }
+  let name = base_name(input)?;
+  let mut entries = vec![Entry::new(input.to_path_buf(), name.to_owned())?];
+  // a symlinked input is stored as the link, not walked through
+  let prefix = if entries[0].kind == Kind::Dir {
+    for mut e in filter.walk(input)? {
+      e.rel = format!("{}/{}", name, e.rel);
+      entries.push(e);
+    }
+    format!("{}/", name)
+  } else {
+    String::new()
+  };
+  let path = format!("{}{}", prefix, MANIFEST);
+  if entries.iter().any(|e| e.rel == path) {
+    return Err(Error::Usage(format!(
+      "{} is in the way of the manifest, leave it out with -x {}",
+      path, MANIFEST
+    )));
+  }
+  let manifest = (path, manifest(&entries, &prefix)?);
+  match fmt {
+    Format::Tz | Format::TarGz => {
+      let w = GzEncoder::new(File::create(output)?, Compression::default());
+      tar(&entries, &manifest, w)?.finish()?;
+    }
+    Format::TarZst => {
+      let w = zstd::Encoder::new(File::create(output)?, 0)?;
+      tar(&entries, &manifest, w)?.finish()?;
+    }
+    Format::TarXz => {
+      let w = XzEncoder::new(File::create(output)?, 6);
+      tar(&entries, &manifest, w)?.finish()?;
+    }
+    _ => zip(&entries, &manifest, File::create(output)?)?,
+  }
+  Ok(())
+}
+
+/// Unpack the archive `input` into the directory `output`. `.z` files
+/// are unpacked by `rlib::flate`, which replaces what is there
+/// regardless of `policy` and reports nothing back.
+pub fn unpack(input: &Path, output: &Path, fmt: Format, policy: Overwrite) -> Result<Extracted> {
+  match fmt {
+    Format::Z => {
+      let (i, o) = (input.to_string_lossy(), output.to_string_lossy());
+      flate::unpack(&i, &o);
+      Ok(Extracted::default())
+    }
+    _ => extract::extract(input, output, fmt, policy),
+  }
+}
+
+/// Check every file in the tarball or zip `input` against its
+/// manifest, returning the number of files checked. Files missing
+/// from either side fail as well as a wrong digest.
+pub fn verify(input: &Path, fmt: Format) -> Result<usize> {
+  let mut sums = Sums::default();
+  each(input, fmt, |i, r| match i.kind {
+    Kind::File { .. } => sums.add(i.name, r),
+    _ => Ok(()),
+  })?;
+  sums.check()
+}
+
+/// Digests of the files in an archive and the manifest found among
+/// them
+#[derive(Default)]
+struct Sums {
+  files: BTreeMap<String, String>,
+  /// directory prefix of the manifest and its text
+  manifest: Option<(String, String)>,
+}
+
+impl Sums {
+  fn add<R: Read>(&mut self, name: String, mut r: R) -> Result<()> {
+    let name = name.trim_start_matches("./").to_owned();
+    let (dir, file) = match name.rsplit_once('/') {
+      Some((d, f)) => (format!("{}/", d), f),
+      None => (String::new(), name.as_str()),
+    };
+    // the manifest sits at the top or in the one top directory
+    if file == MANIFEST && dir.matches('/').count() <= 1 && self.manifest.is_none() {
+      let mut text = String::new();
+      r.read_to_string(&mut text)?;
+      self.manifest = Some((dir, text));
+    } else {
+      let sum = sha256(r)?;
+      self.files.insert(name, sum);
+    }
+    Ok(())
+  }
+
+  fn check(mut self) -> Result<usize> {
+    let (dir, text) = self
+      .manifest
+      .take()
+      .ok_or_else(|| Error::Archive(format!("no {} manifest", MANIFEST)))?;
+    let mut n = 0;
+    for line in text.lines().filter(|l| !l.trim().is_empty()) {
+      let (sum, path) = line
+        .split_once("  ")
+        .ok_or_else(|| Error::Archive(format!("bad manifest line: {}", line)))?;
+      let path = format!("{}{}", dir, path);
+      match self.files.remove(&path) {
+        None => return Err(Error::Archive(format!("{} is missing", path))),
+        Some(actual) if actual != sum => {
+          return Err(Error::Verify {
+            expected: format!("{} for {}", sum, path),
+            actual,
+          })
+        }
+        Some(_) => n += 1,
+      }
+    }
+    match self.files.keys().next() {
+      Some(extra) => Err(Error::Archive(format!("{} is not in the manifest", extra))),
+      None => Ok(n),
+    }
+  }
+}
+
+/// The `sha256sum` style manifest of the files in `entries`, with
+/// paths relative to `prefix`
+fn manifest(entries: &[Entry], prefix: &str) -> Result<Vec<u8>> {
+  let mut out = String::new();
+  for e in entries {
+    if let Kind::File { .. } = e.kind {
+      let sum = sha256(File::open(&e.path)?)?;
+      let rel = e.rel.strip_prefix(prefix).unwrap_or(&e.rel);
+      out.push_str(&format!("{}  {}\n", sum, rel));
+    }
+  }
+  Ok(out.into_bytes())
+}
+
+/// Hex-encoded SHA-256 digest of everything read from `r`
+fn sha256<R: Read>(mut r: R) -> io::Result<String> {
+  let mut hasher = Sha256::new();
+  io::copy(&mut r, &mut hasher)?;
+  Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Unix mode of an entry, ignoring everything but the executable bit
+fn mode(kind: &Kind) -> u32 {
+  match kind {
+    Kind::Dir | Kind::File { exec: true } => 0o755,
+    Kind::File { exec: false } => 0o644,
+    Kind::Link(_) => 0o777,
+  }
+}
+
+/// Write `entries` as a tarball to `w`, manifest first, returning `w`
+/// so the caller can finish its compression stream
+fn tar<W: Write>(entries: &[Entry], manifest: &(String, Vec<u8>), w: W) -> Result<W> {
+  let mut b = tar::Builder::new(w);
+  let header = |kind: &Kind, size: u64| {
+    let mut h = tar::Header::new_gnu();
+    h.set_entry_type(match kind {
+      Kind::Dir => tar::EntryType::Directory,
+      Kind::File { .. } => tar::EntryType::Regular,
+      Kind::Link(_) => tar::EntryType::Symlink,
+    });
+    h.set_size(size);
+    h.set_mode(mode(kind));
+    h.set_mtime(MTIME);
+    h.set_uid(0);
+    h.set_gid(0);
+    h
+  };
+  let (name, data) = manifest;
+  let mut h = header(&Kind::File { exec: false }, data.len() as u64);
+  b.append_data(&mut h, name, &data[..])?;
+  for e in entries {
+    match &e.kind {
+      Kind::Dir => b.append_data(&mut header(&e.kind, 0), &e.rel, io::empty())?,
+      Kind::File { .. } => {
+        let f = File::open(&e.path)?;
+        let len = f.metadata()?.len();
+        b.append_data(&mut header(&e.kind, len), &e.rel, f.take(len))?
+      }
+      Kind::Link(to) => {
+        let mut h = header(&e.kind, 0);
+        h.set_path(&e.rel)?;
+        h.set_link_name(to)?;
+        h.set_cksum();
+        b.append(&h, io::empty())?
+      }
+    }
+  }
+  Ok(b.into_inner()?)
+}
+
+/// Write `entries` as a zip archive to `w`, manifest first. Zips
+/// can't hold symlinks, so those are skipped.
+fn zip<W: Write + io::Seek>(entries: &[Entry], manifest: &(String, Vec<u8>), w: W) -> Result<()> {
+  let mut z = ZipWriter::new(w);
+  // DateTime::default is 1980-01-01, the same as MTIME
+  let opts = |kind: &Kind| {
+    FileOptions::default()
+      .compression_method(CompressionMethod::Deflated)
+      .last_modified_time(zip::DateTime::default())
+      .unix_permissions(mode(kind))
+  };
+  let (name, data) = manifest;
+  z.start_file(&**name, opts(&Kind::File { exec: false }))
+    .map_err(Error::archive)?;
+  z.write_all(data)?;
+  for e in entries {
+    match &e.kind {
+      Kind::Dir => z
+        .add_directory(&*e.rel, opts(&e.kind))
+        .map_err(Error::archive)?,
+      Kind::File { .. } => {
+        z.start_file(&*e.rel, opts(&e.kind))
+          .map_err(Error::archive)?;
+        io::copy(&mut File::open(&e.path)?, &mut z)?;
+      }
+      Kind::Link(_) => warn!("{}: zip can't hold symlinks, skipped", e.rel),
+    }
+  }
+  z.finish().map_err(Error::archive)?;
+  Ok(())
+}
+
+/// The last component of `path`, which names it inside an archive
+fn base_name(path: &Path) -> Result<&str> {
+  path
+    .file_name()
+    .and_then(|n| n.to_str())
+    .ok_or_else(|| Error::Usage(format!("can't pack {}", path.display())))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::TempDir;
+  use std::fs;
+
+  #[test]
+  fn test_from_ext() {
+    assert_eq!(Format::from_ext("a.tar.gz"), Some(Format::TarGz));
+    assert_eq!(Format::from_ext("/x/v1.2.TAR.ZST"), Some(Format::TarZst));
+    assert_eq!(Format::from_ext("a.txz"), Some(Format::TarXz));
+    assert_eq!(Format::from_ext("shed.tz"), Some(Format::Tz));
+    assert_eq!(Format::from_ext("notes.org.z"), Some(Format::Z));
+    assert_eq!(Format::from_ext("a.zip"), Some(Format::Zip));
+    assert_eq!(Format::from_ext("a.tar"), None);
+    assert_eq!(Format::from_ext("zip"), None);
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let root = TempDir::new("archive");
+    let src = root.join("pkg");
+    fs::create_dir_all(src.join("sub")).unwrap();
+    fs::create_dir_all(src.join("target")).unwrap();
+    fs::write(src.join("a"), "a").unwrap();
+    fs::write(src.join("sub/b"), "b").unwrap();
+    fs::write(src.join("target/c"), "c").unwrap();
+    let filter = Filter {
+      exclude: vec!["target".to_owned()],
+      ..Filter::default()
+    };
+    for fmt in [Format::TarGz, Format::TarZst, Format::TarXz, Format::Zip] {
+      // no extension, so unpack has to sniff
+      let out = root.join(format!("pkg-{:?}", fmt));
+      pack(&src, &out, fmt, &filter).unwrap();
+      assert_eq!(Format::detect(&out).unwrap(), Some(fmt));
+      assert_eq!(verify(&out, fmt).unwrap(), 2);
+      // rewriting a file changes its mtime but not the archive
+      fs::write(src.join("a"), "a").unwrap();
+      let again = root.join(format!("again-{:?}", fmt));
+      pack(&src, &again, fmt, &filter).unwrap();
+      assert_eq!(fs::read(&out).unwrap(), fs::read(&again).unwrap());
+      let dst = root.join(format!("out-{:?}", fmt));
+      unpack(&out, &dst, fmt, Overwrite::Never).unwrap();
+      assert_eq!(fs::read_to_string(dst.join("pkg/sub/b")).unwrap(), "b");
+      assert_eq!(fs::read_to_string(dst.join("pkg/a")).unwrap(), "a");
+      assert!(!dst.join("pkg/target").exists());
+    }
+    // a manifest of our own would be overwritten
+    fs::write(src.join(MANIFEST), "").unwrap();
+    let out = root.join("pkg.zip");
+    assert!(matches!(
+      pack(&src, &out, Format::Zip, &filter),
+      Err(Error::Usage(_))
+    ));
+    let filter = Filter {
+      exclude: vec!["target".to_owned(), MANIFEST.to_owned()],
+      ..Filter::default()
+    };
+    pack(&src, &out, Format::Zip, &filter).unwrap();
+    assert_eq!(verify(&out, Format::Zip).unwrap(), 2);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn test_symlink_loop() {
+    use std::os::unix::fs::symlink;
+    let root = TempDir::new("loop");
+    let src = root.join("pkg");
+    fs::create_dir_all(src.join("sub")).unwrap();
+    fs::write(src.join("sub/a"), "a").unwrap();
+    symlink("..", src.join("sub/up")).unwrap();
+    symlink("pkg", root.join("link")).unwrap();
+    let out = root.join("pkg.tar.gz");
+    pack(&src, &out, Format::TarGz, &Filter::default()).unwrap();
+    let names: Vec<(String, Kind)> = list(&out, Format::TarGz)
+      .unwrap()
+      .into_iter()
+      .map(|i| (i.name, i.kind))
+      .collect();
+    assert!(names.contains(&("pkg/sub/up".to_owned(), Kind::Link("..".into()))));
+    assert_eq!(names.len(), 5);
+    // the link itself is packed, not the tree behind it
+    pack(&root.join("link"), &out, Format::TarGz, &Filter::default()).unwrap();
+    assert_eq!(list(&out, Format::TarGz).unwrap().len(), 2);
+  }
+
+  #[test]
+  fn test_pack_tz() {
+    let root = TempDir::new("tz");
+    let src = root.join("pkg");
+    fs::create_dir_all(src.join(".hg")).unwrap();
+    fs::create_dir_all(src.join("target")).unwrap();
+    fs::write(src.join(".hg/store"), "").unwrap();
+    fs::write(src.join("target/c"), "c").unwrap();
+    fs::write(src.join("a"), "a").unwrap();
+    let filter = Filter {
+      exclude: vec!["target".to_owned()],
+      ..Filter::default()
+    };
+    let (a, b) = (root.join("a.tz"), root.join("b.tz"));
+    pack(&src, &a, Format::Tz, &filter).unwrap();
+    pack(&src, &b, Format::Tz, &filter).unwrap();
+    assert_eq!(fs::read(&a).unwrap(), fs::read(&b).unwrap());
+    assert_eq!(verify(&a, Format::Tz).unwrap(), 1);
+    let names: Vec<String> = list(&a, Format::Tz)
+      .unwrap()
+      .into_iter()
+      .map(|i| i.name)
+      .collect();
+    assert_eq!(names, ["pkg/SHA256SUMS", "pkg", "pkg/a"]);
+    assert!(pack(&src.join("a"), &a, Format::Tz, &filter).is_err());
+  }
+
+  #[test]
+  fn test_check() {
+    let sums = |files: &[(&str, &str)]| {
+      let mut s = Sums::default();
+      s.add("pkg/SHA256SUMS".to_owned(), &b"aa  a\nbb  sub/b\n"[..])
+        .unwrap();
+      for (k, v) in files {
+        s.files.insert(k.to_string(), v.to_string());
+      }
+      s
+    };
+    assert_eq!(
+      sums(&[("pkg/a", "aa"), ("pkg/sub/b", "bb")])
+        .check()
+        .unwrap(),
+      2
+    );
+    assert!(matches!(
+      sums(&[("pkg/a", "aa"), ("pkg/sub/b", "cc")]).check(),
+      Err(Error::Verify { .. })
+    ));
+    assert!(sums(&[("pkg/a", "aa")]).check().is_err());
+    assert!(
+      sums(&[("pkg/a", "aa"), ("pkg/sub/b", "bb"), ("pkg/x", "xx")])
+        .check()
+        .is_err()
+    );
+    assert!(Sums::default().check().is_err());
+  }
+}
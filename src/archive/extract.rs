@@ -0,0 +1,503 @@
+//! archive/extract.rs --- reading tarballs and zips
+/*!
+Extraction never writes outside the output directory. Every name is
+checked before anything is written: absolute paths, `..` components
+and symlinks pointing out of the tree fail the whole archive. While
+writing, a file whose parent resolves outside the output (through a
+symlink already on disk) is refused as well, and so is a symlink
+whose target does once the links it passes through are followed.
+
+Existing files are kept, replaced when the archive's copy is newer or
+always replaced, following `Overwrite`.
+*/
+use super::{Format, Kind, Sums};
+use crate::{Error, Result};
+
+use flate2::read::GzDecoder;
+use rlib::logger::log::warn;
+use xz2::read::XzDecoder;
+use zip::ZipArchive;
+
+use std::{
+  fs::{self, File},
+  io::{self, Read},
+  path::{Component, Path, PathBuf},
+  str::FromStr,
+  time::UNIX_EPOCH,
+};
+
+/// What to do with files which already exist
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overwrite {
+  Never,
+  /// when the archive's mtime is later
+  Newer,
+  Always,
+}
+
+impl FromStr for Overwrite {
+  type Err = String;
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    match s {
+      "never" => Ok(Overwrite::Never),
+      "newer" => Ok(Overwrite::Newer),
+      "always" => Ok(Overwrite::Always),
+      e => Err(format!("unknown overwrite policy {}", e)),
+    }
+  }
+}
+
+/// An entry in an archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+  pub name: String,
+  pub kind: Kind,
+  pub size: u64,
+  /// seconds since the epoch
+  pub mtime: u64,
+}
+
+/// What `extract` did
+#[derive(Debug, Default)]
+pub struct Extracted {
+  pub written: usize,
+  /// existing files left alone
+  pub kept: Vec<String>,
+}
+
+/// Call `f` with every file, directory and symlink in the tarball or
+/// zip `input`, in archive order. Other entry types are skipped.
+pub fn each<F>(input: &Path, fmt: Format, mut f: F) -> Result<()>
+where
+  F: FnMut(Item, &mut dyn Read) -> Result<()>,
+{
+  let file = File::open(input)?;
+  match fmt {
+    Format::Z => Err(Error::Usage(format!(
+      "{} archives can only be unpacked whole",
+      fmt
+    ))),
+    // rlib::flate's directory format is a gzipped tarball
+    Format::Tz | Format::TarGz => each_tar(GzDecoder::new(file), f),
+    Format::TarZst => each_tar(zstd::Decoder::new(file)?, f),
+    Format::TarXz => each_tar(XzDecoder::new(file), f),
+    Format::Zip => {
+      let mut z = ZipArchive::new(file).map_err(Error::archive)?;
+      for i in 0..z.len() {
+        let mut e = z.by_index(i).map_err(Error::archive)?;
+        let mode = e.unix_mode().unwrap_or(0);
+        let kind = if e.is_dir() {
+          Kind::Dir
+        } else if mode & 0o170000 == 0o120000 {
+          let mut to = String::new();
+          e.read_to_string(&mut to)?;
+          Kind::Link(to.into())
+        } else {
+          Kind::File {
+            exec: mode & 0o111 != 0,
+          }
+        };
+        let t = e.last_modified();
+        let item = Item {
+          name: e.name().trim_end_matches('/').to_owned(),
+          kind,
+          size: e.size(),
+          mtime: epoch(
+            t.year().into(),
+            t.month().into(),
+            t.day().into(),
+            u64::from(t.hour()) * 3600 + u64::from(t.minute()) * 60 + u64::from(t.second()),
+          ),
+        };
+        f(item, &mut e)?;
+      }
+      Ok(())
+    }
+  }
+}
+
+fn each_tar<R: Read, F>(r: R, mut f: F) -> Result<()>
+where
+  F: FnMut(Item, &mut dyn Read) -> Result<()>,
+{
+  let mut a = tar::Archive::new(r);
+  for e in a.entries()? {
+    let mut e = e?;
+    let h = e.header();
+    let kind = match h.entry_type() {
+      t if t.is_file() => Kind::File {
+        exec: h.mode()? & 0o111 != 0,
+      },
+      t if t.is_dir() => Kind::Dir,
+      t if t.is_symlink() => Kind::Link(
+        e.link_name()?
+          .ok_or_else(|| Error::Archive("symlink without a target".to_owned()))?
+          .into_owned(),
+      ),
+      t if t.is_pax_global_extensions() || t.is_pax_local_extensions() => continue,
+      t => {
+        warn!("skipping {:?} entry {}", t, e.path()?.display());
+        continue;
+      }
+    };
+    let item = Item {
+      name: e.path()?.to_string_lossy().trim_end_matches('/').to_owned(),
+      kind,
+      size: h.size()?,
+      mtime: h.mtime()?,
+    };
+    f(item, &mut e)?;
+  }
+  Ok(())
+}
+
+/// Every entry in the tarball or zip `input`
+pub fn list(input: &Path, fmt: Format) -> Result<Vec<Item>> {
+  let mut out = vec![];
+  each(input, fmt, |i, _| {
+    out.push(i);
+    Ok(())
+  })?;
+  Ok(out)
+}
+
+/// Extract the tarball or zip `input` into `output`
+pub fn extract(input: &Path, output: &Path, fmt: Format, policy: Overwrite) -> Result<Extracted> {
+  // refuse the whole archive before writing anything
+  for i in list(input, fmt)? {
+    check(&i)?;
+  }
+  fs::create_dir_all(output)?;
+  let root = output.canonicalize()?;
+  let mut done = Extracted::default();
+  each(input, fmt, |item, r| {
+    let dst = root.join(check(&item)?);
+    inside(&root, &dst)?;
+    if item.kind == Kind::Dir {
+      return Ok(fs::create_dir_all(&dst)?);
+    }
+    if let Ok(m) = fs::symlink_metadata(&dst) {
+      let newer = || {
+        let t = m.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(item.mtime > t.as_secs())
+      };
+      let replace = match policy {
+        Overwrite::Always => true,
+        Overwrite::Newer => newer().unwrap_or(true),
+        Overwrite::Never => false,
+      };
+      if !replace || m.is_dir() {
+        done.kept.push(item.name);
+        return Ok(());
+      }
+      // never write through whatever is there now
+      fs::remove_file(&dst)?;
+    }
+    if let Some(p) = dst.parent() {
+      fs::create_dir_all(p)?;
+    }
+    match &item.kind {
+      Kind::Link(to) => {
+        link_inside(&root, &dst, to)?;
+        symlink(to, &dst)?
+      }
+      Kind::File { exec } => {
+        io::copy(r, &mut File::create(&dst)?)?;
+        finish(&dst, *exec, item.mtime)?;
+      }
+      Kind::Dir => unreachable!(),
+    }
+    done.written += 1;
+    Ok(())
+  })?;
+  Ok(done)
+}
+
+/// Check that every file in `input` was extracted to `output`
+/// unchanged, by the archive's manifest when it has one and by size
+/// otherwise
+pub fn complete(input: &Path, output: &Path, fmt: Format) -> Result<usize> {
+  let items = list(input, fmt)?;
+  let mut sums = Sums::default();
+  let mut n = 0;
+  for i in items {
+    if let Kind::File { .. } = i.kind {
+      let dst = output.join(check(&i)?);
+      let len = fs::metadata(&dst)
+        .map_err(|e| Error::Archive(format!("{}: {}", dst.display(), e)))?
+        .len();
+      if len != i.size {
+        return Err(Error::Archive(format!(
+          "{} is {} bytes, expected {}",
+          dst.display(),
+          len,
+          i.size
+        )));
+      }
+      sums.add(i.name, File::open(&dst)?)?;
+      n += 1;
+    }
+  }
+  if sums.manifest.is_some() {
+    sums.check()
+  } else {
+    Ok(n)
+  }
+}
+
+/// The path of `item` below the output, failing when it or the target
+/// of a symlink would leave it. Only a directory may be the output
+/// itself, as `./` often is.
+fn check(item: &Item) -> Result<PathBuf> {
+  let outside = || Error::Archive(format!("{} points outside the output", item.name));
+  let rel = below(Path::new(&item.name))
+    .filter(|r| item.kind == Kind::Dir || !r.as_os_str().is_empty())
+    .ok_or_else(outside)?;
+  if let Kind::Link(to) = &item.kind {
+    let base = rel.parent().unwrap_or_else(|| Path::new(""));
+    below(&base.join(to)).ok_or_else(outside)?;
+  }
+  Ok(rel)
+}
+
+/// `path` with `.` and `..` resolved, when it stays relative and
+/// doesn't climb above its start
+fn below(path: &Path) -> Option<PathBuf> {
+  let mut out = PathBuf::new();
+  for c in path.components() {
+    match c {
+      Component::Normal(p) => out.push(p),
+      Component::CurDir => (),
+      Component::ParentDir if out.pop() => (),
+      _ => return None,
+    }
+  }
+  Some(out)
+}
+
+/// Fail when the nearest existing ancestor of `dst` resolves outside
+/// `root`, which a symlink on disk could make it do
+fn inside(root: &Path, dst: &Path) -> Result<()> {
+  let mut p = dst.parent();
+  while let Some(d) = p {
+    if let Ok(real) = d.canonicalize() {
+      if real.starts_with(root) {
+        return Ok(());
+      }
+      break;
+    }
+    p = d.parent();
+  }
+  Err(Error::Archive(format!(
+    "{} resolves outside {}",
+    dst.display(),
+    root.display()
+  )))
+}
+
+/// Fail when the symlink `dst -> to` would point outside `root`. The
+/// target is resolved from where `dst` really is and through any
+/// links on disk, refusing a `..` after a name that doesn't exist yet
+/// since it may still become a link.
+fn link_inside(root: &Path, dst: &Path, to: &Path) -> Result<()> {
+  let outside = || {
+    Error::Archive(format!(
+      "{} -> {} points outside {}",
+      dst.display(),
+      to.display(),
+      root.display()
+    ))
+  };
+  let mut real = dst.parent().unwrap_or(root).canonicalize()?;
+  for c in to.components() {
+    match c {
+      Component::Normal(p) => {
+        real.push(p);
+        if let Ok(r) = real.canonicalize() {
+          real = r;
+        }
+      }
+      Component::ParentDir if real.exists() => {
+        real.pop();
+      }
+      Component::CurDir => (),
+      _ => return Err(outside()),
+    }
+  }
+  if real.starts_with(root) {
+    Ok(())
+  } else {
+    Err(outside())
+  }
+}
+
+#[cfg(unix)]
+fn symlink(to: &Path, dst: &Path) -> io::Result<()> {
+  std::os::unix::fs::symlink(to, dst)
+}
+
+#[cfg(not(unix))]
+fn symlink(to: &Path, dst: &Path) -> io::Result<()> {
+  warn!("skipping symlink {} -> {}", dst.display(), to.display());
+  Ok(())
+}
+
+/// Set the mode and mtime of an extracted file
+fn finish(dst: &Path, exec: bool, mtime: u64) -> io::Result<()> {
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = if exec { 0o755 } else { 0o644 };
+    fs::set_permissions(dst, fs::Permissions::from_mode(mode))?;
+  }
+  #[cfg(not(unix))]
+  let _ = exec;
+  let t = filetime::FileTime::from_unix_time(mtime as i64, 0);
+  filetime::set_file_mtime(dst, t)
+}
+
+/// Seconds since the epoch of a UTC date and time of day
+fn epoch(y: i64, m: i64, d: i64, secs: u64) -> u64 {
+  // days from civil, http://howardhinnant.github.io/date_algorithms.html
+  let y = if m <= 2 { y - 1 } else { y };
+  let era = if y >= 0 { y } else { y - 399 } / 400;
+  let yoe = y - era * 400;
+  let mp = (m + 9) % 12;
+  let doy = (153 * mp + 2) / 5 + d - 1;
+  let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+  let days = era * 146097 + doe - 719468;
+  (days.max(0) as u64) * 86400 + secs
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::archive::{pack, Filter};
+  use crate::testing::TempDir;
+
+  fn item(name: &str, kind: Kind) -> Item {
+    Item {
+      name: name.to_owned(),
+      kind,
+      size: 0,
+      mtime: 0,
+    }
+  }
+
+  #[test]
+  fn test_check() {
+    let file = Kind::File { exec: false };
+    assert_eq!(
+      check(&item("a/./b", file.clone())).unwrap(),
+      Path::new("a/b")
+    );
+    assert_eq!(
+      check(&item("a/../b", file.clone())).unwrap(),
+      Path::new("b")
+    );
+    assert!(check(&item("../b", file.clone())).is_err());
+    assert!(check(&item("/etc/passwd", file.clone())).is_err());
+    assert!(check(&item("a/../../b", file.clone())).is_err());
+    assert!(check(&item(".", file)).is_err());
+    assert_eq!(check(&item(".", Kind::Dir)).unwrap(), Path::new(""));
+    assert!(check(&item("a/l", Kind::Link("../b".into()))).is_ok());
+    assert!(check(&item("a/l", Kind::Link("../../b".into()))).is_err());
+    assert!(check(&item("a/l", Kind::Link("/etc".into()))).is_err());
+    assert_eq!(epoch(1980, 1, 1, 0), 315_532_800);
+    assert_eq!(epoch(2021, 12, 13, 9000), 1_639_353_600 + 9000);
+  }
+
+  #[test]
+  fn test_extract() {
+    let root = TempDir::new("extract");
+    let src = root.join("pkg");
+    fs::create_dir_all(&src).unwrap();
+    fs::write(src.join("a"), "new").unwrap();
+    fs::write(src.join("b"), "b").unwrap();
+    for fmt in [Format::TarGz, Format::Zip] {
+      let out = root.join(format!("pkg-{:?}", fmt));
+      pack(&src, &out, fmt, &Filter::default()).unwrap();
+      let names: Vec<String> = list(&out, fmt)
+        .unwrap()
+        .into_iter()
+        .map(|i| i.name)
+        .collect();
+      assert_eq!(names, ["pkg/SHA256SUMS", "pkg", "pkg/a", "pkg/b"]);
+      let dst = root.join(format!("out-{:?}", fmt));
+      fs::create_dir_all(dst.join("pkg")).unwrap();
+      fs::write(dst.join("pkg/a"), "old").unwrap();
+      let done = extract(&out, &dst, fmt, Overwrite::Never).unwrap();
+      assert_eq!(done.kept, ["pkg/a"]);
+      assert_eq!(fs::read_to_string(dst.join("pkg/a")).unwrap(), "old");
+      assert!(complete(&out, &dst, fmt).is_err());
+      // the archive's 1980 mtime is older than the file
+      extract(&out, &dst, fmt, Overwrite::Newer).unwrap();
+      assert_eq!(fs::read_to_string(dst.join("pkg/a")).unwrap(), "old");
+      let done = extract(&out, &dst, fmt, Overwrite::Always).unwrap();
+      assert!(done.kept.is_empty());
+      assert_eq!(fs::read_to_string(dst.join("pkg/a")).unwrap(), "new");
+      assert_eq!(complete(&out, &dst, fmt).unwrap(), 2);
+    }
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn test_link_chain() {
+    use flate2::{write::GzEncoder, Compression};
+    let root = TempDir::new("chain");
+    // each name passes `check`, but d/l is the output itself, so
+    // going up from or through it leaves the output
+    let chains: [&[(&str, &str)]; 2] = [
+      &[("d/l", ".."), ("d/l/x", "..")],
+      &[("d/l", ".."), ("y", "d/l/..")],
+    ];
+    for (n, links) in chains.iter().enumerate() {
+      let tgz = root.join(format!("chain-{}.tar.gz", n));
+      let gz = GzEncoder::new(File::create(&tgz).unwrap(), Compression::default());
+      let mut b = tar::Builder::new(gz);
+      for (name, to) in links.iter() {
+        let mut h = tar::Header::new_gnu();
+        h.set_entry_type(tar::EntryType::Symlink);
+        h.set_link_name(to).unwrap();
+        h.set_size(0);
+        b.append_data(&mut h, name, io::empty()).unwrap();
+      }
+      b.into_inner().unwrap().finish().unwrap();
+      let dst = root.join(format!("out-{}", n));
+      assert!(extract(&tgz, &dst, Format::TarGz, Overwrite::Never).is_err());
+      assert!(fs::symlink_metadata(dst.join("x")).is_err());
+      assert!(fs::symlink_metadata(dst.join("y")).is_err());
+    }
+  }
+
+  #[test]
+  fn test_tz() {
+    let root = TempDir::new("tz");
+    let tz = root.join("evil.tz");
+    let mut b = tar::Builder::new(flate2::write::GzEncoder::new(
+      File::create(&tz).unwrap(),
+      flate2::Compression::default(),
+    ));
+    let mut h = tar::Header::new_gnu();
+    // set_path refuses `..`, so write the name by hand
+    h.as_old_mut().name[..8].copy_from_slice(b"../evil\0");
+    h.set_size(1);
+    h.set_cksum();
+    b.append(&h, &b"x"[..]).unwrap();
+    b.into_inner().unwrap().finish().unwrap();
+    let dst = root.join("out");
+    assert!(crate::archive::unpack(&tz, &dst, Format::Tz, Overwrite::Never).is_err());
+    assert!(!root.join("evil").exists());
+
+    let src = root.join("pkg");
+    fs::create_dir_all(&src).unwrap();
+    fs::write(src.join("a"), "new").unwrap();
+    let tz = root.join("pkg.tz");
+    pack(&src, &tz, Format::Tz, &Filter::default()).unwrap();
+    fs::create_dir_all(dst.join("pkg")).unwrap();
+    fs::write(dst.join("pkg/a"), "old").unwrap();
+    let done = crate::archive::unpack(&tz, &dst, Format::Tz, Overwrite::Never).unwrap();
+    assert_eq!(done.kept, ["pkg/a"]);
+    assert_eq!(fs::read_to_string(dst.join("pkg/a")).unwrap(), "old");
+  }
+}
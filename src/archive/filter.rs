@@ -0,0 +1,331 @@
+//! archive/filter.rs --- what pack leaves out
+/*!
+A `Filter` walks a directory in name order, skipping `--exclude`
+globs and, unless turned off, whatever the repo's VC would ignore:
+`.gitignore` files at any depth, the `.hgignore` at the top and the
+`.git` and `.hg` directories themselves.
+
+Globs without a `/` match a name at any depth, like `target` or
+`*.o`. Globs with one are taken from the top of the directory. In a
+`.hgignore`, `glob:` patterns match at any depth and `rootglob:` ones
+from the top, as in hg.
+*/
+use crate::{Error, Result};
+
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+use regex::Regex;
+
+use std::{
+  fs, io,
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+
+/// Name of the manifest `pack` writes at the top of a tarball or zip
+pub const MANIFEST: &str = "SHA256SUMS";
+
+/// What to leave out of an archive
+#[derive(Debug, Clone)]
+pub struct Filter {
+  pub exclude: Vec<String>,
+  /// honor `.gitignore` and `.hgignore`
+  pub vc_ignore: bool,
+}
+
+impl Default for Filter {
+  fn default() -> Self {
+    Filter {
+      exclude: vec![],
+      vc_ignore: true,
+    }
+  }
+}
+
+/// A file, directory or symlink to archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+  pub path: PathBuf,
+  /// path relative to the top of the walk, `/` separated
+  pub rel: String,
+  pub kind: Kind,
+}
+
+impl Entry {
+  /// Describe the file, directory or symlink at `path`
+  pub fn new(path: PathBuf, rel: String) -> io::Result<Self> {
+    let m = fs::symlink_metadata(&path)?;
+    let kind = if m.file_type().is_symlink() {
+      Kind::Link(fs::read_link(&path)?)
+    } else if m.is_dir() {
+      Kind::Dir
+    } else {
+      Kind::File {
+        exec: executable(&m),
+      }
+    };
+    Ok(Entry { path, rel, kind })
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Kind {
+  Dir,
+  File { exec: bool },
+  Link(PathBuf),
+}
+
+impl Filter {
+  /// Every entry below `dir` which isn't filtered out, in name order.
+  /// `dir` itself is not included.
+  pub fn walk(&self, dir: &Path) -> Result<Vec<Entry>> {
+    let skip = Arc::new(Skip {
+      exclude: globs(self.exclude.iter().map(String::as_str))?,
+      hg: if self.vc_ignore {
+        HgIgnore::load(&dir.join(".hgignore"))?
+      } else {
+        HgIgnore::default()
+      },
+      vc: self.vc_ignore,
+    });
+    let top = dir.to_path_buf();
+    let walk = WalkBuilder::new(dir)
+      .standard_filters(false)
+      .git_ignore(self.vc_ignore)
+      .require_git(false)
+      .follow_links(false)
+      .sort_by_file_name(|a, b| a.cmp(b))
+      .filter_entry(move |e| {
+        let is_dir = matches!(e.file_type(), Some(t) if t.is_dir());
+        match e.path().strip_prefix(&top).ok().and_then(|p| p.to_str()) {
+          Some("") => true,
+          Some(rel) => !skip.skip(&rel.replace('\\', "/"), is_dir),
+          None => false,
+        }
+      })
+      .build();
+    let mut out = vec![];
+    for e in walk {
+      let e = e.map_err(Error::archive)?;
+      let rel = match e.path().strip_prefix(dir).ok().and_then(|p| p.to_str()) {
+        Some("") | None => continue,
+        Some(r) => r.replace('\\', "/"),
+      };
+      out.push(Entry::new(e.into_path(), rel)?);
+    }
+    Ok(out)
+  }
+}
+
+/// Compiled `Filter` rules, shared with the walker's threads
+struct Skip {
+  exclude: GlobSet,
+  hg: HgIgnore,
+  vc: bool,
+}
+
+impl Skip {
+  fn skip(&self, rel: &str, is_dir: bool) -> bool {
+    let name = rel.rsplit('/').next().unwrap_or(rel);
+    (self.vc && is_dir && (name == ".git" || name == ".hg"))
+      || self.exclude.is_match(rel)
+      || self.hg.is_match(rel, is_dir)
+  }
+}
+
+/// Patterns from a `.hgignore`. Regexps are the default syntax,
+/// switched with `syntax: glob` or `syntax: rootglob` lines or per
+/// pattern with a `glob:`, `rootglob:` or `re:` prefix.
+#[derive(Debug)]
+struct HgIgnore {
+  re: Vec<Regex>,
+  globs: GlobSet,
+}
+
+impl Default for HgIgnore {
+  fn default() -> Self {
+    HgIgnore {
+      re: vec![],
+      globs: GlobSet::empty(),
+    }
+  }
+}
+
+impl HgIgnore {
+  fn load(path: &Path) -> Result<Self> {
+    match fs::read_to_string(path) {
+      Ok(s) => HgIgnore::parse(&s),
+      Err(_) => Ok(HgIgnore::default()),
+    }
+  }
+
+  fn parse(text: &str) -> Result<Self> {
+    let (mut re, mut globs) = (vec![], vec![]);
+    // None for regexps, Some(rooted) for globs
+    let mut syntax = None;
+    for line in text.lines() {
+      // `\#` is a literal hash
+      let line = match line.find('#') {
+        Some(i) if !line[..i].ends_with('\\') => &line[..i],
+        _ => line,
+      }
+      .trim_end();
+      if line.trim().is_empty() {
+        continue;
+      }
+      if let Some(s) = line.strip_prefix("syntax:") {
+        syntax = match s.trim() {
+          "glob" => Some(false),
+          "rootglob" => Some(true),
+          _ => None,
+        };
+        continue;
+      }
+      let (kind, pat) = match line.split_once(':') {
+        Some(("glob", p)) => (Some(false), p),
+        Some(("rootglob", p)) => (Some(true), p),
+        Some(("re" | "regexp", p)) => (None, p),
+        _ => (syntax, line),
+      };
+      match kind {
+        Some(rooted) => globs.push((rooted, pat.replace("\\#", "#"))),
+        None => re.push(Regex::new(pat).map_err(|e| Error::Archive(format!(".hgignore: {}", e)))?),
+      }
+    }
+    Ok(HgIgnore {
+      re,
+      globs: hg_globs(&globs)?,
+    })
+  }
+
+  /// hg matches files, so a directory also matches when a pattern
+  /// would match anything inside it
+  fn is_match(&self, rel: &str, is_dir: bool) -> bool {
+    let inside = format!("{}/", rel);
+    self
+      .re
+      .iter()
+      .any(|r| r.is_match(rel) || (is_dir && r.is_match(&inside)))
+      || self.globs.is_match(rel)
+  }
+}
+
+/// Compile `--exclude` style globs
+fn globs<'a, I: IntoIterator<Item = &'a str>>(pats: I) -> Result<GlobSet> {
+  let mut b = GlobSetBuilder::new();
+  for p in pats {
+    let p = p.trim_end_matches('/');
+    let p = match p.strip_prefix('/') {
+      Some(p) => p.to_owned(),
+      None if p.contains('/') => p.to_owned(),
+      None => format!("**/{}", p),
+    };
+    b.add(Glob::new(&p).map_err(Error::usage)?);
+  }
+  b.build().map_err(Error::usage)
+}
+
+/// Compile `.hgignore` globs. Rooted ones match from the top and
+/// their `*` stops at a `/`, the others match at any depth.
+fn hg_globs(pats: &[(bool, String)]) -> Result<GlobSet> {
+  let mut b = GlobSetBuilder::new();
+  for (rooted, p) in pats {
+    let p = p.trim_end_matches('/');
+    let g = if *rooted {
+      GlobBuilder::new(p).literal_separator(true).build()
+    } else {
+      Glob::new(&format!("**/{}", p))
+    };
+    b.add(g.map_err(Error::archive)?);
+  }
+  b.build().map_err(Error::archive)
+}
+
+#[cfg(unix)]
+fn executable(m: &fs::Metadata) -> bool {
+  use std::os::unix::fs::PermissionsExt;
+  m.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn executable(_: &fs::Metadata) -> bool {
+  false
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::TempDir;
+
+  #[test]
+  fn test_hgignore() {
+    let hg =
+      HgIgnore::parse("# build\n^target/\n\\.orig$\nsyntax: glob\n*.o\nre:^notes\n").unwrap();
+    assert!(hg.is_match("target", true));
+    assert!(hg.is_match("target/debug/x", false));
+    assert!(!hg.is_match("src/target.rs", false));
+    assert!(hg.is_match("a/b.rs.orig", false));
+    assert!(hg.is_match("a/b.o", false));
+    assert!(hg.is_match("notes.org", false));
+    assert!(!hg.is_match("src/notes.org", false));
+    let hg = HgIgnore::parse("rootglob:build\nsyntax: rootglob\n*.log\nglob:*.tmp\n").unwrap();
+    assert!(hg.is_match("build", true));
+    assert!(!hg.is_match("src/build", true));
+    assert!(hg.is_match("a.log", false));
+    assert!(!hg.is_match("logs/a.log", false));
+    assert!(hg.is_match("src/a.tmp", false));
+  }
+
+  #[test]
+  fn test_walk() {
+    let root = TempDir::new("filter");
+    for d in ["src", "target/debug", ".git", "doc"] {
+      fs::create_dir_all(root.join(d)).unwrap();
+    }
+    for f in [
+      "src/a.rs",
+      "src/a.rs~",
+      "target/debug/x",
+      ".git/HEAD",
+      "doc/b.org",
+      "SHA256SUMS",
+    ] {
+      fs::write(root.join(f), f).unwrap();
+    }
+    fs::write(root.join(".gitignore"), "*~\n").unwrap();
+    fs::write(root.join(".hgignore"), "syntax: glob\ntarget\n").unwrap();
+    let rels =
+      |f: &Filter| -> Vec<String> { f.walk(&root).unwrap().into_iter().map(|e| e.rel).collect() };
+    let f = Filter {
+      exclude: vec!["doc".to_owned()],
+      ..Filter::default()
+    };
+    // the walk keeps a SHA256SUMS, pack refuses it
+    assert_eq!(
+      rels(&f),
+      [".gitignore", ".hgignore", "SHA256SUMS", "src", "src/a.rs"]
+    );
+    let all = Filter {
+      exclude: vec![],
+      vc_ignore: false,
+    };
+    assert_eq!(
+      rels(&all),
+      [
+        ".git",
+        ".git/HEAD",
+        ".gitignore",
+        ".hgignore",
+        "SHA256SUMS",
+        "doc",
+        "doc/b.org",
+        "src",
+        "src/a.rs",
+        "src/a.rs~",
+        "target",
+        "target/debug",
+        "target/debug/x",
+      ]
+    );
+  }
+}
@@ -1,12 +1,35 @@
 //! bin/shc.rs --- shed-cli
-use rlib::util::Result;
-use shed::{build_cli, App};
-use std::env;
+/*!
+Errors are printed to stderr and `shc` exits with the code of the
+error's kind, see `shed::Error` for the table. Plugins replace the
+process, so `shc foo` exits with the code of `shc-foo`. The plugin
+directories are only searched for help or an unknown subcommand.
+*/
+use rlib::util::cli::ArgMatches;
+use shed::{build_cli, plugin, App, Result};
+use std::{env, process};
+
+async fn run(cli: &ArgMatches) -> Result<()> {
+  let app = App::new(cli)?; //initialize
+  app.dispatch().await //dispatch
+}
 
 #[tokio::main]
-async fn main() -> Result<()> {
-  let cli = build_cli().version(env!("DEMON_VERSION")).get_matches();
-  let app = App::new(&cli)?; //initialize
-  app.dispatch().await?; //dispatch
-  Ok(())
+async fn main() {
+  let cli = match build_cli().version(env!("DEMON_VERSION")).try_get_matches() {
+    Ok(cli) => cli,
+    Err(e) if e.use_stderr() => e.exit(),
+    // help, listing plugins as well
+    Err(_) => {
+      let shed = plugin::shed_root(plugin::config_arg(env::args()).as_deref());
+      let dirs = plugin::dirs(shed.as_deref(), env::var_os("PATH").as_deref());
+      plugin::register(build_cli(), &dirs)
+        .version(env!("DEMON_VERSION"))
+        .get_matches()
+    }
+  };
+  if let Err(e) = run(&cli).await {
+    eprintln!("shc: {}", e);
+    process::exit(e.code());
+  }
 }
@@ -1,8 +1,11 @@
 //! bin/shd.rs --- shed-daemon
-use rlib::{kala::Result, logger::flexi};
+use rlib::logger::flexi;
+use shed::Error;
 
 #[tokio::main]
-async fn main() -> Result<()> {
-  flexi("trace")?;
-  Ok(())
+async fn main() {
+  if let Err(e) = flexi("trace").map_err(Error::other) {
+    eprintln!("shd: {}", e);
+    std::process::exit(e.code());
+  }
 }
@@ -1,16 +1,21 @@
 //! bin/she.rs --- Emacs wrapper
 use rlib::kala::cmd::shell::{emacs, emacsclient};
+use shed::Error;
 
 #[tokio::main]
 async fn main() {
   let int = std::env::args().nth(1);
-  if let Some(i) = int {
+  let res = if let Some(i) = int {
     emacsclient(vec!["--socket-name=she", "--eval", &i])
       .await
-      .expect("failed to execute.");
+      .map_err(|e| Error::ipc(format!("failed to execute: {}", e)))
   } else {
     emacs(vec!["--fg-daemon=she", "--eval", "(shed-cmd-server-start)"])
       .await
-      .expect("failed to start.");
+      .map_err(|e| Error::ipc(format!("failed to start: {}", e)))
+  };
+  if let Err(e) = res {
+    eprintln!("she: {}", e);
+    std::process::exit(e.code());
   }
 }
@@ -1,8 +1,11 @@
 /// bin/shs.rs --- shed-server
-use rlib::{logger::flexi, util::Result};
+use rlib::logger::flexi;
+use shed::Error;
 
 #[tokio::main]
-async fn main() -> Result<()> {
-  flexi("trace")?;
-  Ok(())
+async fn main() {
+  if let Err(e) = flexi("trace").map_err(Error::other) {
+    eprintln!("shs: {}", e);
+    std::process::exit(e.code());
+  }
 }
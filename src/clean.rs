@@ -0,0 +1,230 @@
+//! clean.rs --- shed cleanup
+/*!
+`shc clean` collects everything it intends to do into a `Plan` before
+touching the disk. The plan is either printed (`--dry-run`) or
+executed. Removals are restricted to the shed path and never follow
+symlinks; anything else is left to a project's own clean script.
+*/
+use crate::{
+  config::Project,
+  list::{disk_usage, fmt_size},
+  script::{self, Script},
+  Config, Error, Result,
+};
+
+use rlib::logger::log::{error, info, warn};
+
+use std::{
+  fmt, fs, io,
+  path::{Path, PathBuf},
+};
+
+/// A single cleanup action
+#[derive(Debug)]
+pub enum Target {
+  /// Run a clean script inside a project directory
+  Script {
+    name: String,
+    dir: PathBuf,
+    cmd: String,
+  },
+  /// Remove a file or directory
+  Remove(PathBuf),
+}
+
+impl fmt::Display for Target {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Target::Script { name, dir, cmd } => {
+        write!(f, "{}: run `{}` in {}", name, cmd, dir.display())
+      }
+      Target::Remove(p) => {
+        let size = disk_usage(p).map(|(s, _)| fmt_size(s)).unwrap_or_default();
+        write!(f, "delete {} ({})", p.display(), size)
+      }
+    }
+  }
+}
+
+/// An ordered list of cleanup actions
+#[derive(Debug)]
+pub struct Plan<'a> {
+  cfg: &'a Config,
+  targets: Vec<Target>,
+}
+
+impl<'a> Plan<'a> {
+  pub fn new(cfg: &'a Config) -> Self {
+    Plan {
+      cfg,
+      targets: vec![],
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.targets.is_empty()
+  }
+
+  pub fn targets(&self) -> &[Target] {
+    &self.targets
+  }
+
+  /// Add the clean script of `project`. Projects which are missing on
+  /// disk or have no script are skipped.
+  pub fn project(&mut self, project: Project) -> &mut Self {
+    let dir = project.dir(&self.cfg.path);
+    if !dir.is_dir() {
+      warn!("{} not found at {}", project.name(), dir.display());
+      return self;
+    }
+    match script::resolve(self.cfg, &project, Script::Clean) {
+      Some(cmd) => self.targets.push(Target::Script {
+        name: project.name().to_owned(),
+        dir,
+        cmd,
+      }),
+      None => info!("no clean script for {}", project.name()),
+    }
+    self
+  }
+
+  /// Add every entry inside `dir`, leaving `dir` itself in place.
+  /// `dir` must resolve to a path inside the shed, after `..` and
+  /// symlinks.
+  pub fn contents<P: AsRef<Path>>(&mut self, dir: P) -> io::Result<&mut Self> {
+    let dir = dir.as_ref();
+    if !dir.is_dir() {
+      return Ok(self);
+    }
+    let dir = dir.canonicalize()?;
+    if !dir.starts_with(self.cfg.path.canonicalize()?) {
+      return Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("{} is outside of the shed", dir.display()),
+      ));
+    }
+    let mut entries = fs::read_dir(&dir)?
+      .map(|e| e.map(|e| e.path()))
+      .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    self.targets.extend(entries.into_iter().map(Target::Remove));
+    Ok(self)
+  }
+
+  /// Whether any target deletes files rather than running a script
+  pub fn removes(&self) -> bool {
+    self.targets.iter().any(|t| matches!(t, Target::Remove(_)))
+  }
+
+  /// Print the plan without executing it
+  pub fn print(&self) {
+    for t in self.targets.iter() {
+      println!("would {}", t);
+    }
+  }
+
+  /// Execute every target. Failures are reported and the remaining
+  /// targets still run.
+  pub fn execute(&self) -> Result<()> {
+    let mut failed = 0;
+    for t in self.targets.iter() {
+      println!("{}", t);
+      let res = match t {
+        Target::Script { dir, cmd, .. } => script::command(cmd, dir).status().and_then(|s| {
+          if s.success() {
+            Ok(())
+          } else {
+            Err(io::Error::new(io::ErrorKind::Other, s.to_string()))
+          }
+        }),
+        Target::Remove(p) => remove(p),
+      };
+      if let Err(e) = res {
+        error!("{}: {}", t, e);
+        failed += 1;
+      }
+    }
+    match failed {
+      0 => Ok(()),
+      n => Err(Error::Script(format!(
+        "{} of {} clean targets failed",
+        n,
+        self.targets.len()
+      ))),
+    }
+  }
+}
+
+/// Remove `path` without following symlinks
+fn remove(path: &Path) -> io::Result<()> {
+  let meta = fs::symlink_metadata(path)?;
+  if meta.is_dir() {
+    fs::remove_dir_all(path)
+  } else {
+    fs::remove_file(path)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::TempDir;
+
+  fn shed(tmp: &TempDir) -> Config {
+    let mut cfg = Config::new();
+    cfg.path = tmp.join("shed");
+    for f in ["stash/tmp/a", "stash/tmp/b/c"] {
+      let p = cfg.path.join(f);
+      fs::create_dir_all(p.parent().unwrap()).unwrap();
+      fs::write(p, f).unwrap();
+    }
+    cfg
+  }
+
+  #[test]
+  fn test_contents_dry_run() {
+    let tmp = TempDir::new("clean");
+    let cfg = shed(&tmp);
+    let mut plan = Plan::new(&cfg);
+    plan.contents(cfg.path.join("stash/tmp")).unwrap();
+    plan.contents(cfg.path.join("stash/missing")).unwrap();
+    let tmp_dir = cfg.path.join("stash/tmp").canonicalize().unwrap();
+    let removed: Vec<_> = plan
+      .targets()
+      .iter()
+      .map(|t| match t {
+        Target::Remove(p) => p.clone(),
+        t => panic!("unexpected target {}", t),
+      })
+      .collect();
+    assert_eq!(removed, [tmp_dir.join("a"), tmp_dir.join("b")]);
+    assert!(plan.removes());
+    plan.print();
+    assert!(cfg.path.join("stash/tmp/a").is_file());
+    assert!(cfg.path.join("stash/tmp/b/c").is_file());
+    plan.execute().unwrap();
+    assert!(tmp_dir.is_dir());
+    assert_eq!(fs::read_dir(&tmp_dir).unwrap().count(), 0);
+  }
+
+  #[test]
+  fn test_contents_outside() {
+    let tmp = TempDir::new("clean");
+    let cfg = shed(&tmp);
+    let outside = tmp.join("outside");
+    fs::create_dir_all(&outside).unwrap();
+    fs::write(outside.join("keep"), "").unwrap();
+    let mut plan = Plan::new(&cfg);
+    let denied =
+      |r: io::Result<&mut Plan>| matches!(r, Err(e) if e.kind() == io::ErrorKind::PermissionDenied);
+    assert!(denied(plan.contents(&outside)));
+    assert!(denied(plan.contents(cfg.path.join("stash/../../outside"))));
+    #[cfg(unix)]
+    {
+      std::os::unix::fs::symlink(&outside, cfg.path.join("stash/link")).unwrap();
+      assert!(denied(plan.contents(cfg.path.join("stash/link"))));
+    }
+    assert!(plan.is_empty());
+    assert!(outside.join("keep").is_file());
+  }
+}
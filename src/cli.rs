@@ -6,6 +6,7 @@ pub fn build_cli() -> App<'static> {
 //    .about("shed multi-development tool")
     .setting(AppSettings::TrailingVarArg)
     .setting(AppSettings::ArgRequiredElseHelp)
+    .setting(AppSettings::AllowExternalSubcommands)
     .color(ColorChoice::Auto)
     .arg(
       Arg::new("config")
@@ -27,10 +28,16 @@ pub fn build_cli() -> App<'static> {
 //        .about("initialize the shed")
         .arg(
           Arg::new("path")
-            .takes_value(true)
-            .default_value("~/.config/shed/shed.cfg"),
+            .takes_value(true),
+//            .about("config file to write, defaults to ~/.config/shed/shed.cfg"),
         )
         .arg(Arg::new("force").short('f').long("force"))
+        .arg(
+          Arg::new("yes")
+            .short('y')
+            .long("yes"),
+//            .about("accept all defaults without prompting"),
+        )
         .arg(Arg::new("db").short('d').long("db"))
         .arg(
           Arg::new("fmt")
@@ -39,6 +46,32 @@ pub fn build_cli() -> App<'static> {
 //            .about("config format")
             .possible_values(&["json", "ron", "bin"]),
         ),
+      App::new("config")
+//        .about("inspect and change configuration values")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommands(vec![
+          App::new("get")
+//            .about("print a dotted config path and the layer it came from")
+            .arg(Arg::new("key").takes_value(true)),
+          App::new("set")
+//            .about("set a dotted config path in the active config file")
+            .arg(Arg::new("key").takes_value(true).required(true))
+            .arg(Arg::new("value").takes_value(true).required(true)),
+          App::new("check")
+//            .about("validate config files and print every problem found")
+            .arg(Arg::new("input").takes_value(true)),
+          App::new("convert")
+//            .about("rewrite a config in another format")
+            .arg(Arg::new("input").takes_value(true).required(true))
+            .arg(Arg::new("output").takes_value(true))
+            .arg(
+              Arg::new("fmt")
+                .long("fmt")
+                .takes_value(true)
+                .required(true)
+                .possible_values(&["json", "ron", "bin"]),
+            ),
+        ]),
       App::new("edit")
         .alias("e")
 //        .about("edit all the things")
@@ -46,10 +79,91 @@ pub fn build_cli() -> App<'static> {
       App::new("clean")
         .alias("c")
 //        .about("clean stuff up")
-        .arg(Arg::new("input").takes_value(true).default_value(".")),
+        .arg(Arg::new("input").takes_value(true))
+        .arg(
+          Arg::new("lab")
+            .long("lab")
+            .takes_value(true)
+            .min_values(0),
+//            .about("clean the specified lab environment"),
+        )
+        .arg(
+          Arg::new("project")
+            .long("project")
+            .takes_value(true)
+            .min_values(0),
+//            .about("clean the specified project"),
+        )
+        .arg(Arg::new("stash").long("stash"))
+        .arg(Arg::new("store").long("store"))
+        .arg(Arg::new("all").long("all").short('a'))
+        .arg(
+          Arg::new("dry_run")
+            .long("dry-run")
+            .short('n'),
+//            .about("list what would be deleted"),
+        )
+        .arg(
+          Arg::new("yes")
+            .long("yes")
+            .short('y'),
+//            .about("delete stash and store entries without asking"),
+        ),
+      App::new("list")
+        .alias("l")
+//        .about("list projects, labs, stash or store")
+        .arg(
+          Arg::new("input")
+            .takes_value(true)
+            .possible_values(&["projects", "labs", "stash", "store"]),
+        ),
+      App::new("test")
+        .alias("t")
+//        .about("run project test scripts")
+        .arg(Arg::new("input").takes_value(true))
+        .arg(
+          Arg::new("all")
+            .long("all")
+            .short('a'),
+//            .about("test everything"),
+        ),
+      App::new("run")
+        .alias("r")
+//        .about("run a command from usr.shell.cmds")
+        .setting(AppSettings::TrailingVarArg)
+        .arg(
+          Arg::new("format")
+            .long("format")
+//            .about("output format")
+            .takes_value(true)
+            .possible_values(&["text", "json", "sexp"])
+            .default_value("text"),
+        )
+        .arg(Arg::new("name").takes_value(true))
+        .arg(
+          Arg::new("args")
+            .takes_value(true)
+            .multiple_values(true)
+            .allow_hyphen_values(true),
+        )
+        .arg(
+          Arg::new("project")
+            .long("project")
+            .short('p')
+            .takes_value(true),
+//            .about("fill {project} with this project"),
+        ),
       App::new("status")
         .alias("s")
 //        .about("print basic info")
+        .arg(
+          Arg::new("format")
+            .long("format")
+//            .about("output format")
+            .takes_value(true)
+            .possible_values(&["text", "json", "sexp"])
+            .default_value("text"),
+        )
         .arg(Arg::new("input"))
         .arg(Arg::new("sys").long("sys").short('s')
 	     //	     .about("system info")
@@ -69,21 +183,86 @@ pub fn build_cli() -> App<'static> {
 		       .arg(Arg::new("weather").short('w')
 			    //			    .about("weather report")
 		       )
+        .arg(
+          Arg::new("at")
+            .long("at")
+            .takes_value(true)
+//            .about("lat,lon or a name from weather.locations")
+            .requires("weather"),
+        )
+        .arg(
+          Arg::new("cached")
+            .long("cached")
+//            .about("reuse a weather report younger than weather.ttl")
+            .requires("weather"),
+        )
+        .arg(
+          Arg::new("save")
+            .long("save")
+//            .about("save the location of our public IP as weather.home")
+            .requires("weather"),
+        )
 			    .arg(Arg::new("vc").short('v')
 				 //				 .about("show repo status")
 			    )
         .arg(
           Arg::new("remote")
             .short('r')
-//            .about("query remote for changes")
-            .requires("vc"),
+            .long("remote"),
+//            .about("query remote for changes"),
+        )
+        .arg(
+          Arg::new("all")
+            .short('a')
+            .long("all"),
+//            .about("show every repo in the config"),
+        )
+        .arg(
+          Arg::new("dirty")
+            .long("dirty")
+//            .about("only show repos with uncommitted changes")
+            .requires("all"),
+        )
+        .arg(
+          Arg::new("behind")
+            .long("behind")
+//            .about("only show repos with incoming changes")
+            .requires("all"),
+        )
+        .arg(
+          Arg::new("jobs")
+            .short('j')
+            .long("jobs")
+            .takes_value(true)
+//            .about("number of repos to query at once")
+            .default_value("8"),
         ),
       App::new("pack")
 //        .about("create packages from file or directory")
         .arg(Arg::new("input").takes_value(true))
-        .arg(Arg::new("output").takes_value(true).default_value(".")),
+        .arg(Arg::new("output").takes_value(true).default_value("."))
+        .arg(
+          Arg::new("format")
+            .long("format")
+            .takes_value(true)
+//            .about("archive format, defaults to the output extension")
+            .possible_values(&["z", "tz", "tar.gz", "tar.zst", "tar.xz", "zip"]),
+        )
+        .arg(
+          Arg::new("exclude")
+            .short('x')
+            .long("exclude")
+            .takes_value(true)
+            .multiple_occurrences(true),
+//            .about("leave out paths matching a glob"),
+        )
+        .arg(
+          Arg::new("no_ignore")
+            .long("no-ignore"),
+//            .about("include what .gitignore and .hgignore list"),
+        ),
       App::new("unpack")
-//        .about("unpack .z or .tz files")
+//        .about("unpack .z, .tz, .tar.gz, .tar.zst, .tar.xz or .zip files")
         .arg(Arg::new("input").takes_value(true))
         .arg(
           Arg::new("output")
@@ -94,26 +273,112 @@ pub fn build_cli() -> App<'static> {
         .arg(
           Arg::new("replace")
             .short('r')
-//            .about("consume input package"),
+//            .about("remove the input once the extraction is checked"),
+        )
+        .arg(
+          Arg::new("format")
+            .long("format")
+            .takes_value(true)
+//            .about("archive format, detected from the input by default")
+            .possible_values(&["z", "tz", "tar.gz", "tar.zst", "tar.xz", "zip"]),
+        )
+        .arg(
+          Arg::new("verify")
+            .long("verify"),
+//            .about("check the archive against its manifest first"),
+        )
+        .arg(
+          Arg::new("overwrite")
+            .long("overwrite")
+            .takes_value(true)
+            .possible_values(&["never", "newer", "always"])
+            .default_value("never"),
+//            .about("what to do with existing files"),
+        )
+        .arg(
+          Arg::new("list")
+            .short('l')
+            .long("list"),
+//            .about("print the contents without extracting"),
         ),
       App::new("download")
 //        .about("fetch resources")
         .alias("dl")
 				 .arg(Arg::new("input").takes_value(true)
 				      //				      .about("object URI")
-				 ),
-				      App::new("pull")
-//				      .about("fetch resources")
-				      .arg(
-        Arg::new("input")
-          .takes_value(true)
-//          .about("parent to pull from"),
-      ),
+				 )
+        .arg(
+          Arg::new("sha256")
+            .long("sha256")
+            .takes_value(true),
+//            .about("verify the download against a SHA-256 digest"),
+        ),
+      App::new("pull")
+//        .about("fetch resources")
+        .arg(
+          Arg::new("input")
+            .takes_value(true)
+//            .about("parent to pull from"),
+        )
+        .arg(
+          Arg::new("all")
+            .short('a')
+            .long("all"),
+//            .about("every project in the config"),
+        )
+        .arg(
+          Arg::new("pkg")
+            .short('p')
+            .long("pkg")
+            .takes_value(true)
+            .multiple_values(true)
+//            .about("projects to pull")
+            .conflicts_with("all"),
+        )
+        .arg(
+          Arg::new("jobs")
+            .short('j')
+            .long("jobs")
+            .takes_value(true)
+//            .about("number of repos to pull at once")
+            .default_value("8"),
+        )
+        .arg(
+          Arg::new("force")
+            .short('f')
+            .long("force"),
+//            .about("pull repos with uncommitted changes too"),
+        ),
       App::new("push")
 //        .about("commit changes to upstream")
-				      .arg(Arg::new("to").takes_value(true)
-					   //					   .about("parent to push to")
-				      ),
+        .arg(
+          Arg::new("to")
+            .takes_value(true)
+//            .about("parent to push to"),
+        )
+        .arg(
+          Arg::new("all")
+            .short('a')
+            .long("all"),
+//            .about("every project in the config"),
+        )
+        .arg(
+          Arg::new("pkg")
+            .short('p')
+            .long("pkg")
+            .takes_value(true)
+            .multiple_values(true)
+//            .about("projects to push")
+            .conflicts_with("all"),
+        )
+        .arg(
+          Arg::new("jobs")
+            .short('j')
+            .long("jobs")
+            .takes_value(true)
+//            .about("number of repos to push at once")
+            .default_value("8"),
+        ),
       App::new("serve")
 //        .about("network services")
         .arg(
@@ -128,6 +393,18 @@ pub fn build_cli() -> App<'static> {
             .takes_value(true)
             .possible_values(&["hg", "dm", "ftp"])
 //            .about("network backend"),
+        )
+        .arg(
+          Arg::new("upload")
+            .long("upload"),
+//            .about("accept uploads into stash/inbox (ftp)"),
+        )
+        .arg(
+          Arg::new("max-upload")
+            .long("max-upload")
+            .takes_value(true)
+//            .about("largest upload accepted, in MiB (1024)")
+            .requires("upload"),
         ),
       App::new("build")
         .alias("b")
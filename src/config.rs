@@ -1,22 +1,37 @@
 /// config.rs --- shed configurations
 use std::{
   collections::HashMap,
-  fs,
+  fmt, fs,
+  io::{self, BufReader, Read},
   path::{Path, PathBuf},
+  str::FromStr,
 };
 
 use rlib::{
-  logger::log::{error, info},
+  logger::log::info,
   obj::{
-    impl_config, ron::de::from_reader, Configure, HgwebConfig, MercurialConfig, NetworkConfig,
-    Objective, PackageConfig, ProgramConfig, ProjectConfig, Result, UserConfig,
+    impl_config, ron, Configure, HgwebConfig, MercurialConfig, NetworkConfig, Objective,
+    PackageConfig, ProgramConfig, ProjectConfig, UserConfig,
   },
 };
 
-use serde::{Deserialize, Serialize};
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
 
-/// Shed configuration type
+use crate::Result;
+
+pub mod check;
+pub mod expand;
+pub mod layer;
+pub use self::{
+  check::{Level, Problem},
+  layer::{Layer, Layers},
+};
+
+/// Shed configuration type. Fields missing from a file are taken
+/// from `Config::new`, so a config layer only needs to spell out what
+/// it changes.
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(default = "Config::new")]
 pub struct Config {
   pub path: PathBuf, // the shed path on disk
   pub src: Vec<PackageConfig>,
@@ -25,6 +40,79 @@ pub struct Config {
   pub hg: MercurialConfig,
   pub lab: Vec<ProjectConfig>,
   pub usr: UserConfig,
+  pub scripts: HashMap<String, ScriptConfig>, // keyed by package or project name
+  pub weather: WeatherConfig,
+}
+
+/// Shell commands run inside a package or project directory
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct ScriptConfig {
+  pub build: Option<String>,
+  pub clean: Option<String>,
+  pub test: Option<String>,
+}
+
+/// Locations and caching for `shc status --weather`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WeatherConfig {
+  /// used when `usr.geo` is unset, saved after an IP lookup
+  pub home: Option<Location>,
+  /// named locations for `--at`
+  pub locations: HashMap<String, Location>,
+  /// seconds a report saved with `--cached` stays fresh
+  pub ttl: u64,
+}
+
+impl Default for WeatherConfig {
+  fn default() -> Self {
+    WeatherConfig {
+      home: None,
+      locations: HashMap::new(),
+      ttl: 600,
+    }
+  }
+}
+
+/// A point on the globe in decimal degrees
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+  pub lat: f64,
+  pub lon: f64,
+}
+
+impl Location {
+  /// The point of rlib's `UserConfig.geo`, when set
+  pub fn of_user(usr: &UserConfig) -> Option<Location> {
+    let p = &usr.geo.as_ref()?.point;
+    Some(Location {
+      lat: f64::from(p.lat),
+      lon: f64::from(p.lng),
+    })
+  }
+}
+
+impl FromStr for Location {
+  type Err = String;
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    let bad = || format!("'{}' is not a lat,lon pair", s);
+    let (lat, lon) = s.split_once(',').ok_or_else(bad)?;
+    let (lat, lon): (f64, f64) = (
+      lat.trim().parse().map_err(|_| bad())?,
+      lon.trim().parse().map_err(|_| bad())?,
+    );
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+      return Err(format!("{} is off the globe", s));
+    }
+    Ok(Location { lat, lon })
+  }
+}
+
+impl fmt::Display for Location {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{:.4},{:.4}", self.lat, self.lon)
+  }
 }
 
 impl Config {
@@ -38,50 +126,415 @@ impl Config {
     let lab = vec![];
     let usr = UserConfig::default();
     Config {
-      path: PathBuf::from(option_env!("SHED").unwrap_or("~/shed")),
+      path: PathBuf::from("~/shed"),
       src: vec![],
       bin: vec![],
       net: NetworkConfig::default(),
       hg,
       lab,
       usr,
+      scripts: HashMap::new(),
+      weather: WeatherConfig::default(),
     }
   }
 
-  pub fn write<P: AsRef<Path>>(&self, path: P, ext: Option<&str>) -> Result<()> {
-    let path = path.as_ref();
-    let f_path = &path.join("shed.cfg");
+  /// Write the config to the file at `path`, creating its parent
+  /// directory when missing. Without a `fmt` the format is taken from
+  /// the file extension, defaulting to RON.
+  pub fn write<P: AsRef<Path>>(&self, path: P, fmt: Option<Format>) -> Result<()> {
+    let f_path = path.as_ref();
+    if let Some(d) = f_path.parent() {
+      fs::create_dir_all(d)?;
+    }
+    let fmt = fmt
+      .or_else(|| Format::from_ext(f_path))
+      .unwrap_or(Format::Ron);
     let file = fs::File::create(f_path)?;
-    match ext {
-      Some(i) => match i {
-        "json" => self.to_json_writer(file)?,
-        "ron" => self.to_ron_writer(file)?,
-        "bin" => self.encode_into(file)?,
-        i => {
-          error!("extension '{}' not understood", i);
-          std::process::exit(1);
-        }
-      },
-      None => self.to_ron_writer(file)?,
+    match fmt {
+      Format::Json => self.to_json_writer(file).map_err(crate::Error::config)?,
+      Format::Ron => self.to_ron_writer(file).map_err(crate::Error::config)?,
+      Format::Bin => bincode::serialize_into(file, self).map_err(crate::Error::config)?,
     }
-    println!("wrote config to {}", f_path.display());
+    println!("wrote {} config to {}", fmt, f_path.display());
     Ok(())
   }
 
-  pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
-    let f = fs::File::open(path)?;
-    let config: Config = match from_reader(f) {
-      Ok(x) => {
-        info!("loading config: {:?}", x);
-        x
-      }
-      Err(e) => {
-        error!("Failed to load config: {}", e);
-        std::process::exit(1);
-      }
+  /// Write only the top-level `fields` of the config to `path`, like
+  /// `write`. Bincode has no field names, so it always gets the whole
+  /// config.
+  pub fn write_fields<P: AsRef<Path>>(
+    &self,
+    path: P,
+    fmt: Option<Format>,
+    fields: &[String],
+  ) -> Result<()> {
+    let f_path = path.as_ref();
+    let fmt = fmt
+      .or_else(|| Format::from_ext(f_path))
+      .unwrap_or(Format::Ron);
+    if fmt == Format::Bin {
+      return self.write(f_path, Some(fmt));
+    }
+    if let Some(d) = f_path.parent() {
+      fs::create_dir_all(d)?;
+    }
+    let part = Fields { cfg: self, fields };
+    let file = fs::File::create(f_path)?;
+    match fmt {
+      Format::Json => serde_json::to_writer_pretty(file, &part).map_err(crate::Error::config)?,
+      _ => ron::ser::to_writer_pretty(file, &part, ron::ser::PrettyConfig::new())
+        .map_err(crate::Error::config)?,
+    }
+    println!("wrote {} config to {}", fmt, f_path.display());
+    Ok(())
+  }
+
+  /// Expand `~` and environment variables in every path, see
+  /// `expand`. Configs are loaded and written unexpanded.
+  pub fn expanded(mut self) -> Self {
+    self.path = expand::expand_path(&self.path);
+    expand::expand_fields(&mut self.src);
+    expand::expand_fields(&mut self.lab);
+    expand::expand_fields(&mut self.bin);
+    expand::expand_fields(&mut self.hg.web);
+    // commands are left to the shell, while env values never see one
+    let cmds = std::mem::take(&mut self.usr.shell.cmds);
+    expand::expand_fields(&mut self.usr);
+    self.usr.shell.cmds = cmds;
+    for v in self.usr.shell.env.values_mut() {
+      *v = expand::expand(v);
+    }
+    self
+  }
+
+  /// Iterate over all `src` packages and `lab` projects
+  pub fn projects(&self) -> impl Iterator<Item = Project<'_>> {
+    self
+      .src
+      .iter()
+      .map(Project::Src)
+      .chain(self.lab.iter().map(Project::Lab))
+  }
+
+  /// Find a package or project by name
+  pub fn project(&self, name: &str) -> Option<Project<'_>> {
+    self.projects().find(|p| p.name() == name)
+  }
+
+  /// Load a config, detecting its `Format` from the file extension
+  /// or contents
+  pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+    let path = path.as_ref();
+    let io_err = |e| Error::Io(path.to_path_buf(), e);
+    let fmt = Format::detect(path).map_err(io_err)?;
+    let f = BufReader::new(fs::File::open(path).map_err(io_err)?);
+    let parse_err = |pos, msg: String| Error::Parse {
+      path: path.to_path_buf(),
+      fmt,
+      pos,
+      msg,
+    };
+    let config: Config = match fmt {
+      Format::Ron => ron::de::from_reader(f)
+        .map_err(|e| parse_err(Some((e.position.line, e.position.col)), e.code.to_string()))?,
+      Format::Json => serde_json::from_reader(f).map_err(|e| {
+        let pos = (e.line() > 0).then(|| (e.line(), e.column()));
+        parse_err(pos, e.to_string())
+      })?,
+      Format::Bin => bincode::deserialize_from(f).map_err(|e| parse_err(None, e.to_string()))?,
     };
+    info!("loading config: {:?}", config);
     Ok(config)
   }
 }
 
 impl_config!(Config);
+
+/// Some of the top-level fields of a `Config`, serialized as one
+struct Fields<'a> {
+  cfg: &'a Config,
+  fields: &'a [String],
+}
+
+impl Serialize for Fields<'_> {
+  fn serialize<S: Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+    let has = |f: &str| self.fields.iter().any(|i| i == f);
+    macro_rules! fields {
+      ($($f:ident),*) => {{
+        let n = [$(stringify!($f)),*].iter().filter(|f| has(f)).count();
+        let mut st = s.serialize_struct("Config", n)?;
+        $(if has(stringify!($f)) {
+          st.serialize_field(stringify!($f), &self.cfg.$f)?;
+        })*
+        st.end()
+      }};
+    }
+    fields!(path, src, bin, net, hg, lab, usr, scripts, weather)
+  }
+}
+
+/// Config serialization formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+  Ron,
+  Json,
+  Bin,
+}
+
+impl Format {
+  /// Guess the format from a `.ron`, `.json` or `.bin` extension
+  pub fn from_ext<P: AsRef<Path>>(path: P) -> Option<Format> {
+    path.as_ref().extension()?.to_str()?.parse().ok()
+  }
+
+  /// Guess the format from the first bytes of a file. Text which
+  /// opens with `{` is JSON, any other text is RON, and anything
+  /// containing NUL or invalid UTF-8 is bincode.
+  pub fn sniff(buf: &[u8]) -> Format {
+    let text = match std::str::from_utf8(buf) {
+      Ok(t) => t,
+      // a multi-byte char may be cut off at the end of the buffer
+      Err(e) if e.error_len().is_none() => std::str::from_utf8(&buf[..e.valid_up_to()]).unwrap(),
+      Err(_) => return Format::Bin,
+    };
+    if text.contains('\0') {
+      Format::Bin
+    } else if text.trim_start().starts_with('{') {
+      Format::Json
+    } else {
+      Format::Ron
+    }
+  }
+
+  /// Detect the format of the file at `path` by extension, falling
+  /// back to `Format::sniff`
+  pub fn detect<P: AsRef<Path>>(path: P) -> io::Result<Format> {
+    let path = path.as_ref();
+    if let Some(f) = Format::from_ext(path) {
+      return Ok(f);
+    }
+    let mut buf = Vec::with_capacity(64);
+    fs::File::open(path)?.take(64).read_to_end(&mut buf)?;
+    Ok(Format::sniff(&buf))
+  }
+}
+
+impl FromStr for Format {
+  type Err = String;
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    match s {
+      "ron" => Ok(Format::Ron),
+      "json" => Ok(Format::Json),
+      "bin" => Ok(Format::Bin),
+      s => Err(format!("config format '{}' not understood", s)),
+    }
+  }
+}
+
+impl fmt::Display for Format {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(match self {
+      Format::Ron => "ron",
+      Format::Json => "json",
+      Format::Bin => "bin",
+    })
+  }
+}
+
+/// Errors produced while loading a config file
+#[derive(Debug)]
+pub enum Error {
+  Io(PathBuf, io::Error),
+  /// `pos` is the 1-based line and column, when the format reports one
+  Parse {
+    path: PathBuf,
+    fmt: Format,
+    pos: Option<(usize, usize)>,
+    msg: String,
+  },
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Error::Io(p, e) => write!(f, "{}: {}", p.display(), e),
+      Error::Parse {
+        path,
+        fmt,
+        pos: Some((l, c)),
+        msg,
+      } => write!(
+        f,
+        "{}:{}:{}: invalid {} config: {}",
+        path.display(),
+        l,
+        c,
+        fmt,
+        msg
+      ),
+      Error::Parse { path, fmt, msg, .. } => {
+        write!(f, "{}: invalid {} config: {}", path.display(), fmt, msg)
+      }
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+/// A package from `Config.src` or a project from `Config.lab`
+#[derive(Debug, Clone, Copy)]
+pub enum Project<'a> {
+  Src(&'a PackageConfig),
+  Lab(&'a ProjectConfig),
+}
+
+impl<'a> Project<'a> {
+  pub fn name(&self) -> &'a str {
+    match self {
+      Project::Src(p) => &p.name,
+      Project::Lab(p) => &p.name,
+    }
+  }
+
+  /// The project directory relative to the shed `root`
+  pub fn dir<P: AsRef<Path>>(&self, root: P) -> PathBuf {
+    let root = root.as_ref();
+    match self {
+      Project::Src(p) => root.join("src").join(&p.name),
+      Project::Lab(p) => root.join("lab").join(&p.name),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::TempDir;
+
+  fn roundtrip(fmt: Format, path: PathBuf) {
+    let mut cfg = Config::new();
+    cfg.path = "/tmp/shed-test".into();
+    cfg.scripts.insert(
+      "shed".to_owned(),
+      ScriptConfig {
+        build: Some("make b".to_owned()),
+        ..Default::default()
+      },
+    );
+    cfg.write(&path, Some(fmt)).unwrap();
+    assert_eq!(Format::detect(&path).unwrap(), fmt);
+    let back = Config::load(&path).unwrap();
+    assert_eq!(
+      serde_json::to_value(&cfg).unwrap(),
+      serde_json::to_value(&back).unwrap()
+    );
+  }
+
+  #[test]
+  fn test_roundtrip_ext() {
+    let tmp = TempDir::new("cfg");
+    roundtrip(Format::Ron, tmp.join("shed.ron"));
+    roundtrip(Format::Json, tmp.join("shed.json"));
+    roundtrip(Format::Bin, tmp.join("shed.bin"));
+  }
+
+  #[test]
+  fn test_roundtrip_sniff() {
+    let tmp = TempDir::new("cfg");
+    roundtrip(Format::Ron, tmp.join("ron.cfg"));
+    roundtrip(Format::Json, tmp.join("json.cfg"));
+    roundtrip(Format::Bin, tmp.join("bin.cfg"));
+  }
+
+  #[test]
+  fn test_expanded_keeps_file_raw() {
+    let tmp = TempDir::new("cfg");
+    let path = tmp.join("raw.ron");
+    Config::new().write(&path, None).unwrap();
+    let cfg = Config::load(&path).unwrap();
+    assert_eq!(cfg.path, PathBuf::from("~/shed"));
+    let home = std::env::var("HOME").unwrap();
+    assert_eq!(cfg.expanded().path, Path::new(&home).join("shed"));
+  }
+
+  #[test]
+  fn test_write_fields() {
+    let tmp = TempDir::new("cfg");
+    let mut cfg = Config::new();
+    cfg.path = "/srv/shed".into();
+    cfg.weather.ttl = 60;
+    let fields = ["path".to_owned()];
+    for f in ["part.ron", "part.json"] {
+      let path = tmp.join(f);
+      cfg.write_fields(&path, None, &fields).unwrap();
+      assert!(!fs::read_to_string(&path).unwrap().contains("weather"));
+      let back = Config::load(&path).unwrap();
+      assert_eq!(back.path, PathBuf::from("/srv/shed"));
+      assert_eq!(back.weather.ttl, 600);
+    }
+    let path = tmp.join("part.bin");
+    cfg.write_fields(&path, None, &fields).unwrap();
+    assert_eq!(Config::load(&path).unwrap().weather.ttl, 60);
+  }
+
+  #[test]
+  fn test_expanded_usr() {
+    let mut cfg = Config::new();
+    let env = &mut cfg.usr.shell.env;
+    env.insert("BIN".to_owned(), "${HOME}/bin:/usr/bin".to_owned());
+    let cmds = &mut cfg.usr.shell.cmds;
+    cmds.insert("home".to_owned(), "echo $HOME".to_owned());
+    let home = std::env::var("HOME").unwrap();
+    let cfg = cfg.expanded();
+    assert_eq!(cfg.usr.shell.env["BIN"], format!("{}/bin:/usr/bin", home));
+    assert_eq!(cfg.usr.shell.cmds["home"], "echo $HOME");
+  }
+
+  #[test]
+  fn test_parse_error_position() {
+    let tmp = TempDir::new("cfg");
+    let path = tmp.join("bad.ron");
+    fs::write(&path, "(\n  path: 1,\n)").unwrap();
+    match Config::load(&path) {
+      Err(Error::Parse {
+        fmt: Format::Ron,
+        pos: Some((2, _)),
+        ..
+      }) => (),
+      r => panic!("expected a RON parse error on line 2, got {:?}", r),
+    }
+    let path = tmp.join("bad.json");
+    fs::write(&path, "{\n\"path\": 1}").unwrap();
+    assert!(matches!(
+      Config::load(&path),
+      Err(Error::Parse {
+        fmt: Format::Json,
+        pos: Some((2, _)),
+        ..
+      })
+    ));
+  }
+
+  #[test]
+  fn test_location() {
+    let l = Location {
+      lat: 41.3557,
+      lon: -72.0995,
+    };
+    assert_eq!(" 41.3557, -72.0995".parse(), Ok(l));
+    assert!("41.3557".parse::<Location>().is_err());
+    assert!("91,0".parse::<Location>().is_err());
+    assert_eq!(l.to_string(), "41.3557,-72.0995");
+    assert_eq!(Location::of_user(&UserConfig::default()), None);
+  }
+
+  #[test]
+  fn test_sniff() {
+    assert_eq!(Format::sniff(b"  {\"path\": \"~/shed\"}"), Format::Json);
+    assert_eq!(Format::sniff(b"#![enable(implicit_some)]\n("), Format::Ron);
+    assert_eq!(Format::sniff(b"(path: \"~/shed\")"), Format::Ron);
+    // bincode length prefix of a 40 byte path starts with '('
+    assert_eq!(Format::sniff(b"(\0\0\0\0\0\0\0/home"), Format::Bin);
+    assert_eq!(Format::sniff(&[0xff, 0xfe, 0x00]), Format::Bin);
+  }
+}
@@ -0,0 +1,273 @@
+//! config/check.rs --- config validation
+/*!
+A config which parses can still be wrong. `check` looks for the
+mistakes serde can't catch and returns every problem it finds rather
+than stopping at the first:
+
+- a name used by more than one package or project
+- a shed path or project directory missing on disk
+- a `socket` under `net` or `hg.web` which isn't a `host:port`
+- a `hg.web.paths` entry pointing at something that isn't a repo
+
+Fields are checked through the same JSON tree used by `Layers`, so a
+problem is reported with the dotted path `shc config get` accepts.
+*/
+use super::{layer::lookup, Config};
+
+use serde_json::Value;
+
+use std::{collections::HashMap, fmt, net::SocketAddr};
+
+/// How bad a `Problem` is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+  /// something which may be fixed later, like a project which hasn't
+  /// been cloned yet
+  Warn,
+  Error,
+}
+
+impl fmt::Display for Level {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(match self {
+      Level::Warn => "warning",
+      Level::Error => "error",
+    })
+  }
+}
+
+/// A single problem found in a config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+  pub level: Level,
+  /// dotted path of the offending field
+  pub field: String,
+  pub msg: String,
+}
+
+impl Problem {
+  fn new(level: Level, field: impl Into<String>, msg: impl Into<String>) -> Self {
+    Problem {
+      level,
+      field: field.into(),
+      msg: msg.into(),
+    }
+  }
+}
+
+impl fmt::Display for Problem {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}: {}: {}", self.level, self.field, self.msg)
+  }
+}
+
+/// Validate `cfg`, returning every problem found
+pub fn check(cfg: &Config) -> Vec<Problem> {
+  let mut out = vec![];
+  let tree = match serde_json::to_value(cfg) {
+    Ok(t) => t,
+    Err(e) => return vec![Problem::new(Level::Error, "", e.to_string())],
+  };
+  names(cfg, &mut out);
+  paths(cfg, &mut out);
+  for f in ["net", "hg.web"] {
+    if let Some(v) = lookup(&tree, f) {
+      sockets(v, f, &mut out);
+    }
+  }
+  if let Some(v) = lookup(&tree, "hg.web.paths") {
+    web_paths(cfg, v, &mut out);
+  }
+  out
+}
+
+/// Package and project names must be unique, they are looked up
+/// across both `src` and `lab`
+fn names(cfg: &Config, out: &mut Vec<Problem>) {
+  let src = cfg
+    .src
+    .iter()
+    .enumerate()
+    .map(|(i, p)| (p.name.as_str(), format!("src.{}.name", i)));
+  let lab = cfg
+    .lab
+    .iter()
+    .enumerate()
+    .map(|(i, p)| (p.name.as_str(), format!("lab.{}.name", i)));
+  duplicates(src.chain(lab), out)
+}
+
+fn duplicates<'a, I: Iterator<Item = (&'a str, String)>>(names: I, out: &mut Vec<Problem>) {
+  let mut seen: HashMap<&str, String> = HashMap::new();
+  for (name, field) in names {
+    if name.is_empty() {
+      out.push(Problem::new(Level::Error, field, "empty name"));
+    } else if let Some(first) = seen.get(name) {
+      out.push(Problem::new(
+        Level::Error,
+        field,
+        format!("duplicate name '{}', first used by {}", name, first),
+      ));
+    } else {
+      seen.insert(name, field);
+    }
+  }
+}
+
+fn paths(cfg: &Config, out: &mut Vec<Problem>) {
+  if !cfg.path.is_dir() {
+    out.push(Problem::new(
+      Level::Error,
+      "path",
+      format!("{} does not exist, run 'shc init'", cfg.path.display()),
+    ));
+    return;
+  }
+  for (i, p) in cfg.src.iter().enumerate() {
+    let dir = cfg.path.join("src").join(&p.name);
+    if !dir.is_dir() {
+      out.push(Problem::new(
+        Level::Warn,
+        format!("src.{}", i),
+        format!("{} does not exist", dir.display()),
+      ));
+    }
+  }
+  for (i, p) in cfg.lab.iter().enumerate() {
+    let dir = cfg.path.join("lab").join(&p.name);
+    if !dir.is_dir() {
+      out.push(Problem::new(
+        Level::Warn,
+        format!("lab.{}", i),
+        format!("{} does not exist", dir.display()),
+      ));
+    }
+  }
+}
+
+/// Check every string field named `socket` below `v`
+fn sockets(v: &Value, path: &str, out: &mut Vec<Problem>) {
+  if let Value::Object(m) = v {
+    for (k, c) in m {
+      let p = format!("{}.{}", path, k);
+      match c {
+        Value::String(s) if k == "socket" && !is_socket(s) => out.push(Problem::new(
+          Level::Error,
+          p,
+          format!("'{}' is not a valid host:port address", s),
+        )),
+        Value::Object(_) => sockets(c, &p, out),
+        _ => (),
+      }
+    }
+  }
+}
+
+/// true for an IP socket address or a `host:port` pair
+pub fn is_socket(s: &str) -> bool {
+  if s.parse::<SocketAddr>().is_ok() {
+    return true;
+  }
+  match s.rsplit_once(':') {
+    Some((host, port)) => {
+      port.parse::<u16>().is_ok()
+        && !host.is_empty()
+        && host
+          .chars()
+          .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+    }
+    None => false,
+  }
+}
+
+/// hgweb serves `name = path` pairs, each path must be a Mercurial
+/// repo. Relative paths are taken from the shed root.
+fn web_paths(cfg: &Config, v: &Value, out: &mut Vec<Problem>) {
+  let pairs: Vec<(String, &Value)> = match v {
+    Value::Object(m) => m.iter().map(|(k, v)| (k.clone(), v)).collect(),
+    Value::Array(a) => a
+      .iter()
+      .enumerate()
+      .map(|(i, v)| (i.to_string(), v))
+      .collect(),
+    _ => return,
+  };
+  for (k, v) in pairs {
+    // entries may be a plain path or a [name, path] pair
+    let repo = match v {
+      Value::String(s) => s,
+      Value::Array(a) => match a.last() {
+        Some(Value::String(s)) => s,
+        _ => continue,
+      },
+      _ => continue,
+    };
+    // hgweb collections end in '*' or '**'
+    let dir = cfg.path.join(repo.trim_end_matches('*'));
+    let ok = if repo.ends_with('*') {
+      dir.is_dir()
+    } else {
+      dir.join(".hg").is_dir()
+    };
+    if !ok {
+      out.push(Problem::new(
+        Level::Error,
+        format!("hg.web.paths.{}", k),
+        format!("unknown repo {}", dir.display()),
+      ));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::TempDir;
+  use serde_json::json;
+
+  #[test]
+  fn test_is_socket() {
+    assert!(is_socket("127.0.0.1:0"));
+    assert!(is_socket("[::1]:8080"));
+    assert!(is_socket("localhost:8080"));
+    assert!(!is_socket("localhost"));
+    assert!(!is_socket("127.0.0.1:99999"));
+    assert!(!is_socket(":80"));
+    assert!(!is_socket("my host:80"));
+  }
+
+  #[test]
+  fn test_duplicates() {
+    let mut out = vec![];
+    let names = vec![
+      ("a", "src.0.name".to_owned()),
+      ("b", "src.1.name".to_owned()),
+      ("", "src.2.name".to_owned()),
+      ("a", "lab.0.name".to_owned()),
+    ];
+    duplicates(names.into_iter(), &mut out);
+    let fields: Vec<&str> = out.iter().map(|p| p.field.as_str()).collect();
+    assert_eq!(fields, ["src.2.name", "lab.0.name"]);
+    assert!(out[1].msg.contains("src.0.name"));
+  }
+
+  #[test]
+  fn test_sockets() {
+    let mut out = vec![];
+    let v = json!({"socket": "0.0.0.0:80", "peers": {"socket": "nope"}, "name": "x"});
+    sockets(&v, "net", &mut out);
+    assert_eq!(out.len(), 1);
+    assert_eq!(out[0].field, "net.peers.socket");
+  }
+
+  #[test]
+  fn test_missing_root() {
+    let mut cfg = Config::new();
+    let tmp = TempDir::new("check");
+    cfg.path = tmp.join("missing");
+    let problems = check(&cfg);
+    assert!(problems
+      .iter()
+      .any(|p| p.field == "path" && p.level == Level::Error));
+  }
+}
@@ -0,0 +1,144 @@
+//! config/expand.rs --- path expansion
+/*!
+Paths in a config may start with `~` and contain `$VAR` or `${VAR}`.
+They are stored as written and only expanded in the effective config
+built by `App::new`, so `shc config set` and `shc config convert`
+never bake the current `$HOME` into a file.
+
+The rlib configs don't say which of their strings are paths, so
+fields are picked by name: every string under `path`, `paths`,
+`root`, `dir` or a key ending in `_path`, `_dir` or `_file` is
+expanded, and any other string only when it starts with `~` or `$`.
+
+Variables which aren't set are left as they are, which keeps the
+mistake visible in error messages instead of silently producing a
+path relative to the root.
+*/
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use std::{
+  env,
+  path::{Path, PathBuf},
+};
+
+/// Expand a leading `~` and any `$VAR` or `${VAR}` in `s`
+pub fn expand(s: &str) -> String {
+  let s = match s.strip_prefix('~') {
+    Some(rest) if rest.is_empty() || rest.starts_with('/') => match env::var("HOME") {
+      Ok(home) => format!("{}{}", home, rest),
+      Err(_) => s.to_owned(),
+    },
+    _ => s.to_owned(),
+  };
+  let mut out = String::with_capacity(s.len());
+  let mut rest = s.as_str();
+  while let Some(i) = rest.find('$') {
+    out.push_str(&rest[..i]);
+    let after = &rest[i + 1..];
+    let (name, len) = match after.strip_prefix('{') {
+      Some(b) => match b.find('}') {
+        Some(j) => (&b[..j], j + 2),
+        None => ("", 0),
+      },
+      None => {
+        let j = after
+          .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+          .unwrap_or(after.len());
+        (&after[..j], j)
+      }
+    };
+    match env::var(name) {
+      Ok(v) if !name.is_empty() => out.push_str(&v),
+      _ => out.push_str(&rest[i..i + 1 + len]),
+    }
+    rest = &after[len..];
+  }
+  out.push_str(rest);
+  out
+}
+
+/// `expand` for paths
+pub fn expand_path<P: AsRef<Path>>(p: P) -> PathBuf {
+  expand(&p.as_ref().to_string_lossy()).into()
+}
+
+/// true for the names of fields holding paths
+fn is_path_key(k: &str) -> bool {
+  matches!(k, "path" | "paths" | "root" | "dir")
+    || ["_path", "_dir", "_file"].iter().any(|s| k.ends_with(s))
+}
+
+/// Expand the paths in `v`: every string below a path field, and any
+/// other string starting with `~` or `$`
+pub fn expand_value(v: &mut Value) {
+  walk(v, false)
+}
+
+fn walk(v: &mut Value, path: bool) {
+  match v {
+    Value::String(s) if path || s.starts_with('~') || s.starts_with('$') => *s = expand(s),
+    Value::Array(a) => a.iter_mut().for_each(|v| walk(v, path)),
+    Value::Object(m) => m
+      .iter_mut()
+      .for_each(|(k, v)| walk(v, path || is_path_key(k))),
+    _ => (),
+  }
+}
+
+/// Apply `expand_value` to `t` through its serialized form. `t` is
+/// left untouched if it no longer deserializes.
+pub fn expand_fields<T: Serialize + DeserializeOwned>(t: &mut T) {
+  if let Ok(mut v) = serde_json::to_value(&*t) {
+    expand_value(&mut v);
+    if let Ok(x) = serde_json::from_value(v) {
+      *t = x;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_expand() {
+    let home = env::var("HOME").unwrap();
+    env::set_var("SHED_EXPAND_TEST", "x");
+    assert_eq!(expand("~"), home);
+    assert_eq!(expand("~/shed"), format!("{}/shed", home));
+    assert_eq!(expand("~user/shed"), "~user/shed");
+    assert_eq!(expand("/a/$SHED_EXPAND_TEST/b"), "/a/x/b");
+    assert_eq!(expand("/a/${SHED_EXPAND_TEST}b"), "/a/xb");
+    assert_eq!(expand("$SHED_EXPAND_TEST.d"), "x.d");
+    assert_eq!(expand("/a/$SHED_EXPAND_UNSET/b"), "/a/$SHED_EXPAND_UNSET/b");
+    assert_eq!(expand("/a/${SHED_EXPAND_TEST"), "/a/${SHED_EXPAND_TEST");
+    assert_eq!(expand("cost: $"), "cost: $");
+  }
+
+  #[test]
+  fn test_expand_value() {
+    env::set_var("SHED_EXPAND_VALUE", "/v");
+    let mut v = json!({
+      "name": "shed-$SHED_EXPAND_VALUE",
+      "path": "$SHED_EXPAND_VALUE/src",
+      "l": ["~x", 1],
+      "src_dir": "/a${SHED_EXPAND_VALUE}",
+      "paths": {"hg": "/srv$SHED_EXPAND_VALUE/hg"},
+      "cmd": "echo $SHED_EXPAND_VALUE"
+    });
+    expand_value(&mut v);
+    assert_eq!(
+      v,
+      json!({
+        "name": "shed-$SHED_EXPAND_VALUE",
+        "path": "/v/src",
+        "l": ["~x", 1],
+        "src_dir": "/a/v",
+        "paths": {"hg": "/srv/v/hg"},
+        "cmd": "echo $SHED_EXPAND_VALUE"
+      })
+    );
+  }
+}
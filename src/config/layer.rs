@@ -0,0 +1,572 @@
+//! config/layer.rs --- layered configuration
+/*!
+The effective `Config` is resolved from a stack of layers, lowest
+first:
+
+1. built-in defaults (`Config::new`)
+2. the system file `/etc/shed/shed.cfg`
+3. the user file `~/.config/shed/shed.cfg`
+4. the file named by `$SHED_CFG`
+5. the file given with `-c`
+6. `SHED_*` environment variables
+
+A file layer only overrides the fields written out in it, so a user
+file won't clobber settings from the system file that it leaves
+untouched, even when it spells out a default value. Top-level fields
+may be left out altogether, and `shc config set` writes back only the
+fields a file already had plus the one it sets. Bincode files
+carry no field names and override everything. A `SHED_*` variable
+sets a single field: `SHED_NET_SOCKET` sets `net.socket`, and `SHED`
+itself sets `path`. String fields take the variable as is, others
+parse it as JSON. Values are merged as a JSON tree, which also keeps
+track of the layer each value came from.
+*/
+use super::{expand::expand_path, Config, Format};
+
+use crate::{Error, Result};
+
+use rlib::{logger::log::info, obj::ron};
+use serde_json::Value;
+
+use std::{
+  collections::BTreeMap,
+  env, fmt, fs,
+  path::{Path, PathBuf},
+};
+
+/// The system-wide config file
+pub const SYSTEM_CFG: &str = "/etc/shed/shed.cfg";
+
+/// The source of a configuration value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Layer {
+  Default,
+  System(PathBuf),
+  User(PathBuf),
+  EnvFile(PathBuf),
+  Cli(PathBuf),
+  Env(String),
+}
+
+impl Layer {
+  /// The file backing this layer
+  pub fn path(&self) -> Option<&Path> {
+    match self {
+      Layer::System(p) | Layer::User(p) | Layer::EnvFile(p) | Layer::Cli(p) => Some(p),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for Layer {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Layer::Default => f.write_str("default"),
+      Layer::System(p) => write!(f, "system: {}", p.display()),
+      Layer::User(p) => write!(f, "user: {}", p.display()),
+      Layer::EnvFile(p) => write!(f, "$SHED_CFG: {}", p.display()),
+      Layer::Cli(p) => write!(f, "-c: {}", p.display()),
+      Layer::Env(v) => write!(f, "env: ${}", v),
+    }
+  }
+}
+
+/// A resolved configuration with the origin of every value
+#[derive(Debug, Clone)]
+pub struct Layers {
+  defaults: Value,
+  value: Value,
+  /// dotted path -> layer, for every value not taken from the
+  /// defaults
+  origin: BTreeMap<String, Layer>,
+  /// the file `shc config set` writes to
+  target: PathBuf,
+}
+
+impl Layers {
+  /// Start from the built-in defaults
+  pub fn new() -> Result<Self> {
+    let defaults = serde_json::to_value(Config::new())?;
+    Ok(Layers {
+      value: defaults.clone(),
+      defaults,
+      origin: BTreeMap::new(),
+      target: user_cfg(),
+    })
+  }
+
+  /// Resolve every layer, with `cli` being the path given with `-c`
+  pub fn load(cli: Option<&str>) -> Result<Self> {
+    Layers::resolve(files(cli), env::vars())
+  }
+
+  /// Apply the file layers in `files`, lowest first, followed by the
+  /// `SHED_*` variables in `vars`
+  pub fn resolve<I>(files: Vec<Layer>, vars: I) -> Result<Self>
+  where
+    I: IntoIterator<Item = (String, String)>,
+  {
+    let mut layers = Layers::new()?;
+    for l in files {
+      layers.file(l)?;
+    }
+    layers.env(vars);
+    Ok(layers)
+  }
+
+  /// Apply a file layer
+  pub fn file(&mut self, layer: Layer) -> Result<&mut Self> {
+    let path = match layer.path() {
+      Some(p) => p.to_path_buf(),
+      None => return Err(Error::Config(format!("{} is not a file layer", layer))),
+    };
+    info!("loading config layer {}", layer);
+    let v = serde_json::to_value(Config::load(&path)?)?;
+    let fields = written(&path, &v)?;
+    overlay(&mut self.value, &v, &fields, "", &mut self.origin, &layer);
+    if !matches!(layer, Layer::System(_)) {
+      self.target = path;
+    }
+    Ok(self)
+  }
+
+  /// Apply `SHED_*` environment variables. Unknown fields are
+  /// ignored.
+  pub fn env<I: IntoIterator<Item = (String, String)>>(&mut self, vars: I) -> &mut Self {
+    for (k, v) in vars {
+      let path = match k.as_str() {
+        "SHED" => Some(vec!["path".to_owned()]),
+        "SHED_CFG" => None,
+        k => k
+          .strip_prefix("SHED_")
+          .and_then(|f| env_path(&self.value, f)),
+      };
+      if let Some(path) = path {
+        let path = path.join(".");
+        let v = parse_value(&v, lookup(&self.value, &path));
+        if set(&mut self.value, &self.defaults, &path, v).is_ok() {
+          self.mark(&path, Layer::Env(k));
+        }
+      }
+    }
+    self
+  }
+
+  /// Build the effective `Config`, with paths left as written
+  pub fn config(&self) -> Result<Config> {
+    Ok(serde_json::from_value(self.value.clone())?)
+  }
+
+  /// The value at the dotted `path` and the layers it came from
+  pub fn get(&self, path: &str) -> Option<(&Value, Vec<&Layer>)> {
+    let v = lookup(&self.value, path)?;
+    Some((v, self.origin_of(path)))
+  }
+
+  /// Every leaf value with its dotted path and layer
+  pub fn leaves(&self) -> Vec<(String, &Value, &Layer)> {
+    let mut out = vec![];
+    leaves(&self.value, String::new(), &mut out);
+    out
+      .into_iter()
+      .map(|(p, v)| {
+        let l = self.origin_of(&p).pop().unwrap_or(&Layer::Default);
+        (p, v, l)
+      })
+      .collect()
+  }
+
+  /// The built-in defaults as a JSON tree
+  pub fn defaults(&self) -> &Value {
+    &self.defaults
+  }
+
+  /// The file written by `shc config set`: the last file layer which
+  /// was loaded, or the user file
+  pub fn target(&self) -> &Path {
+    &self.target
+  }
+
+  fn origin_of(&self, path: &str) -> Vec<&Layer> {
+    // an ancestor set the whole value
+    if let Some(l) = self
+      .origin
+      .iter()
+      .rfind(|(k, _)| is_prefix(k, path))
+      .map(|(_, l)| l)
+    {
+      return vec![l];
+    }
+    let mut ls: Vec<&Layer> = vec![];
+    for (_, l) in self.origin.iter().filter(|(k, _)| is_prefix(path, k)) {
+      if !ls.contains(&l) {
+        ls.push(l);
+      }
+    }
+    if ls.is_empty() {
+      ls.push(&Layer::Default);
+    }
+    ls
+  }
+
+  fn mark(&mut self, path: &str, layer: Layer) {
+    self.origin.retain(|k, _| !is_prefix(path, k));
+    self.origin.insert(path.to_owned(), layer);
+  }
+}
+
+/// The per-user config file
+pub fn user_cfg() -> PathBuf {
+  Path::new(&env::var("HOME").unwrap_or_default()).join(".config/shed/shed.cfg")
+}
+
+/// The file layers to load, lowest first. The system and user files
+/// are skipped when missing, `$SHED_CFG` and `-c` never are.
+pub fn files(cli: Option<&str>) -> Vec<Layer> {
+  let mut out = vec![];
+  let system = PathBuf::from(SYSTEM_CFG);
+  if system.is_file() {
+    out.push(Layer::System(system));
+  }
+  let user = user_cfg();
+  if user.is_file() {
+    out.push(Layer::User(user));
+  }
+  if let Ok(p) = env::var("SHED_CFG") {
+    out.push(Layer::EnvFile(expand_path(p)));
+  }
+  if let Some(p) = cli {
+    out.push(Layer::Cli(expand_path(p)));
+  }
+  out
+}
+
+/// Parse a raw value for a field currently holding `current`. String
+/// fields take `s` as is, anything else is parsed as JSON, falling
+/// back to a plain string.
+pub fn parse_value(s: &str, current: Option<&Value>) -> Value {
+  match current {
+    Some(Value::String(_)) => Value::String(s.to_owned()),
+    _ => serde_json::from_str(s).unwrap_or_else(|_| Value::String(s.to_owned())),
+  }
+}
+
+/// Set the value at the dotted `path` in `root`. The parent must
+/// already exist and the field must be in `defaults`, unless the
+/// parent is a map, which is an empty object in the defaults.
+pub fn set(root: &mut Value, defaults: &Value, path: &str, value: Value) -> Result<()> {
+  let (parent, key) = match path.rsplit_once('.') {
+    Some((p, k)) => (lookup_mut(root, p), k),
+    None => (Some(root), path),
+  };
+  let fields = match path.rsplit_once('.') {
+    Some((p, _)) => lookup(defaults, p),
+    None => Some(defaults),
+  };
+  let known = match fields {
+    Some(Value::Object(d)) => d.is_empty() || d.contains_key(key),
+    // inside a map or list entry, which the defaults don't describe
+    None => true,
+    Some(_) => false,
+  };
+  match parent {
+    Some(Value::Object(m)) if known => {
+      m.insert(key.to_owned(), value);
+      Ok(())
+    }
+    _ => Err(Error::Usage(format!("no such config field: {}", path))),
+  }
+}
+
+/// Look up the dotted `path` in `root`
+pub fn lookup<'a>(root: &'a Value, path: &str) -> Option<&'a Value> {
+  if path.is_empty() {
+    return Some(root);
+  }
+  path.split('.').try_fold(root, |v, k| match v {
+    Value::Object(m) => m.get(k),
+    Value::Array(a) => a.get(k.parse::<usize>().ok()?),
+    _ => None,
+  })
+}
+
+fn lookup_mut<'a>(root: &'a mut Value, path: &str) -> Option<&'a mut Value> {
+  path.split('.').try_fold(root, |v, k| match v {
+    Value::Object(m) => m.get_mut(k),
+    Value::Array(a) => a.get_mut(k.parse::<usize>().ok()?),
+    _ => None,
+  })
+}
+
+/// Merge the fields of `new` named in `written` into `base`
+fn overlay(
+  base: &mut Value,
+  new: &Value,
+  written: &Value,
+  path: &str,
+  origin: &mut BTreeMap<String, Layer>,
+  layer: &Layer,
+) {
+  match (base, new, written) {
+    (Value::Object(b), Value::Object(n), Value::Object(w)) => {
+      for (k, w) in w {
+        let v = match n.get(k) {
+          Some(v) => v,
+          None => continue,
+        };
+        let p = join(path, k);
+        match b.get_mut(k) {
+          Some(bv) => overlay(bv, v, w, &p, origin, layer),
+          None => {
+            b.insert(k.clone(), v.clone());
+            origin.insert(p, layer.clone());
+          }
+        }
+      }
+    }
+    (b, n, _) => {
+      *b = n.clone();
+      origin.retain(|k, _| !is_prefix(path, k));
+      origin.insert(path.to_owned(), layer.clone());
+    }
+  }
+}
+
+/// The top-level fields written out in the config file at `path`
+pub fn fields(path: &Path) -> Result<Vec<String>> {
+  let v = serde_json::to_value(Config::load(path)?)?;
+  Ok(match written(path, &v)? {
+    Value::Object(m) => m.into_iter().map(|(k, _)| k).collect(),
+    _ => vec![],
+  })
+}
+
+/// The fields written out in the config file at `path`, which loaded
+/// as `v`. Bincode has no field names, so all of `v` counts.
+fn written(path: &Path, v: &Value) -> Result<Value> {
+  let text = || fs::read_to_string(path);
+  Ok(match Format::detect(path)? {
+    Format::Json => serde_json::from_str(&text()?)?,
+    Format::Ron => ron_fields(&ron::de::from_str(&text()?).map_err(Error::config)?),
+    Format::Bin => v.clone(),
+  })
+}
+
+/// The struct and map keys of a RON value, with `true` at the leaves
+fn ron_fields(v: &ron::Value) -> Value {
+  match v {
+    ron::Value::Map(m) => Value::Object(
+      m.iter()
+        .filter_map(|(k, v)| match k {
+          ron::Value::String(k) => Some((k.clone(), ron_fields(v))),
+          _ => None,
+        })
+        .collect(),
+    ),
+    ron::Value::Option(Some(v)) => ron_fields(v),
+    _ => Value::Bool(true),
+  }
+}
+
+/// Resolve an upper-case env var suffix such as `NET_SOCKET` against
+/// the fields of `v`
+fn env_path(v: &Value, name: &str) -> Option<Vec<String>> {
+  let m = v.as_object()?;
+  for (k, child) in m {
+    let key = k.to_uppercase();
+    if name == key {
+      return Some(vec![k.clone()]);
+    }
+    if let Some(rest) = name.strip_prefix(&key).and_then(|r| r.strip_prefix('_')) {
+      if let Some(mut p) = env_path(child, rest) {
+        p.insert(0, k.clone());
+        return Some(p);
+      }
+    }
+  }
+  None
+}
+
+fn leaves<'a>(v: &'a Value, path: String, out: &mut Vec<(String, &'a Value)>) {
+  match v {
+    Value::Object(m) if !m.is_empty() => {
+      for (k, c) in m {
+        leaves(c, join(&path, k), out);
+      }
+    }
+    v => out.push((path, v)),
+  }
+}
+
+fn join(path: &str, key: &str) -> String {
+  if path.is_empty() {
+    key.to_owned()
+  } else {
+    format!("{}.{}", path, key)
+  }
+}
+
+/// true when `a` equals `b` or is one of its ancestors
+fn is_prefix(a: &str, b: &str) -> bool {
+  a.is_empty() || b == a || b.starts_with(a) && b[a.len()..].starts_with('.')
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::TempDir;
+  use serde_json::json;
+
+  fn layers(defaults: Value) -> Layers {
+    Layers {
+      value: defaults.clone(),
+      defaults,
+      origin: BTreeMap::new(),
+      target: PathBuf::new(),
+    }
+  }
+
+  #[test]
+  fn test_overlay_written_values() {
+    let mut l = layers(json!({"path": "~/shed", "net": {"socket": "127.0.0.1:0", "peers": null}}));
+    let sys = json!({"path": "~/shed", "net": {"socket": "0.0.0.0:80", "peers": null}});
+    let usr = json!({"path": "/srv/shed", "net": {"socket": "0.0.0.0:80", "peers": null}});
+    let cli = json!({"path": "/srv/shed", "net": {"socket": "127.0.0.1:0", "peers": null}});
+    let (s, u, c) = (
+      Layer::System("s".into()),
+      Layer::User("u".into()),
+      Layer::Cli("c".into()),
+    );
+    overlay(
+      &mut l.value,
+      &sys,
+      &json!({"net": {"socket": true}}),
+      "",
+      &mut l.origin,
+      &s,
+    );
+    overlay(
+      &mut l.value,
+      &usr,
+      &json!({"path": true}),
+      "",
+      &mut l.origin,
+      &u,
+    );
+    assert_eq!(
+      l.get("net.socket").unwrap(),
+      (&json!("0.0.0.0:80"), vec![&s])
+    );
+    assert_eq!(l.get("path").unwrap(), (&json!("/srv/shed"), vec![&u]));
+    assert_eq!(l.get("net.peers").unwrap().1, vec![&Layer::Default]);
+    assert_eq!(l.get("net").unwrap().1, vec![&s]);
+    // spelling out a default still overrides the layers below
+    overlay(
+      &mut l.value,
+      &cli,
+      &json!({"net": {"socket": 1}}),
+      "",
+      &mut l.origin,
+      &c,
+    );
+    assert_eq!(
+      l.get("net.socket").unwrap(),
+      (&json!("127.0.0.1:0"), vec![&c])
+    );
+  }
+
+  #[test]
+  fn test_written() {
+    let tmp = TempDir::new("layer");
+    let v = json!({"path": "~/shed", "hg": {"paths": {"a": "b"}, "ui": {}}});
+    let ron = tmp.join("shed.ron");
+    fs::write(
+      &ron,
+      r#"Config(hg: (paths: Some({"a": "b"})), usr: (shell: (shell: Bash)))"#,
+    )
+    .unwrap();
+    assert_eq!(
+      written(&ron, &v).unwrap(),
+      json!({"hg": {"paths": {"a": true}}, "usr": {"shell": {"shell": true}}})
+    );
+    let js = tmp.join("shed.json");
+    fs::write(&js, r#"{"path": "/srv"}"#).unwrap();
+    assert_eq!(written(&js, &v).unwrap(), json!({"path": "/srv"}));
+    let bin = tmp.join("shed.bin");
+    fs::write(&bin, [0u8]).unwrap();
+    assert_eq!(written(&bin, &v).unwrap(), v);
+  }
+
+  #[test]
+  fn test_partial_files() {
+    let tmp = TempDir::new("layer");
+    let sys = tmp.join("system.json");
+    fs::write(&sys, r#"{"path": "/opt/shed", "weather": {"ttl": 60}}"#).unwrap();
+    let usr = tmp.join("user.ron");
+    fs::write(
+      &usr,
+      r#"(path: "/srv/shed", scripts: {"shed": (build: Some("make b"))})"#,
+    )
+    .unwrap();
+    let (s, u) = (Layer::System(sys), Layer::User(usr.clone()));
+    let l = Layers::resolve(vec![s.clone(), u.clone()], vec![]).unwrap();
+    let cfg = l.config().unwrap();
+    assert_eq!(cfg.path, Path::new("/srv/shed"));
+    assert_eq!(cfg.weather.ttl, 60);
+    assert_eq!(cfg.scripts["shed"].build.as_deref(), Some("make b"));
+    assert_eq!(l.get("path").unwrap().1, vec![&u]);
+    assert_eq!(l.get("weather.ttl").unwrap().1, vec![&s]);
+    assert_eq!(l.get("src").unwrap().1, vec![&Layer::Default]);
+    assert_eq!(l.target(), usr);
+    assert_eq!(fields(&usr).unwrap(), ["path", "scripts"]);
+  }
+
+  #[test]
+  fn test_env() {
+    let mut l = layers(
+      json!({"path": "~/shed", "net": {"socket": "127.0.0.1:0", "port": 0}, "usr": {"shell": {"env": {}}}}),
+    );
+    l.env(vec![
+      ("SHED".to_owned(), "123".to_owned()),
+      ("SHED_NET_SOCKET".to_owned(), "0.0.0.0:8080".to_owned()),
+      ("SHED_NET_PORT".to_owned(), "8080".to_owned()),
+      ("SHED_NOPE".to_owned(), "1".to_owned()),
+      ("SHED_CFG".to_owned(), "/tmp/x".to_owned()),
+    ]);
+    assert_eq!(
+      l.get("path").unwrap(),
+      (&json!("123"), vec![&Layer::Env("SHED".into())])
+    );
+    assert_eq!(l.get("net.socket").unwrap().0, &json!("0.0.0.0:8080"));
+    assert_eq!(l.get("net.port").unwrap().0, &json!(8080));
+    assert!(l.get("nope").is_none());
+  }
+
+  #[test]
+  fn test_set() {
+    let defaults = json!({"usr": {"shell": {"env": {}}}, "src": []});
+    let mut v = json!({"usr": {"shell": {"env": {}}}, "src": [{"name": "a"}]});
+    set(
+      &mut v,
+      &defaults,
+      "usr.shell.env.EDITOR",
+      parse_value("vi", None),
+    )
+    .unwrap();
+    assert_eq!(lookup(&v, "usr.shell.env.EDITOR"), Some(&json!("vi")));
+    set(&mut v, &defaults, "src.0.name", json!("b")).unwrap();
+    assert_eq!(lookup(&v, "src.0.name"), Some(&json!("b")));
+    assert!(set(&mut v, &defaults, "usr.nope.x", json!(1)).is_err());
+    assert!(set(&mut v, &defaults, "usr.n", json!(5)).is_err());
+    assert!(set(&mut v, &defaults, "nope", json!(5)).is_err());
+    assert!(lookup(&v, "usr.n").is_none());
+  }
+
+  #[test]
+  fn test_parse_value() {
+    assert_eq!(parse_value("123", Some(&json!("~/shed"))), json!("123"));
+    assert_eq!(parse_value("123", Some(&json!(0))), json!(123));
+    assert_eq!(parse_value("true", None), json!(true));
+    assert_eq!(parse_value("vi -f", None), json!("vi -f"));
+  }
+}
@@ -0,0 +1,416 @@
+//! download.rs --- HTTP downloads
+/*!
+Downloads are streamed chunk by chunk into a `.part` file in
+`stash/tmp`, with the server's ETag or Last-Modified date saved next
+to it. When both are present the transfer is resumed with an HTTP
+Range request guarded by `If-Range`, so a file which changed remotely
+is fetched again from the start instead of being spliced onto stale
+data. A `.part` file without a validator, or a reply for the wrong
+range, also starts over. Once the body is complete it is
+optionally verified against a SHA-256 digest and then renamed into the
+destination directory, so a finished file never appears half-written.
+*/
+use indicatif::{ProgressBar, ProgressStyle};
+use rlib::{
+  logger::log::warn,
+  net::reqwest::{
+    header::{
+      HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE,
+    },
+    Client, StatusCode, Url,
+  },
+};
+use sha2::{Digest, Sha256};
+
+use std::{
+  error, fmt,
+  fs::{self, File, OpenOptions},
+  io::{self, Read, Write},
+  path::{Path, PathBuf},
+};
+
+/// A single HTTP file download
+#[derive(Debug, Clone)]
+pub struct Download {
+  url: Url,
+  tmp: PathBuf,
+  dst: PathBuf,
+  sha256: Option<String>,
+  progress: bool,
+}
+
+impl Download {
+  /// Download `url` into `dst`, keeping partial data in `tmp`
+  pub fn new<P: AsRef<Path>>(url: Url, tmp: P, dst: P) -> Self {
+    Download {
+      url,
+      tmp: tmp.as_ref().to_path_buf(),
+      dst: dst.as_ref().to_path_buf(),
+      sha256: None,
+      progress: true,
+    }
+  }
+
+  /// Verify the completed file against a hex-encoded SHA-256 digest
+  pub fn sha256(mut self, digest: Option<&str>) -> Self {
+    self.sha256 = digest.map(|d| d.trim().to_lowercase());
+    self
+  }
+
+  /// Show a progress bar on stderr
+  pub fn progress(mut self, show: bool) -> Self {
+    self.progress = show;
+    self
+  }
+
+  /// The file name taken from the last segment of the URL path
+  pub fn file_name(&self) -> Result<String, Error> {
+    self
+      .url
+      .path_segments()
+      .and_then(|mut s| s.next_back())
+      .filter(|s| !s.is_empty())
+      .map(str::to_owned)
+      .ok_or_else(|| Error::FileName(self.url.clone()))
+  }
+
+  /// Path of the partial download
+  pub fn part_path(&self) -> Result<PathBuf, Error> {
+    Ok(self.tmp.join(format!("{}.part", self.file_name()?)))
+  }
+
+  /// Path of the validator saved next to the partial download
+  pub fn tag_path(&self) -> Result<PathBuf, Error> {
+    Ok(self.tmp.join(format!("{}.part.tag", self.file_name()?)))
+  }
+
+  /// Fetch the resource, returning the path of the completed file
+  pub async fn run(&self, client: &Client) -> Result<PathBuf, Error> {
+    let name = self.file_name()?;
+    let part = self.part_path()?;
+    let tag = self.tag_path()?;
+    let dst = self.dst.join(&name);
+    fs::create_dir_all(&self.tmp)?;
+    fs::create_dir_all(&self.dst)?;
+
+    let mut offset = fs::metadata(&part).map(|m| m.len()).unwrap_or(0);
+    // without a validator a changed remote file can't be detected
+    let validator = fs::read_to_string(&tag).ok().filter(|t| !t.is_empty());
+    if validator.is_none() {
+      offset = 0;
+    }
+    let (mut res, mut file) = loop {
+      let mut req = client.get(self.url.clone());
+      if let (true, Some(v)) = (offset > 0, &validator) {
+        req = req
+          .header(RANGE, format!("bytes={}-", offset))
+          .header(IF_RANGE, v.as_str());
+      }
+      let res = req.send().await?;
+      let (start, total) = content_range(res.headers());
+      match res.status() {
+        StatusCode::PARTIAL_CONTENT if offset > 0 && start == Some(offset) => {
+          break (res, OpenOptions::new().append(true).open(&part)?);
+        }
+        // the part file already holds the full body
+        StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 && total == Some(offset) => {
+          return self.finish(&part, &dst);
+        }
+        StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => {
+          warn!("{}: server sent the wrong range, starting over", name);
+          offset = 0;
+        }
+        s if s.is_success() && s != StatusCode::PARTIAL_CONTENT => {
+          // a full body, either fresh or because the remote changed
+          offset = 0;
+          match validator_of(res.headers()) {
+            Some(v) => fs::write(&tag, v)?,
+            None => remove(&tag)?,
+          }
+          break (res, File::create(&part)?);
+        }
+        s => return Err(Error::Status(s)),
+      }
+    };
+
+    let len = res
+      .headers()
+      .get(CONTENT_LENGTH)
+      .and_then(|v| v.to_str().ok())
+      .and_then(|v| v.parse::<u64>().ok())
+      .map(|l| l + offset);
+    let bar = match (self.progress, len) {
+      (false, _) => ProgressBar::hidden(),
+      (true, Some(l)) => ProgressBar::new(l).with_style(
+        ProgressStyle::default_bar()
+          .template("{msg} [{bar:32}] {bytes}/{total_bytes} {bytes_per_sec} {eta}")
+          .progress_chars("=> "),
+      ),
+      (true, None) => ProgressBar::new_spinner(),
+    };
+    bar.set_message(name);
+    bar.set_position(offset);
+    while let Some(chunk) = res.chunk().await? {
+      file.write_all(&chunk)?;
+      bar.inc(chunk.len() as u64);
+    }
+    file.sync_all()?;
+    bar.finish_and_clear();
+    self.finish(&part, &dst)
+  }
+
+  /// Verify `part` and move it to `dst`
+  fn finish(&self, part: &Path, dst: &Path) -> Result<PathBuf, Error> {
+    remove(&self.tag_path()?)?;
+    if let Some(expected) = &self.sha256 {
+      let actual = sha256_file(part)?;
+      if &actual != expected {
+        // a corrupt part file can't be resumed, start over next time
+        fs::remove_file(part)?;
+        return Err(Error::Checksum {
+          expected: expected.clone(),
+          actual,
+        });
+      }
+    }
+    fs::rename(part, dst)?;
+    Ok(dst.to_path_buf())
+  }
+}
+
+/// The `(start, total)` of a `Content-Range: bytes START-END/TOTAL`
+/// or `bytes */TOTAL` header
+fn content_range(headers: &HeaderMap) -> (Option<u64>, Option<u64>) {
+  let v = match headers
+    .get(CONTENT_RANGE)
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.strip_prefix("bytes "))
+  {
+    Some(v) => v,
+    None => return (None, None),
+  };
+  let (range, total) = v.split_once('/').unwrap_or((v, "*"));
+  let start = range.split_once('-').and_then(|(s, _)| s.parse().ok());
+  (start, total.parse().ok())
+}
+
+/// A strong ETag, or else the Last-Modified date, to send as `If-Range`
+fn validator_of(headers: &HeaderMap) -> Option<String> {
+  let get = |h| headers.get(h).and_then(|v: &HeaderValue| v.to_str().ok());
+  get(ETAG)
+    .filter(|e| !e.starts_with("W/"))
+    .or_else(|| get(LAST_MODIFIED))
+    .map(str::to_owned)
+}
+
+/// Remove `path` if it exists
+fn remove(path: &Path) -> io::Result<()> {
+  match fs::remove_file(path) {
+    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+    _ => Ok(()),
+  }
+}
+
+/// Hex-encoded SHA-256 digest of the file at `path`
+pub fn sha256_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
+  let mut f = File::open(path)?;
+  let mut hasher = Sha256::new();
+  let mut buf = [0; 8192];
+  loop {
+    match f.read(&mut buf)? {
+      0 => break,
+      n => hasher.update(&buf[..n]),
+    }
+  }
+  Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Download errors
+#[derive(Debug)]
+pub enum Error {
+  Http(rlib::net::reqwest::Error),
+  Io(io::Error),
+  Status(StatusCode),
+  Checksum { expected: String, actual: String },
+  FileName(Url),
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Error::Http(e) => write!(f, "{}", e),
+      Error::Io(e) => write!(f, "{}", e),
+      Error::Status(s) => write!(f, "server responded with {}", s),
+      Error::Checksum { expected, actual } => {
+        write!(f, "checksum mismatch: expected {}, got {}", expected, actual)
+      }
+      Error::FileName(u) => write!(f, "failed to parse a file name from {}", u),
+    }
+  }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+  fn from(e: io::Error) -> Self {
+    Error::Io(e)
+  }
+}
+
+impl From<rlib::net::reqwest::Error> for Error {
+  fn from(e: rlib::net::reqwest::Error) -> Self {
+    Error::Http(e)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{self, TempDir};
+  use hyper::{Body, Response};
+  use std::{
+    net::SocketAddr,
+    sync::{
+      atomic::{AtomicBool, Ordering},
+      Arc,
+    },
+  };
+
+  const BODY: &[u8] = &[0, 159, 146, 150, 255, 10, 13, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+  const BODY_SHA256: &str = "0f65b83bdb1ad6c04d6f10a06e41d4381c7e7c19f0985bf2a5e44667ded491ed";
+  const TAG: &str = "\"v1\"";
+
+  /// Serve `BODY` tagged `TAG` on a local socket, honoring
+  /// `Range: bytes=N-` when `If-Range` matches. A `bad` server sends
+  /// every range from the start.
+  fn serve(ranged: Arc<AtomicBool>, bad: bool) -> SocketAddr {
+    testing::serve(move |req| {
+      let h = |k| {
+        req
+          .headers()
+          .get(k)
+          .and_then(|v: &HeaderValue| v.to_str().ok())
+      };
+      let start = h(RANGE)
+        .and_then(|v| v.strip_prefix("bytes="))
+        .and_then(|v| v.trim_end_matches('-').parse::<usize>().ok())
+        .filter(|_| h(IF_RANGE) == Some(TAG));
+      let res = Response::builder().header(ETAG, TAG);
+      let len = BODY.len();
+      match start {
+        Some(s) if s >= len => res
+          .status(416)
+          .header(CONTENT_RANGE, format!("bytes */{}", len))
+          .body(Body::empty()),
+        Some(s) => {
+          ranged.store(true, Ordering::SeqCst);
+          let s = if bad { 0 } else { s };
+          res
+            .status(206)
+            .header(CONTENT_RANGE, format!("bytes {}-{}/{}", s, len - 1, len))
+            .body(Body::from(&BODY[s..]))
+        }
+        None => res.body(Body::from(BODY)),
+      }
+      .unwrap()
+    })
+  }
+
+  /// Download from `addr` with `part` and `tag` already in place
+  async fn fetch(addr: SocketAddr, part: Option<&[u8]>, tag: Option<&str>) -> PathBuf {
+    let root = TempDir::new("dl");
+    let (tmp, dst) = (root.join("tmp"), root.join("stash"));
+    let url = Url::parse(&format!("http://{}/f.bin", addr)).unwrap();
+    let dl = Download::new(url, &tmp, &dst).progress(false);
+    fs::create_dir_all(&tmp).unwrap();
+    if let Some(p) = part {
+      fs::write(dl.part_path().unwrap(), p).unwrap();
+    }
+    if let Some(t) = tag {
+      fs::write(dl.tag_path().unwrap(), t).unwrap();
+    }
+    let out = dl.run(&Client::new()).await.unwrap();
+    assert_eq!(fs::read(&out).unwrap(), BODY);
+    assert!(!dl.part_path().unwrap().exists());
+    assert!(!dl.tag_path().unwrap().exists());
+    out.strip_prefix(&*root).unwrap().to_path_buf()
+  }
+
+  #[tokio::test]
+  async fn test_download_binary() {
+    let root = TempDir::new("dl");
+    let addr = serve(Arc::new(AtomicBool::new(false)), false);
+    let url = Url::parse(&format!("http://{}/f.bin", addr)).unwrap();
+    let dl = Download::new(url, root.join("tmp"), root.join("stash"))
+      .sha256(Some(BODY_SHA256))
+      .progress(false);
+    let out = dl.run(&Client::new()).await.unwrap();
+    assert_eq!(out, root.join("stash/f.bin"));
+    assert_eq!(fs::read(&out).unwrap(), BODY);
+    assert!(!dl.part_path().unwrap().exists());
+  }
+
+  #[tokio::test]
+  async fn test_download_resume() {
+    let ranged = Arc::new(AtomicBool::new(false));
+    let addr = serve(ranged.clone(), false);
+    let out = fetch(addr, Some(&BODY[..5]), Some(TAG)).await;
+    assert_eq!(out, Path::new("stash/f.bin"));
+    assert!(ranged.load(Ordering::SeqCst));
+  }
+
+  #[tokio::test]
+  async fn test_download_restart() {
+    let ranged = Arc::new(AtomicBool::new(false));
+    let addr = serve(ranged.clone(), false);
+    // no validator, a stale one, and a part longer than the body
+    fetch(addr, Some(b"stale"), None).await;
+    fetch(addr, Some(b"stale"), Some("\"v0\"")).await;
+    let mut long = BODY.to_vec();
+    long.extend_from_slice(b"junk");
+    fetch(addr, Some(&long), Some(TAG)).await;
+    assert!(!ranged.load(Ordering::SeqCst));
+    // a complete part is only renamed
+    fetch(addr, Some(BODY), Some(TAG)).await;
+    // a server ignoring the offset
+    let ranged = Arc::new(AtomicBool::new(false));
+    let bad = serve(ranged.clone(), true);
+    fetch(bad, Some(&BODY[..5]), Some(TAG)).await;
+    assert!(ranged.load(Ordering::SeqCst));
+  }
+
+  #[test]
+  fn test_content_range() {
+    let mut h = HeaderMap::new();
+    assert_eq!(content_range(&h), (None, None));
+    h.insert(CONTENT_RANGE, HeaderValue::from_static("bytes 5-15/16"));
+    assert_eq!(content_range(&h), (Some(5), Some(16)));
+    h.insert(CONTENT_RANGE, HeaderValue::from_static("bytes */16"));
+    assert_eq!(content_range(&h), (None, Some(16)));
+    h.insert(ETAG, HeaderValue::from_static("W/\"x\""));
+    assert_eq!(validator_of(&h), None);
+    h.insert(
+      LAST_MODIFIED,
+      HeaderValue::from_static("Sat, 01 Jan 2000 00:00:00 GMT"),
+    );
+    assert_eq!(
+      validator_of(&h).as_deref(),
+      Some("Sat, 01 Jan 2000 00:00:00 GMT")
+    );
+  }
+
+  #[tokio::test]
+  async fn test_download_checksum_mismatch() {
+    let root = TempDir::new("dl");
+    let addr = serve(Arc::new(AtomicBool::new(false)), false);
+    let (tmp, dst) = (root.join("tmp"), root.join("stash"));
+    let url = Url::parse(&format!("http://{}/f.bin", addr)).unwrap();
+    let dl = Download::new(url, &tmp, &dst)
+      .sha256(Some(&"0".repeat(64)))
+      .progress(false);
+    let res = dl.run(&Client::new()).await;
+    assert!(matches!(res, Err(Error::Checksum { .. })));
+    assert!(!dst.join("f.bin").exists());
+    assert!(!dl.part_path().unwrap().exists());
+  }
+}
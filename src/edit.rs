@@ -0,0 +1,212 @@
+//! edit.rs --- shc edit
+/*!
+`shc edit` opens a file in the user's editor. The editor is the first
+of `VISUAL` and `EDITOR` set in `usr.shell.env`, then the same
+variables in the environment. Without either, `emacsclient -t` is
+used, connecting to the default Emacs server or to the `she` daemon,
+which is started when neither is running.
+
+Like git, a `VISUAL` or `EDITOR` command line is run by `sh`, so it
+may quote its arguments (`code --wait "--user-data-dir=/my dir"`).
+
+Named targets are resolved before paths, so `shc edit cfg` opens the
+config file even when a file called `cfg` exists in the current
+directory.
+*/
+use crate::{Config, Error, Result};
+
+use std::{
+  collections::HashMap,
+  env, fs,
+  path::{Path, PathBuf},
+  process::{Command, Stdio},
+  thread,
+  time::Duration,
+};
+
+/// Socket name of the `she` Emacs daemon
+pub const SHE_SOCKET: &str = "she";
+
+/// How long to wait for a freshly started `she` daemon
+const SHE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The program used to edit files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Editor {
+  /// a shell command line from `VISUAL` or `EDITOR`, the path is
+  /// appended
+  Cmd(String),
+  /// `emacsclient -t`, with the `she` daemon as a fallback
+  Emacs,
+}
+
+impl Editor {
+  /// Pick the editor from `configured` (`usr.shell.env`), then the
+  /// environment
+  pub fn resolve(configured: &HashMap<String, String>) -> Editor {
+    let keys = ["VISUAL", "EDITOR"];
+    keys
+      .iter()
+      .filter_map(|k| configured.get(*k).cloned())
+      .chain(keys.iter().filter_map(|k| env::var(k).ok()))
+      .find(|c| !c.trim().is_empty())
+      .map_or(Editor::Emacs, Editor::Cmd)
+  }
+
+  /// Open `path` and wait for the editor to exit
+  pub fn open(&self, path: &Path) -> Result<()> {
+    match self {
+      Editor::Cmd(c) => {
+        // `$0` names the editor in the shell's own messages
+        let mut sh = Command::new("sh");
+        sh.arg("-c").arg(format!("{} \"$@\"", c)).arg(c).arg(path);
+        run(&mut sh, c)
+      }
+      Editor::Emacs => {
+        let mut c = Command::new("emacsclient");
+        c.arg("-t");
+        if !server_up(None) {
+          if !server_up(Some(SHE_SOCKET)) {
+            start_she()?;
+          }
+          c.arg(format!("--socket-name={}", SHE_SOCKET));
+        }
+        run(c.arg(path), "emacsclient")
+      }
+    }
+  }
+}
+
+/// Resolve `input` to a path. `cfg` is the config file `config set`
+/// writes to, `log` the newest `shc` log and a package or project
+/// name its directory. Anything else is a path.
+pub fn target(cfg: &Config, cfg_file: &Path, input: &str) -> PathBuf {
+  match input {
+    "cfg" => cfg_file.to_path_buf(),
+    "log" => {
+      let dir = cfg.path.join("data/log");
+      newest(&dir).unwrap_or(dir)
+    }
+    i => match cfg.project(i) {
+      Some(p) => p.dir(&cfg.path),
+      None => PathBuf::from(i),
+    },
+  }
+}
+
+/// The most recently modified file in `dir`
+fn newest(dir: &Path) -> Option<PathBuf> {
+  fs::read_dir(dir)
+    .ok()?
+    .filter_map(|e| {
+      let e = e.ok()?;
+      let m = e.metadata().ok()?;
+      m.is_file().then(|| (m.modified().ok(), e.path()))
+    })
+    .max()
+    .map(|(_, p)| p)
+}
+
+/// Check whether an Emacs server answers on `socket`, or the default
+/// socket
+fn server_up(socket: Option<&str>) -> bool {
+  let mut c = Command::new("emacsclient");
+  if let Some(s) = socket {
+    c.arg(format!("--socket-name={}", s));
+  }
+  c.args(["--eval", "t"])
+    .stdin(Stdio::null())
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .status()
+    .map(|s| s.success())
+    .unwrap_or(false)
+}
+
+/// Start the `she` daemon in the background and wait for it to answer
+fn start_she() -> Result<()> {
+  // prefer the she installed next to this program
+  let she = env::current_exe()
+    .ok()
+    .map(|p| p.with_file_name("she"))
+    .filter(|p| p.is_file())
+    .unwrap_or_else(|| "she".into());
+  Command::new(&she)
+    .stdin(Stdio::null())
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .spawn()
+    .map_err(|e| Error::Ipc(format!("can't start {}: {}", she.display(), e)))?;
+  let step = Duration::from_millis(200);
+  let mut waited = Duration::ZERO;
+  while waited < SHE_TIMEOUT {
+    if server_up(Some(SHE_SOCKET)) {
+      return Ok(());
+    }
+    thread::sleep(step);
+    waited += step;
+  }
+  Err(Error::Ipc(format!(
+    "she didn't answer within {}s",
+    SHE_TIMEOUT.as_secs()
+  )))
+}
+
+/// Run the editor `prog`, failing when it can't start or exits
+/// non-zero
+fn run(cmd: &mut Command, prog: &str) -> Result<()> {
+  let status = cmd
+    .status()
+    .map_err(|e| Error::Ipc(format!("can't run {}: {}", prog, e)))?;
+  if status.success() {
+    Ok(())
+  } else {
+    Err(Error::Ipc(format!("{} exited with {}", prog, status)))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::TempDir;
+
+  #[test]
+  fn test_resolve_configured() {
+    let mut env = HashMap::new();
+    env.insert("EDITOR".to_owned(), "vi".to_owned());
+    assert_eq!(Editor::resolve(&env), Editor::Cmd("vi".to_owned()));
+    env.insert("VISUAL".to_owned(), "code -w".to_owned());
+    assert_eq!(Editor::resolve(&env), Editor::Cmd("code -w".to_owned()));
+  }
+
+  #[test]
+  fn test_open_cmd() {
+    let tmp = TempDir::new("edit");
+    let src = tmp.join("my notes");
+    fs::write(&src, "hi").unwrap();
+    let dst = tmp.join("new file");
+    let editor = Editor::Cmd(format!("cp -p '{}'", src.display()));
+    editor.open(&dst).unwrap();
+    assert_eq!(fs::read_to_string(&dst).unwrap(), "hi");
+    assert!(matches!(
+      Editor::Cmd("false".to_owned()).open(&dst),
+      Err(Error::Ipc(m)) if m.starts_with("false exited")
+    ));
+  }
+
+  #[test]
+  fn test_target() {
+    let root = TempDir::new("edit");
+    let mut cfg = Config::new();
+    cfg.path = root.to_path_buf();
+    let file = Path::new("/etc/shed.cfg");
+    assert_eq!(target(&cfg, file, "cfg"), file);
+    assert_eq!(target(&cfg, file, "src/x"), Path::new("src/x"));
+    // no logs yet
+    let logs = root.join("data/log");
+    assert_eq!(target(&cfg, file, "log"), logs);
+    fs::create_dir_all(logs.join("build")).unwrap();
+    fs::write(logs.join("shc_1.log"), "").unwrap();
+    assert_eq!(target(&cfg, file, "log"), logs.join("shc_1.log"));
+  }
+}
@@ -0,0 +1,212 @@
+//! error.rs --- shed errors
+/*!
+Every fallible operation in the library ends up as an `Error`. The
+variants are coarse on purpose: they say *what kind* of thing went
+wrong, which is what a script wrapping `shc` needs to decide whether
+to retry, prompt or give up. The message carries the details.
+
+Each variant maps to a stable process exit code:
+
+| code | variant   | meaning                                    |
+|------|-----------|--------------------------------------------|
+|    1 | `Other`   | anything not covered below                 |
+|    2 | `Usage`   | bad arguments or unknown names             |
+|    3 | `Config`  | a config file is missing, broken or wrong  |
+|    4 | `Io`      | filesystem errors                          |
+|    5 | `Net`     | network down, timeouts, bad HTTP status    |
+|    6 | `Vc`      | a version control command failed          |
+|    7 | `Dirty`   | a repo has uncommitted changes             |
+|    8 | `Archive` | packing or unpacking failed                |
+|    9 | `Ipc`     | a daemon, database or editor didn't answer |
+|   10 | `Script`  | a build, clean or test script failed       |
+|   11 | `Verify`  | a checksum didn't match                    |
+
+Codes are only ever added, never reused, so they are safe to match on.
+*/
+use crate::{config, download, uri::UriError};
+
+use std::{error, fmt, io, path::PathBuf};
+
+/// shed result type
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// shed error type
+#[derive(Debug)]
+pub enum Error {
+  Other(String),
+  Usage(String),
+  Config(String),
+  Io(io::Error),
+  Net(String),
+  Vc(String),
+  Dirty(PathBuf),
+  Archive(String),
+  Ipc(String),
+  Script(String),
+  Verify { expected: String, actual: String },
+}
+
+impl Error {
+  /// The process exit code for this error
+  pub fn code(&self) -> i32 {
+    match self {
+      Error::Other(_) => 1,
+      Error::Usage(_) => 2,
+      Error::Config(_) => 3,
+      Error::Io(_) => 4,
+      Error::Net(_) => 5,
+      Error::Vc(_) => 6,
+      Error::Dirty(_) => 7,
+      Error::Archive(_) => 8,
+      Error::Ipc(_) => 9,
+      Error::Script(_) => 10,
+      Error::Verify { .. } => 11,
+    }
+  }
+
+  pub fn usage<E: fmt::Display>(e: E) -> Self {
+    Error::Usage(e.to_string())
+  }
+
+  pub fn config<E: fmt::Display>(e: E) -> Self {
+    Error::Config(e.to_string())
+  }
+
+  pub fn net<E: fmt::Display>(e: E) -> Self {
+    Error::Net(e.to_string())
+  }
+
+  pub fn vc<E: fmt::Display>(e: E) -> Self {
+    Error::Vc(e.to_string())
+  }
+
+  pub fn archive<E: fmt::Display>(e: E) -> Self {
+    Error::Archive(e.to_string())
+  }
+
+  pub fn ipc<E: fmt::Display>(e: E) -> Self {
+    Error::Ipc(e.to_string())
+  }
+
+  pub fn script<E: fmt::Display>(e: E) -> Self {
+    Error::Script(e.to_string())
+  }
+
+  pub fn other<E: fmt::Display>(e: E) -> Self {
+    Error::Other(e.to_string())
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Error::Other(m) | Error::Script(m) => f.write_str(m),
+      Error::Usage(m) => write!(f, "usage: {}", m),
+      Error::Config(m) => write!(f, "config: {}", m),
+      Error::Io(e) => write!(f, "io: {}", e),
+      Error::Net(m) => write!(f, "network: {}", m),
+      Error::Vc(m) => write!(f, "vc: {}", m),
+      Error::Dirty(p) => write!(f, "{} has uncommitted changes", p.display()),
+      Error::Archive(m) => write!(f, "archive: {}", m),
+      Error::Ipc(m) => write!(f, "ipc: {}", m),
+      Error::Verify { expected, actual } => {
+        write!(
+          f,
+          "checksum mismatch: expected {}, got {}",
+          expected, actual
+        )
+      }
+    }
+  }
+}
+
+impl error::Error for Error {
+  fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+    match self {
+      Error::Io(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for Error {
+  fn from(e: io::Error) -> Self {
+    Error::Io(e)
+  }
+}
+
+impl From<config::Error> for Error {
+  fn from(e: config::Error) -> Self {
+    Error::Config(e.to_string())
+  }
+}
+
+/// JSON trees are only used for config values
+impl From<serde_json::Error> for Error {
+  fn from(e: serde_json::Error) -> Self {
+    Error::Config(e.to_string())
+  }
+}
+
+impl From<UriError> for Error {
+  fn from(e: UriError) -> Self {
+    Error::Usage(e.to_string())
+  }
+}
+
+impl From<download::Error> for Error {
+  fn from(e: download::Error) -> Self {
+    match e {
+      download::Error::Io(e) => Error::Io(e),
+      download::Error::Checksum { expected, actual } => Error::Verify { expected, actual },
+      download::Error::FileName(_) => Error::Usage(e.to_string()),
+      download::Error::Http(_) | download::Error::Status(_) => Error::Net(e.to_string()),
+    }
+  }
+}
+
+/// Errors from rlib which don't carry a kind
+impl From<Box<dyn error::Error>> for Error {
+  fn from(e: Box<dyn error::Error>) -> Self {
+    Error::Other(e.to_string())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_codes_are_distinct() {
+    let errs = [
+      Error::Other(String::new()),
+      Error::Usage(String::new()),
+      Error::Config(String::new()),
+      Error::Io(io::ErrorKind::NotFound.into()),
+      Error::Net(String::new()),
+      Error::Vc(String::new()),
+      Error::Dirty(PathBuf::new()),
+      Error::Archive(String::new()),
+      Error::Ipc(String::new()),
+      Error::Script(String::new()),
+      Error::Verify {
+        expected: String::new(),
+        actual: String::new(),
+      },
+    ];
+    let codes: Vec<i32> = errs.iter().map(Error::code).collect();
+    assert_eq!(codes, (1..=11).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn test_from_download() {
+    let e: Error = download::Error::Checksum {
+      expected: "a".to_owned(),
+      actual: "b".to_owned(),
+    }
+    .into();
+    assert_eq!(e.code(), 11);
+    let e: Error = UriError::Empty.into();
+    assert_eq!(e.code(), 2);
+  }
+}
@@ -0,0 +1,167 @@
+//! init.rs --- shed bootstrap
+/*!
+`shc init` asks a handful of questions on stdin, writes the answers
+into the current `Config` and creates the shed directory tree. With
+`--yes` every question takes its default. Re-running init never removes
+anything: existing directories are kept and an existing config is
+only replaced with `--force`.
+*/
+use crate::Config;
+
+use rlib::obj::{ron, AuthConfig};
+
+use std::{
+  collections::{BTreeMap, HashMap},
+  fs,
+  io::{self, BufRead, Write},
+  path::{Path, PathBuf},
+};
+
+/// The directories making up a shed, relative to its root
+pub const TREE: [&str; 6] = ["stash", "store", "src", "lab", "data", "data/log"];
+
+/// Create the shed directory tree under `root`, returning the
+/// directories which didn't exist yet
+pub fn build_tree<P: AsRef<Path>>(root: P) -> io::Result<Vec<PathBuf>> {
+  let root = root.as_ref();
+  let mut created = vec![];
+  for d in std::iter::once(root.to_path_buf()).chain(TREE.iter().map(|i| root.join(i))) {
+    if !d.is_dir() {
+      fs::create_dir_all(&d)?;
+      created.push(d);
+    }
+  }
+  Ok(created)
+}
+
+/// Line-based prompts on stdin
+pub struct Prompt {
+  /// answer every prompt with its default
+  yes: bool,
+}
+
+impl Prompt {
+  pub fn new(yes: bool) -> Self {
+    Prompt { yes }
+  }
+
+  /// Ask `q`, returning `default` on empty input
+  pub fn ask(&self, q: &str, default: &str) -> io::Result<String> {
+    if self.yes {
+      return Ok(default.to_owned());
+    }
+    print!("{} [{}]: ", q, default);
+    io::stdout().flush()?;
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    match line.trim() {
+      "" => Ok(default.to_owned()),
+      a => Ok(a.to_owned()),
+    }
+  }
+
+  /// Ask a yes/no question
+  pub fn confirm(&self, q: &str, default: bool) -> io::Result<bool> {
+    let a = self.ask(q, if default { "Y/n" } else { "y/N" })?;
+    Ok(match a.to_lowercase().as_str() {
+      "y" | "yes" => true,
+      "n" | "no" => false,
+      _ => default,
+    })
+  }
+}
+
+/// Update `cfg` with the answers to `prompt`, using its values as
+/// defaults. Fields which aren't asked about are kept as they are.
+pub fn configure(prompt: &Prompt, mut cfg: Config) -> io::Result<Config> {
+  cfg.path = prompt.ask("shed path", &cfg.path.to_string_lossy())?.into();
+
+  let mut remotes: BTreeMap<String, String> =
+    cfg.hg.paths.take().unwrap_or_default().into_iter().collect();
+  for (name, url) in remotes.iter() {
+    println!("VC remote {} = {}", name, url);
+  }
+  loop {
+    let name = prompt.ask("VC remote to add or change (empty when done)", "")?;
+    if name.is_empty() {
+      break;
+    }
+    let url = remotes.get(&name).cloned().unwrap_or_default();
+    match prompt.ask(&format!("{} url ('-' to remove)", name), &url)?.as_str() {
+      "-" => {
+        remotes.remove(&name);
+      }
+      "" => (),
+      u => {
+        remotes.insert(name, u.to_owned());
+      }
+    }
+  }
+  if !remotes.is_empty() {
+    cfg.hg.paths = Some(remotes.into_iter().collect::<HashMap<_, _>>());
+  }
+
+  // ShellConfig.shell is read back from its RON variant name
+  let shell = ron::ser::to_string(&cfg.usr.shell.shell).unwrap_or_default();
+  loop {
+    let a = prompt.ask("user shell", &shell)?;
+    match ron::de::from_str(&a) {
+      Ok(s) => {
+        cfg.usr.shell.shell = s;
+        break;
+      }
+      Err(e) => println!("unknown shell '{}': {}", a, e),
+    }
+  }
+
+  let providers: Vec<&str> = cfg.usr.auth.iter().map(|a| a.provider.as_str()).collect();
+  let answer = prompt.ask("auth providers (comma separated)", &providers.join(","))?;
+  let mut old = std::mem::take(&mut cfg.usr.auth);
+  for p in answer.split(',').map(str::trim).filter(|a| !a.is_empty()) {
+    // keep the settings of providers which were already there
+    let auth = match old.iter().position(|a| a.provider == p) {
+      Some(i) => old.remove(i),
+      None => AuthConfig {
+        provider: p.to_owned(),
+        ..Default::default()
+      },
+    };
+    cfg.usr.auth.push(auth);
+  }
+  Ok(cfg)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::TempDir;
+  #[test]
+  fn test_build_tree_idempotent() {
+    let tmp = TempDir::new("init");
+    let root = tmp.join("shed");
+    let created = build_tree(&root).unwrap();
+    assert_eq!(created.len(), TREE.len() + 1);
+    fs::write(root.join("stash/keep"), "").unwrap();
+    assert!(build_tree(&root).unwrap().is_empty());
+    assert!(root.join("stash/keep").is_file());
+  }
+
+  #[test]
+  fn test_configure_keeps_fields() {
+    let mut cfg = Config::new();
+    cfg.path = "~/shed".into();
+    cfg.hg.paths = Some(HashMap::from([
+      ("default".to_owned(), "https://hg.rwest.io".to_owned()),
+      ("backup".to_owned(), "ssh://nas//hg".to_owned()),
+    ]));
+    cfg.scripts.insert("shed".to_owned(), Default::default());
+    cfg.weather.ttl = 5;
+    cfg.usr.auth.push(AuthConfig {
+      provider: "google".to_owned(),
+      ..Default::default()
+    });
+    let before = serde_json::to_value(&cfg).unwrap();
+    let after = configure(&Prompt::new(true), cfg).unwrap();
+    assert_eq!(serde_json::to_value(&after).unwrap(), before);
+  }
+}
@@ -16,14 +16,39 @@ individual programs in the `bin` folder.
 
 // client
 mod app;
+mod clean;
 mod cli;
-pub use self::{app::App, cli::build_cli, config::Config};
+mod edit;
+mod init;
+mod list;
+pub mod plugin;
+mod shell;
+mod status;
+mod sync;
+pub use self::{
+  app::App,
+  cli::build_cli,
+  config::Config,
+  error::{Error, Result},
+  output::Output,
+  uri::{ResourceUri, UriError},
+};
 
 // common
+mod archive;
 mod coding;
 mod config;
+mod download;
+mod error;
+mod output;
+mod script;
+mod uri;
+mod vc;
 
 // services
 mod web;
 
+#[cfg(test)]
+mod testing;
+
 pub const MTU: usize = u16::MAX as usize;
@@ -0,0 +1,340 @@
+//! list.rs --- shed resource listings
+/*!
+Listings merge the packages and projects declared in `Config` with
+what actually exists under the shed path on disk. Each `Entry` records
+on-disk presence, size, last-modified time and VC state.
+*/
+use crate::{config::Project, vc::Vc, Config};
+
+use std::{
+  fmt, fs, io,
+  path::{Path, PathBuf},
+  str::FromStr,
+  time::SystemTime,
+};
+
+/// A listable section of the shed tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+  Projects,
+  Labs,
+  Stash,
+  Store,
+}
+
+impl Section {
+  pub const ALL: [Section; 4] = [
+    Section::Projects,
+    Section::Labs,
+    Section::Stash,
+    Section::Store,
+  ];
+
+  /// The section directory relative to the shed `root`
+  pub fn dir<P: AsRef<Path>>(&self, root: P) -> PathBuf {
+    root.as_ref().join(match self {
+      Section::Projects => "src",
+      Section::Labs => "lab",
+      Section::Stash => "stash",
+      Section::Store => "store",
+    })
+  }
+}
+
+impl FromStr for Section {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "projects" | "src" => Ok(Section::Projects),
+      "labs" | "lab" => Ok(Section::Labs),
+      "stash" => Ok(Section::Stash),
+      "store" => Ok(Section::Store),
+      s => Err(format!("unknown section '{}'", s)),
+    }
+  }
+}
+
+impl fmt::Display for Section {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(match self {
+      Section::Projects => "projects",
+      Section::Labs => "labs",
+      Section::Stash => "stash",
+      Section::Store => "store",
+    })
+  }
+}
+
+/// VC state of an entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VcState {
+  pub vc: Vc,
+  pub dirty: Option<bool>,
+}
+
+impl fmt::Display for VcState {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.dirty {
+      Some(true) => write!(f, "{}*", self.vc),
+      Some(false) => write!(f, "{}", self.vc),
+      None => write!(f, "{}?", self.vc),
+    }
+  }
+}
+
+/// A single listing entry
+#[derive(Debug)]
+pub struct Entry {
+  pub name: String,
+  pub path: PathBuf,
+  /// declared in `Config`, always true for stash and store entries
+  pub declared: bool,
+  pub exists: bool,
+  pub size: u64,
+  pub modified: Option<SystemTime>,
+  pub vc: Option<VcState>,
+}
+
+impl Entry {
+  /// Build an `Entry` by inspecting `path` on disk
+  pub fn new<P: AsRef<Path>>(name: &str, path: P, declared: bool) -> Self {
+    let path = path.as_ref().to_path_buf();
+    let exists = path.exists();
+    let (size, modified) = if exists {
+      disk_usage(&path).unwrap_or((0, None))
+    } else {
+      (0, None)
+    };
+    let vc = if path.is_dir() {
+      Vc::detect(&path).map(|vc| VcState {
+        vc,
+        dirty: vc.backend().is_dirty(&path),
+      })
+    } else {
+      None
+    };
+    Entry {
+      name: name.to_owned(),
+      path,
+      declared,
+      exists,
+      size,
+      modified,
+      vc,
+    }
+  }
+
+  /// `+` present, `!` declared but missing, `?` on disk but not
+  /// declared
+  pub fn presence(&self) -> char {
+    match (self.declared, self.exists) {
+      (true, true) => '+',
+      (true, false) => '!',
+      (false, _) => '?',
+    }
+  }
+}
+
+impl fmt::Display for Entry {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "{} {:<24} {:>8} {:>10} {:<5}",
+      self.presence(),
+      self.name,
+      if self.exists {
+        fmt_size(self.size)
+      } else {
+        "-".to_owned()
+      },
+      self.modified.map(fmt_age).unwrap_or_else(|| "-".to_owned()),
+      self
+        .vc
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| "-".to_owned()),
+    )
+  }
+}
+
+/// List a `Section` of the shed
+pub fn list(cfg: &Config, section: Section) -> io::Result<Vec<Entry>> {
+  let dir = section.dir(&cfg.path);
+  let mut entries: Vec<Entry> = match section {
+    Section::Projects => cfg
+      .src
+      .iter()
+      .map(Project::Src)
+      .map(|p| Entry::new(p.name(), p.dir(&cfg.path), true))
+      .collect(),
+    Section::Labs => cfg
+      .lab
+      .iter()
+      .map(Project::Lab)
+      .map(|p| Entry::new(p.name(), p.dir(&cfg.path), true))
+      .collect(),
+    Section::Stash | Section::Store => vec![],
+  };
+  if dir.is_dir() {
+    for e in fs::read_dir(&dir)? {
+      let e = e?;
+      let name = e.file_name().to_string_lossy().into_owned();
+      if entries.iter().any(|i| i.name == name) {
+        continue;
+      }
+      let declared = matches!(section, Section::Stash | Section::Store);
+      entries.push(Entry::new(&name, e.path(), declared));
+    }
+  }
+  entries.sort_by(|a, b| a.name.cmp(&b.name));
+  Ok(entries)
+}
+
+/// Print a listing table to stdout
+pub fn print(entries: &[Entry]) {
+  println!(
+    "  {:<24} {:>8} {:>10} {:<5}",
+    "name", "size", "modified", "vc"
+  );
+  for e in entries {
+    println!("{}", e);
+  }
+}
+
+/// Print a summary of each `Section` to stdout
+pub fn print_stats(cfg: &Config) -> io::Result<()> {
+  println!("shed: {}", cfg.path.display());
+  for s in Section::ALL {
+    let entries = list(cfg, s)?;
+    let missing = entries.iter().filter(|e| !e.exists).count();
+    let size: u64 = entries.iter().map(|e| e.size).sum();
+    println!(
+      "  {:<10} {:>4} entries {:>8} {}",
+      s,
+      entries.len(),
+      fmt_size(size),
+      if missing > 0 {
+        format!("({} missing)", missing)
+      } else {
+        String::new()
+      }
+    );
+  }
+  Ok(())
+}
+
+/// Total size and newest modification time of `path`. Symlinks are
+/// not followed.
+pub(crate) fn disk_usage(path: &Path) -> io::Result<(u64, Option<SystemTime>)> {
+  let meta = fs::symlink_metadata(path)?;
+  let mut size = meta.len();
+  let mut modified = meta.modified().ok();
+  if meta.is_dir() {
+    size = 0;
+    for e in fs::read_dir(path)? {
+      let (s, m) = disk_usage(&e?.path())?;
+      size += s;
+      modified = modified.max(m);
+    }
+  }
+  Ok((size, modified))
+}
+
+/// Format a byte count like `ls -h`
+pub(crate) fn fmt_size(n: u64) -> String {
+  const UNITS: [&str; 5] = ["K", "M", "G", "T", "P"];
+  if n < 1024 {
+    return n.to_string();
+  }
+  let mut x = n as f64;
+  let mut unit = "";
+  for u in UNITS {
+    x /= 1024.0;
+    unit = u;
+    if x < 1024.0 {
+      break;
+    }
+  }
+  if x < 10.0 {
+    format!("{:.1}{}", x, unit)
+  } else {
+    format!("{:.0}{}", x, unit)
+  }
+}
+
+/// Format the time elapsed since `t`
+pub(crate) fn fmt_age(t: SystemTime) -> String {
+  let s = SystemTime::now()
+    .duration_since(t)
+    .map(|d| d.as_secs())
+    .unwrap_or(0);
+  match s {
+    0..=59 => format!("{}s ago", s),
+    60..=3599 => format!("{}m ago", s / 60),
+    3600..=86399 => format!("{}h ago", s / 3600),
+    _ => format!("{}d ago", s / 86400),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::TempDir;
+  use std::time::Duration;
+
+  #[test]
+  fn test_fmt() {
+    assert_eq!(fmt_size(0), "0");
+    assert_eq!(fmt_size(1023), "1023");
+    assert_eq!(fmt_size(1536), "1.5K");
+    assert_eq!(fmt_size(20 << 20), "20M");
+    assert_eq!(fmt_size(3 << 40), "3.0T");
+    let ago = |s: u64| fmt_age(SystemTime::now() - Duration::from_secs(s));
+    assert_eq!(ago(5), "5s ago");
+    assert_eq!(ago(120), "2m ago");
+    assert_eq!(ago(7200), "2h ago");
+    assert_eq!(ago(3 * 86400), "3d ago");
+    // clock skew
+    assert_eq!(
+      fmt_age(SystemTime::now() + Duration::from_secs(60)),
+      "0s ago"
+    );
+  }
+
+  #[test]
+  fn test_disk_usage() {
+    let root = TempDir::new("list");
+    fs::create_dir_all(root.join("d/e")).unwrap();
+    fs::write(root.join("d/a"), "abc").unwrap();
+    fs::write(root.join("d/e/b"), "de").unwrap();
+    let (size, modified) = disk_usage(&root.join("d")).unwrap();
+    assert_eq!(size, 5);
+    assert!(modified.is_some());
+    assert_eq!(disk_usage(&root.join("d/a")).unwrap().0, 3);
+    assert!(disk_usage(&root.join("missing")).is_err());
+  }
+
+  #[test]
+  fn test_list() {
+    let root = TempDir::new("list");
+    let mut cfg = Config::new();
+    cfg.path = root.to_path_buf();
+    fs::create_dir_all(root.join("stash/dir")).unwrap();
+    fs::write(root.join("stash/b.txt"), "12345").unwrap();
+    fs::write(root.join("stash/dir/c"), "1").unwrap();
+    let ls = list(&cfg, Section::Stash).unwrap();
+    let names: Vec<&str> = ls.iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(names, ["b.txt", "dir"]);
+    assert!(ls.iter().all(|e| e.presence() == '+'));
+    assert_eq!(ls[0].size, 5);
+    assert_eq!(ls[1].size, 1);
+    assert!(ls[1].vc.is_none());
+    // a missing section is empty, not an error
+    assert!(list(&cfg, Section::Store).unwrap().is_empty());
+    let missing = Entry::new("gone", root.join("gone"), true);
+    assert_eq!(missing.presence(), '!');
+    assert!(missing.to_string().starts_with("! gone "));
+    assert_eq!(Entry::new("x", root.join("stash"), false).presence(), '?');
+    assert_eq!("src".parse::<Section>(), Ok(Section::Projects));
+    assert!("bin".parse::<Section>().is_err());
+  }
+}
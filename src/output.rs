@@ -0,0 +1,156 @@
+//! output.rs --- machine-readable output
+/*!
+Commands which report information build a typed value and hand it to
+`Output::render`. Plain text uses the value's `Display` impl, JSON
+and s-expressions are derived from its `Serialize` impl, so the three
+formats can't drift apart.
+
+S-expressions are meant to be `read` by Emacs: objects become plists
+with keyword keys, arrays become vectors, `null` and `false` become
+`nil` and `true` becomes `t`.
+*/
+use crate::Result;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use std::{fmt, str::FromStr};
+
+/// Output format selected with `--format` on reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+  Text,
+  Json,
+  Sexp,
+}
+
+impl Output {
+  /// Render `t` in this format, failing when it doesn't serialize
+  pub fn render<T: Serialize + fmt::Display>(&self, t: &T) -> Result<String> {
+    Ok(match self {
+      Output::Text => t.to_string(),
+      Output::Json => serde_json::to_string_pretty(t)?,
+      Output::Sexp => sexp(&serde_json::to_value(t)?),
+    })
+  }
+}
+
+impl FromStr for Output {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "text" => Ok(Output::Text),
+      "json" => Ok(Output::Json),
+      "sexp" => Ok(Output::Sexp),
+      s => Err(format!("output format '{}' not understood", s)),
+    }
+  }
+}
+
+/// Write `v` as an Emacs Lisp s-expression
+pub fn sexp(v: &Value) -> String {
+  let mut out = String::new();
+  write_sexp(v, &mut out);
+  out
+}
+
+fn write_sexp(v: &Value, out: &mut String) {
+  match v {
+    Value::Null | Value::Bool(false) => out.push_str("nil"),
+    Value::Bool(true) => out.push('t'),
+    Value::Number(n) => out.push_str(&n.to_string()),
+    Value::String(s) => {
+      out.push('"');
+      for c in s.chars() {
+        if c == '"' || c == '\\' {
+          out.push('\\');
+        }
+        out.push(c);
+      }
+      out.push('"');
+    }
+    Value::Array(a) => {
+      out.push('[');
+      for (i, x) in a.iter().enumerate() {
+        if i > 0 {
+          out.push(' ');
+        }
+        write_sexp(x, out);
+      }
+      out.push(']');
+    }
+    Value::Object(m) => {
+      out.push('(');
+      for (i, (k, x)) in m.iter().enumerate() {
+        if i > 0 {
+          out.push(' ');
+        }
+        out.push(':');
+        out.push_str(&k.replace('_', "-"));
+        out.push(' ');
+        write_sexp(x, out);
+      }
+      out.push(')');
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_sexp() {
+    let v = json!({
+      "cpus": 8,
+      "host_name": "box",
+      "kernel": null,
+      "nested": {"ok": false, "x": 1.5},
+      "tags": ["a \"b\"", "c\\d"],
+      "up": true
+    });
+    assert_eq!(
+      sexp(&v),
+      r#"(:cpus 8 :host-name "box" :kernel nil :nested (:ok nil :x 1.5) :tags ["a \"b\"" "c\\d"] :up t)"#
+    );
+  }
+
+  #[test]
+  fn test_render() {
+    #[derive(Serialize)]
+    struct Ip {
+      ip: String,
+    }
+    impl fmt::Display for Ip {
+      fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ip {}", self.ip)
+      }
+    }
+    let ip = Ip {
+      ip: "10.0.0.1".to_owned(),
+    };
+    assert_eq!(Output::Text.render(&ip).unwrap(), "ip 10.0.0.1");
+    assert_eq!(Output::Sexp.render(&ip).unwrap(), r#"(:ip "10.0.0.1")"#);
+    assert_eq!(
+      serde_json::from_str::<Value>(&Output::Json.render(&ip).unwrap()).unwrap(),
+      json!({"ip": "10.0.0.1"})
+    );
+  }
+
+  #[test]
+  fn test_render_error() {
+    // JSON object keys must be strings
+    #[derive(Serialize)]
+    struct Grid(std::collections::BTreeMap<(u8, u8), u8>);
+    impl fmt::Display for Grid {
+      fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} cells", self.0.len())
+      }
+    }
+    let g = Grid([((0, 0), 1)].into_iter().collect());
+    assert_eq!(Output::Text.render(&g).unwrap(), "1 cells");
+    assert!(Output::Json.render(&g).is_err());
+    assert!(Output::Sexp.render(&g).is_err());
+  }
+}
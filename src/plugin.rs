@@ -0,0 +1,234 @@
+//! plugin.rs --- external shc subcommands
+/*!
+`shc foo args..` runs the first executable named `shc-foo` found in
+`$SHED/bin` or on `$PATH`, like git does with `git-foo`. Built-in
+commands and their aliases always win. The plugin inherits our stdio
+and environment plus:
+
+- `SHED` :: the resolved shed root
+- `SHED_CFG` :: the config file `shc` resolved, when it exists
+
+so a plugin calling `shc` again sees the same config.
+
+When help is asked for, plugins are also added to the CLI as
+subcommands which take any arguments, which is how they show up in
+`shc help`. Otherwise the directories are only searched once an
+unknown subcommand is given.
+*/
+use crate::{config::Layers, Error, Result};
+
+use rlib::util::cli::{App, AppSettings, Arg};
+
+use std::{
+  collections::BTreeMap,
+  env,
+  ffi::OsStr,
+  fs,
+  path::{Path, PathBuf},
+  process::Command,
+};
+
+/// Prefix of plugin executables
+pub const PREFIX: &str = "shc-";
+
+/// Directories searched for plugins, in order: `$SHED/bin` and then
+/// those in `path`, the value of `$PATH`
+pub fn dirs(shed: Option<&Path>, path: Option<&OsStr>) -> Vec<PathBuf> {
+  shed
+    .map(|s| s.join("bin"))
+    .into_iter()
+    .chain(path.into_iter().flat_map(env::split_paths))
+    .collect()
+}
+
+/// Every plugin by name, the first found in `dirs` winning
+pub fn find(dirs: &[PathBuf]) -> BTreeMap<String, PathBuf> {
+  let mut out = BTreeMap::new();
+  for d in dirs {
+    let entries = match fs::read_dir(d) {
+      Ok(e) => e,
+      Err(_) => continue,
+    };
+    for e in entries.filter_map(|e| e.ok()) {
+      let path = e.path();
+      if let Some(name) = plugin_name(&path) {
+        out.entry(name).or_insert(path);
+      }
+    }
+  }
+  out
+}
+
+/// The plugin called `name`
+pub fn which(name: &str, dirs: &[PathBuf]) -> Option<PathBuf> {
+  find(dirs).remove(name)
+}
+
+/// The shed root from the config layers, with `cli` as given to
+/// `-c`, before the CLI is parsed
+pub fn shed_root(cli: Option<&str>) -> Option<PathBuf> {
+  let cfg = Layers::load(cli).ok()?.config().ok()?;
+  Some(cfg.expanded().path)
+}
+
+/// The value of `-c`/`--config` in raw `args`, for when they don't
+/// parse as they are
+pub fn config_arg<I: IntoIterator<Item = String>>(args: I) -> Option<String> {
+  let mut args = args.into_iter().skip(1);
+  let mut found = None;
+  while let Some(a) = args.next() {
+    match a.as_str() {
+      "--" => break,
+      "-c" | "--config" => found = args.next(),
+      _ => {
+        if let Some(v) = a.strip_prefix("--config=") {
+          found = Some(v.to_owned());
+        } else if let Some(v) = a.strip_prefix("-c").filter(|v| !v.is_empty()) {
+          found = Some(v.to_owned());
+        }
+      }
+    }
+  }
+  found
+}
+
+/// Add every plugin which doesn't shadow a built-in or one of its
+/// aliases to `app`
+pub fn register(mut app: App<'static>, dirs: &[PathBuf]) -> App<'static> {
+  for name in find(dirs).into_keys() {
+    let taken = app
+      .get_subcommands()
+      .any(|s| s.get_name() == name || s.get_all_aliases().any(|a| a == name));
+    if taken {
+      continue;
+    }
+    app = app.subcommand(
+      App::new(name)
+        .setting(AppSettings::DisableHelpFlag)
+        .setting(AppSettings::TrailingVarArg)
+        .arg(
+          Arg::new("args")
+            .takes_value(true)
+            .multiple_values(true)
+            .allow_hyphen_values(true),
+        ),
+    );
+  }
+  app
+}
+
+/// Replace `shc` with the plugin at `path`, which only returns when
+/// it can't be started. Without `exec` the plugin is waited for and
+/// `shc` exits with its code.
+pub fn exec(path: &Path, args: &[&str], cfg_file: &Path, shed: &Path) -> Result<()> {
+  let mut cmd = command(path, args, cfg_file, shed);
+  let fail = |e| Error::Other(format!("can't run {}: {}", path.display(), e));
+  #[cfg(unix)]
+  {
+    use std::os::unix::process::CommandExt;
+    Err(fail(cmd.exec()))
+  }
+  #[cfg(not(unix))]
+  {
+    let status = cmd.status().map_err(fail)?;
+    std::process::exit(status.code().unwrap_or(1))
+  }
+}
+
+/// The command running the plugin at `path`
+fn command(path: &Path, args: &[&str], cfg_file: &Path, shed: &Path) -> Command {
+  let mut cmd = Command::new(path);
+  cmd.args(args).env("SHED", shed);
+  if cfg_file.is_file() {
+    cmd.env("SHED_CFG", cfg_file);
+  }
+  cmd
+}
+
+/// The name of the plugin at `path`, if it is one
+fn plugin_name(path: &Path) -> Option<String> {
+  let file = path.file_name()?.to_str()?;
+  let name = file.strip_prefix(PREFIX)?;
+  let name = if cfg!(windows) {
+    name.strip_suffix(".exe")?
+  } else {
+    name
+  };
+  (!name.is_empty() && executable(path)).then(|| name.to_owned())
+}
+
+#[cfg(unix)]
+fn executable(path: &Path) -> bool {
+  use std::os::unix::fs::PermissionsExt;
+  fs::metadata(path)
+    .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+    .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn executable(path: &Path) -> bool {
+  path.is_file()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::TempDir;
+
+  #[cfg(unix)]
+  #[test]
+  fn test_find() {
+    use std::os::unix::fs::PermissionsExt;
+    let root = TempDir::new("plugin");
+    fs::create_dir_all(root.join("bin")).unwrap();
+    let script = |name: &str, mode: u32, body: &str| {
+      let p = root.join("bin").join(name);
+      fs::write(&p, body).unwrap();
+      fs::set_permissions(&p, fs::Permissions::from_mode(mode)).unwrap();
+      p
+    };
+    let foo = script(
+      "shc-foo",
+      0o755,
+      "#!/bin/sh\n[ \"$1\" = ok ] && [ \"$SHED_CFG\" = \"$SHED/shed.cfg\" ] || exit 3\n",
+    );
+    script("shc-notes.txt", 0o644, "");
+    script("status", 0o755, "");
+    // leave the host's $PATH out of it
+    let dirs = dirs(Some(root.path()), None);
+    assert_eq!(dirs, [root.join("bin")]);
+    let found = find(&dirs);
+    assert_eq!(found.get("foo"), Some(&foo));
+    assert!(!found.contains_key("notes.txt"));
+    assert_eq!(which("foo", &dirs), Some(foo.clone()));
+    let cfg = root.join("shed.cfg");
+    fs::write(&cfg, "").unwrap();
+    let code = |arg: &str| command(&foo, &[arg], &cfg, &root).status().unwrap().code();
+    assert_eq!(code("ok"), Some(0));
+    assert_eq!(code("no"), Some(3));
+    script("shc-s", 0o755, "");
+    let app = register(
+      App::new("shc").subcommand(App::new("status").alias("s")),
+      &dirs,
+    );
+    let names: Vec<&str> = app.get_subcommands().map(|s| s.get_name()).collect();
+    assert_eq!(names, ["status", "foo"]);
+  }
+
+  #[test]
+  fn test_config_arg() {
+    let arg = |a: &[&str]| config_arg(a.iter().map(|s| s.to_string()));
+    assert_eq!(arg(&["shc", "help"]), None);
+    assert_eq!(
+      arg(&["shc", "-c", "a.cfg", "help"]).as_deref(),
+      Some("a.cfg")
+    );
+    assert_eq!(arg(&["shc", "-ca.cfg"]).as_deref(), Some("a.cfg"));
+    assert_eq!(
+      arg(&["shc", "--config=a.cfg", "-h"]).as_deref(),
+      Some("a.cfg")
+    );
+    assert_eq!(arg(&["shc", "--config", "a.cfg"]).as_deref(), Some("a.cfg"));
+    assert_eq!(arg(&["shc", "run", "--", "-c", "x"]), None);
+  }
+}
@@ -0,0 +1,286 @@
+//! script.rs --- project scripts
+/*!
+Scripts are shell commands run inside a package or project
+directory. The command is taken from `Config.scripts` when configured,
+otherwise a default is derived from the project's build system.
+*/
+use crate::{config::Project, Config};
+
+use rlib::logger::log::error;
+
+use std::{
+  fmt,
+  fs::File,
+  io,
+  path::{Path, PathBuf},
+  process::{Command, ExitStatus},
+  time::{Duration, Instant},
+};
+
+/// The kinds of script a project can define
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Script {
+  Build,
+  Clean,
+  Test,
+}
+
+impl Script {
+  /// The default command for the build system found in `dir`
+  pub fn default_for<P: AsRef<Path>>(&self, dir: P) -> Option<String> {
+    let dir = dir.as_ref();
+    let target = match self {
+      Script::Build => "build",
+      Script::Clean => "clean",
+      Script::Test => "test",
+    };
+    if dir.join("Cargo.toml").is_file() {
+      Some(format!("cargo {}", target))
+    } else if ["makefile", "Makefile", "GNUmakefile"]
+      .iter()
+      .any(|m| dir.join(m).is_file())
+    {
+      match self {
+        Script::Build => Some("make".to_owned()),
+        _ => Some(format!("make {}", target)),
+      }
+    } else {
+      None
+    }
+  }
+}
+
+/// Resolve the `script` command for `project`
+pub fn resolve(cfg: &Config, project: &Project, script: Script) -> Option<String> {
+  let configured = cfg.scripts.get(project.name()).and_then(|s| match script {
+    Script::Build => s.build.clone(),
+    Script::Clean => s.clean.clone(),
+    Script::Test => s.test.clone(),
+  });
+  configured.or_else(|| script.default_for(project.dir(&cfg.path)))
+}
+
+/// Quote `s` as one POSIX shell word, leaving plain words as they are
+pub fn quote(s: &str) -> String {
+  let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+  if !s.is_empty() && s.chars().all(plain) {
+    s.to_owned()
+  } else {
+    format!("'{}'", s.replace('\'', "'\\''"))
+  }
+}
+
+/// Build a `Command` which runs `cmd` with `sh` inside `dir`
+pub fn command<P: AsRef<Path>>(cmd: &str, dir: P) -> Command {
+  let mut c = Command::new("sh");
+  c.arg("-c").arg(cmd).current_dir(dir);
+  c
+}
+
+/// Outcome of a script run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+  Passed,
+  /// exit code, `None` when killed by a signal or failing to spawn
+  Failed(Option<i32>),
+  /// no script is configured or the project is missing on disk
+  Skipped(&'static str),
+}
+
+impl fmt::Display for Status {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Status::Passed => f.write_str("ok"),
+      Status::Failed(Some(c)) => write!(f, "FAILED ({})", c),
+      Status::Failed(None) => f.write_str("FAILED"),
+      Status::Skipped(why) => write!(f, "skipped ({})", why),
+    }
+  }
+}
+
+/// The result of running a script for a single project
+#[derive(Debug)]
+pub struct Report {
+  pub name: String,
+  pub status: Status,
+  pub duration: Duration,
+  /// captured output, when not inherited
+  pub log: Option<PathBuf>,
+}
+
+impl Report {
+  pub fn failed(&self) -> bool {
+    matches!(self.status, Status::Failed(_))
+  }
+}
+
+impl fmt::Display for Report {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "{:<24} {:>8.2}s {}",
+      self.name,
+      self.duration.as_secs_f64(),
+      self.status
+    )
+  }
+}
+
+/// Run `script` for `project`, inheriting stdio
+pub fn run(cfg: &Config, project: &Project, script: Script) -> Report {
+  exec(cfg, project, script, &[], |c| c.status())
+}
+
+/// Run `script` for `project` with extra `args`, capturing stdout
+/// and stderr into `log`
+pub fn run_logged<P: AsRef<Path>>(
+  cfg: &Config,
+  project: &Project,
+  script: Script,
+  args: &[&str],
+  log: P,
+) -> Report {
+  let log = log.as_ref();
+  let mut report = exec(cfg, project, script, args, |c| {
+    let f = File::create(log)?;
+    c.stdout(f.try_clone()?).stderr(f).status()
+  });
+  if log.is_file() {
+    report.log = Some(log.to_path_buf());
+  }
+  report
+}
+
+fn exec<F>(cfg: &Config, project: &Project, script: Script, args: &[&str], f: F) -> Report
+where
+  F: FnOnce(&mut Command) -> io::Result<ExitStatus>,
+{
+  let dir = project.dir(&cfg.path);
+  let cmd = resolve(cfg, project, script);
+  exec_in(project.name(), &dir, cmd.as_deref(), args, f)
+}
+
+/// Run `cmd` with `args` inside `dir`, skipping it when either is
+/// missing
+fn exec_in<F>(name: &str, dir: &Path, cmd: Option<&str>, args: &[&str], f: F) -> Report
+where
+  F: FnOnce(&mut Command) -> io::Result<ExitStatus>,
+{
+  let start = Instant::now();
+  let status = match cmd {
+    _ if !dir.is_dir() => Status::Skipped("not found"),
+    None => Status::Skipped("no script"),
+    Some(cmd) => {
+      let mut cmd = cmd.to_owned();
+      for a in args {
+        cmd.push(' ');
+        cmd.push_str(&quote(a));
+      }
+      println!("{}: {}", name, cmd);
+      match f(&mut command(&cmd, dir)) {
+        Ok(s) if s.success() => Status::Passed,
+        Ok(s) => Status::Failed(s.code()),
+        Err(e) => {
+          error!("{}: {}", name, e);
+          Status::Failed(None)
+        }
+      }
+    }
+  };
+  Report {
+    name: name.to_owned(),
+    status,
+    duration: start.elapsed(),
+    log: None,
+  }
+}
+
+/// Print a summary table of `reports` to stdout
+pub fn summary(reports: &[Report]) {
+  println!("{:-<48}", "");
+  for r in reports {
+    println!("{}", r);
+    if let (true, Some(log)) = (r.failed(), &r.log) {
+      println!("  see {}", log.display());
+    }
+  }
+  let passed = reports
+    .iter()
+    .filter(|r| r.status == Status::Passed)
+    .count();
+  let failed = reports.iter().filter(|r| r.failed()).count();
+  println!(
+    "{} passed, {} failed, {} skipped",
+    passed,
+    failed,
+    reports.len() - passed - failed
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::TempDir;
+  use std::fs;
+
+  #[test]
+  fn test_default_for() {
+    let tmp = TempDir::new("script");
+    assert_eq!(Script::Test.default_for(&tmp), None);
+    fs::write(tmp.join("Makefile"), "").unwrap();
+    assert_eq!(Script::Build.default_for(&tmp).as_deref(), Some("make"));
+    assert_eq!(Script::Test.default_for(&tmp).as_deref(), Some("make test"));
+    fs::write(tmp.join("Cargo.toml"), "").unwrap();
+    assert_eq!(
+      Script::Clean.default_for(&tmp).as_deref(),
+      Some("cargo clean")
+    );
+  }
+
+  #[test]
+  fn test_exec_in() {
+    let tmp = TempDir::new("script");
+    let run = |cmd: Option<&str>, args: &[&str]| exec_in("p", &tmp, cmd, args, |c| c.status());
+    assert_eq!(run(Some("test -d ."), &[]).status, Status::Passed);
+    assert_eq!(run(Some("test"), &["a", "=", "a"]).status, Status::Passed);
+    let r = run(Some("exit 3"), &[]);
+    assert!(r.failed());
+    assert_eq!(r.status, Status::Failed(Some(3)));
+    assert!(r.to_string().starts_with("p "));
+    assert!(r.to_string().ends_with("FAILED (3)"));
+    assert_eq!(run(None, &[]).status, Status::Skipped("no script"));
+    let missing = exec_in("p", &tmp.join("x"), Some("true"), &[], |c| c.status());
+    assert_eq!(missing.status, Status::Skipped("not found"));
+    assert!(!missing.failed());
+  }
+
+  #[test]
+  fn test_exec_in_logged() {
+    let tmp = TempDir::new("script");
+    let log = tmp.join("out.log");
+    let r = exec_in("p", &tmp, Some("echo out; echo err >&2"), &[], |c| {
+      let f = File::create(&log)?;
+      c.stdout(f.try_clone()?).stderr(f).status()
+    });
+    assert_eq!(r.status, Status::Passed);
+    assert_eq!(fs::read_to_string(&log).unwrap(), "out\nerr\n");
+  }
+
+  #[test]
+  fn test_quoted_args() {
+    let tmp = TempDir::new("script");
+    let log = tmp.join("args");
+    let args = ["a b", "; touch x", "$(touch y)", "it's"];
+    let r = exec_in("p", &tmp, Some("printf '%s|'"), &args, |c| {
+      c.stdout(File::create(&log)?).status()
+    });
+    assert_eq!(r.status, Status::Passed);
+    assert_eq!(
+      fs::read_to_string(&log).unwrap(),
+      "a b|; touch x|$(touch y)|it's|"
+    );
+    assert!(!tmp.join("x").exists() && !tmp.join("y").exists());
+    assert_eq!(quote("-j4"), "-j4");
+    assert_eq!(quote(""), "''");
+  }
+}
@@ -0,0 +1,196 @@
+//! shell.rs --- user commands
+/*!
+`usr.shell.cmds` maps names to command lines run by `shc run NAME
+ARGS..` with the configured shell and `usr.shell.env` added to the
+environment. Before running, these placeholders are replaced:
+
+- `{1}`, `{2}`.. :: the positional args
+- `{@}` :: all of them
+- `{project}` :: the directory of the current project
+- `{shed}` :: the shed root
+
+Args are quoted for the shell. When a command uses none of `{1}`..
+or `{@}` the args are appended to it instead. Any other `{..}`, and
+anything right after a `$`, is left alone, so shell braces like
+`${HOME}` or `${1}` still work. Since values
+come out quoted, a placeholder inside quotes, as in `echo '{1}'`, is
+refused rather than quoted twice.
+*/
+use crate::{script::quote, Config, Error, Result};
+
+use rlib::{logger::log::warn, obj::ShellType};
+use serde::Serialize;
+
+use std::{collections::BTreeMap, fmt, path::Path, process::Command};
+
+/// Values for the named placeholders
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Vars<'a> {
+  pub project: Option<&'a Path>,
+  pub shed: Option<&'a Path>,
+}
+
+/// Substitute `args` and `vars` into `cmd`
+pub fn expand(cmd: &str, args: &[&str], vars: Vars) -> Result<String> {
+  let mut out = String::with_capacity(cmd.len());
+  let mut positional = false;
+  let mut quoted = None;
+  let mut rest = cmd;
+  while let Some(i) = rest.find('{') {
+    out.push_str(&rest[..i]);
+    quoted = quotes(&rest[..i], quoted);
+    let shell = rest[..i].ends_with('$');
+    rest = &rest[i..];
+    let key = match rest.find('}') {
+      Some(j) => &rest[1..j],
+      None => break,
+    };
+    let val = match key {
+      _ if shell => None,
+      "@" => {
+        positional = true;
+        Some(args.iter().map(|a| quote(a)).collect::<Vec<_>>().join(" "))
+      }
+      "project" => Some(path(vars.project, "{project} needs a project")?),
+      "shed" => Some(path(vars.shed, "{shed} needs a shed")?),
+      n if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => {
+        positional = true;
+        let a = n
+          .parse::<usize>()
+          .ok()
+          .filter(|&n| n > 0)
+          .and_then(|n| args.get(n - 1))
+          .ok_or_else(|| Error::Usage(format!("missing argument {{{}}}", n)))?;
+        Some(quote(a))
+      }
+      _ => None,
+    };
+    match val {
+      Some(_) if quoted.is_some() => {
+        return Err(Error::Usage(format!(
+          "{{{}}} is quoted already, drop the quotes around it",
+          key
+        )))
+      }
+      Some(v) => {
+        out.push_str(&v);
+        rest = &rest[key.len() + 2..];
+      }
+      None => {
+        out.push('{');
+        quoted = quotes("{", quoted);
+        rest = &rest[1..];
+      }
+    }
+  }
+  out.push_str(rest);
+  if !positional {
+    for a in args {
+      out.push(' ');
+      out.push_str(&quote(a));
+    }
+  }
+  Ok(out)
+}
+
+/// The quote `s` leaves open, starting inside `open`
+fn quotes(s: &str, mut open: Option<char>) -> Option<char> {
+  let mut chars = s.chars();
+  while let Some(c) = chars.next() {
+    match (open, c) {
+      (Some('\''), '\'') => open = None,
+      (Some('\''), _) => (),
+      (_, '\\') => {
+        chars.next();
+      }
+      (None, '\'' | '"') => open = Some(c),
+      (Some('"'), '"') => open = None,
+      _ => (),
+    }
+  }
+  open
+}
+
+fn path(p: Option<&Path>, missing: &str) -> Result<String> {
+  p.map(|p| quote(&p.to_string_lossy()))
+    .ok_or_else(|| Error::Usage(missing.to_owned()))
+}
+
+/// The program for the configured `usr.shell.shell`, `sh` for
+/// shells we don't know how to start
+pub fn program(cfg: &Config) -> &'static str {
+  match cfg.usr.shell.shell {
+    ShellType::Sh => "sh",
+    ShellType::Bash => "bash",
+    ShellType::Zsh => "zsh",
+    ShellType::Fish => "fish",
+    _ => {
+      warn!("no program for usr.shell.shell, running sh");
+      "sh"
+    }
+  }
+}
+
+/// Build a `Command` running `cmd` with `shell`
+pub fn command(shell: &str, cmd: &str) -> Command {
+  let mut c = Command::new(shell);
+  c.arg("-c").arg(cmd);
+  c
+}
+
+/// The commands in `usr.shell.cmds`, by name
+#[derive(Serialize, Debug)]
+pub struct Listing(pub BTreeMap<String, String>);
+
+impl fmt::Display for Listing {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if self.0.is_empty() {
+      return writeln!(f, "no commands in usr.shell.cmds");
+    }
+    let w = self.0.keys().map(String::len).max().unwrap_or(0);
+    for (name, cmd) in &self.0 {
+      writeln!(f, "{:<w$}  {}", name, cmd, w = w)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_expand() {
+    let vars = Vars {
+      project: Some(Path::new("/s/src/my pkg")),
+      shed: Some(Path::new("/s")),
+    };
+    let x = |cmd: &str, args: &[&str]| expand(cmd, args, vars).unwrap();
+    assert_eq!(x("cargo test", &["-q", "a b"]), "cargo test -q 'a b'");
+    assert_eq!(x("cp {2} {1}", &["a", "it's"]), "cp 'it'\\''s' a");
+    assert_eq!(x("echo {@} end", &["x", "y"]), "echo x y end");
+    assert_eq!(
+      x("cd {project} && ls {shed}/bin ${HOME} {a,b}", &[]),
+      "cd '/s/src/my pkg' && ls /s/bin ${HOME} {a,b}"
+    );
+    assert_eq!(x("awk '{print}' {", &[]), "awk '{print}' {");
+    assert_eq!(x("echo ${1} {1} ${@}", &["a"]), "echo ${1} a ${@}");
+    assert_eq!(x("echo ${1}", &["a"]), "echo ${1} a");
+    assert!(matches!(
+      expand("echo {2}", &["a"], vars),
+      Err(Error::Usage(_))
+    ));
+    assert!(matches!(
+      expand("make -C {project}", &[], Vars::default()),
+      Err(Error::Usage(_))
+    ));
+    assert_eq!(
+      x(r#"echo "it's" \' {1}"#, &["a b"]),
+      r#"echo "it's" \' 'a b'"#
+    );
+    assert_eq!(x("echo '\\' {1}", &["a"]), "echo '\\' a");
+    for cmd in ["echo '{1}'", "echo \"x {@}\"", "cd \"{project}\""] {
+      assert!(matches!(expand(cmd, &["a"], vars), Err(Error::Usage(_))));
+    }
+  }
+}
@@ -0,0 +1,585 @@
+//! status.rs --- shc status sections
+/*!
+Each section of `shc status` is collected into a `Status` field so
+it can be rendered as text, JSON or an s-expression (see `Output`).
+Sections we gather ourselves, the host and repos, are typed structs.
+`--all` collects every repo from `Config.src` and `Config.lab` on a
+bounded number of blocking tasks.
+USB, MIDI, IP and weather come from the rlib and tenex clients,
+whose types may change under us, and are kept in whatever shape
+those serialize to, see `Data`.
+Sections which shell out (`hg`, `git`) never change the working
+directory of the process, commands are run with `current_dir`
+instead.
+*/
+use crate::{
+  list::{fmt_age, fmt_size},
+  vc::{self, output, Vc},
+  Error, Result,
+};
+
+use futures::stream::{self, StreamExt};
+use rlib::{
+  kala::cmd::{midi, sys},
+  logger::log::{error, info},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tenex::{ipapi, nws};
+
+use std::{
+  env, fmt, fs,
+  future::Future,
+  path::{Path, PathBuf},
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::task;
+
+/// Every section requested from `shc status`
+#[derive(Serialize, Debug, Default)]
+pub struct Status {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub host: Option<Host>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub usb: Option<Data>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub ip: Option<Data>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub midi: Option<Data>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub weather: Option<Data>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub vc: Option<Repo>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub repos: Option<Vec<Repo>>,
+}
+
+impl fmt::Display for Status {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let mut first = true;
+    let mut section = |f: &mut fmt::Formatter, name: &str| {
+      if !first {
+        writeln!(f)?;
+      }
+      first = false;
+      writeln!(f, "[{}]", name)
+    };
+    if let Some(h) = &self.host {
+      section(f, "host")?;
+      writeln!(f, "{}", h)?;
+    }
+    if let Some(u) = &self.usb {
+      section(f, "usb")?;
+      writeln!(f, "{}", u)?;
+    }
+    if let Some(i) = &self.ip {
+      section(f, "ip")?;
+      writeln!(f, "{}", i)?;
+    }
+    if let Some(m) = &self.midi {
+      section(f, "midi")?;
+      writeln!(f, "{}", m)?;
+    }
+    if let Some(w) = &self.weather {
+      section(f, "weather")?;
+      writeln!(f, "{}", w)?;
+    }
+    if let Some(r) = &self.vc {
+      section(f, "vc")?;
+      writeln!(f, "{}", r)?;
+    }
+    if let Some(rs) = &self.repos {
+      section(f, "repos")?;
+      writeln!(
+        f,
+        "{:<20} {:<3} {:<12} {:>5} {:>4} {:>4}  last",
+        "name", "vc", "branch", "dirty", "in", "out"
+      )?;
+      for r in rs {
+        let n = |o: Option<usize>| o.map_or_else(|| "-".to_owned(), |n| n.to_string());
+        writeln!(
+          f,
+          "{:<20} {:<3} {:<12} {:>5} {:>4} {:>4}  {}",
+          r.name.as_deref().unwrap_or_default(),
+          r.vc,
+          r.branch.as_deref().unwrap_or("?"),
+          n(r.dirty),
+          n(r.incoming),
+          n(r.outgoing),
+          r.age().unwrap_or_else(|| "-".to_owned())
+        )?;
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Basic host information
+#[derive(Serialize, Debug, Clone)]
+pub struct Host {
+  pub hostname: String,
+  pub os: String,
+  pub arch: String,
+  pub kernel: Option<String>,
+  pub cpus: usize,
+  /// total memory in bytes
+  pub memory: Option<u64>,
+  /// seconds since boot
+  pub uptime: Option<u64>,
+}
+
+impl Host {
+  pub fn collect() -> Host {
+    let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+      .ok()
+      .or_else(|| output("hostname", &[], None))
+      .or_else(|| env::var("HOSTNAME").ok())
+      .map(|h| h.trim().to_owned())
+      .unwrap_or_default();
+    let memory = fs::read_to_string("/proc/meminfo").ok().and_then(|m| {
+      m.lines()
+        .find_map(|l| l.strip_prefix("MemTotal:"))
+        .and_then(|l| l.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+    });
+    let uptime = fs::read_to_string("/proc/uptime").ok().and_then(|u| {
+      u.split_whitespace()
+        .next()
+        .and_then(|s| s.parse::<f64>().ok())
+        .map(|s| s as u64)
+    });
+    Host {
+      hostname,
+      os: env::consts::OS.to_owned(),
+      arch: env::consts::ARCH.to_owned(),
+      kernel: output("uname", &["-r"], None),
+      cpus: std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1),
+      memory,
+      uptime,
+    }
+  }
+}
+
+impl fmt::Display for Host {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "{:<8}{}", "name", self.hostname)?;
+    write!(f, "{:<8}{} {}", "os", self.os, self.arch)?;
+    if let Some(k) = &self.kernel {
+      write!(f, " ({})", k)?;
+    }
+    write!(f, "\n{:<8}{}", "cpus", self.cpus)?;
+    if let Some(m) = self.memory {
+      write!(f, "\n{:<8}{}", "memory", fmt_size(m))?;
+    }
+    if let Some(u) = self.uptime {
+      write!(f, "\n{:<8}{}", "uptime", fmt_duration(u))?;
+    }
+    Ok(())
+  }
+}
+
+/// Whatever an rlib or tenex client returned, in the shape it
+/// serializes to. The clients own those types, so a section keeps
+/// their data as is instead of copying it into structs of our own.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct Data(pub Value);
+
+impl Data {
+  pub fn of<T: Serialize>(t: &T) -> Result<Data> {
+    Ok(Data(serde_json::to_value(t)?))
+  }
+
+  /// The number at `key`, such as the `latitude` of an IP lookup
+  pub fn number(&self, key: &str) -> Option<f64> {
+    self.0.get(key)?.as_f64()
+  }
+}
+
+impl fmt::Display for Data {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match &self.0 {
+      Value::Array(a) => {
+        for (i, v) in a.iter().enumerate() {
+          if i > 0 {
+            writeln!(f)?;
+          }
+          write!(f, "{}", Line(v))?;
+        }
+        Ok(())
+      }
+      Value::Object(m) => {
+        let w = m.keys().map(String::len).max().unwrap_or(0);
+        for (i, (k, v)) in m.iter().enumerate() {
+          if i > 0 {
+            writeln!(f)?;
+          }
+          write!(f, "{:<w$}  {}", k, Line(v), w = w)?;
+        }
+        Ok(())
+      }
+      v => write!(f, "{}", Line(v)),
+    }
+  }
+}
+
+/// A value on one line, with the fields of objects and lists
+/// separated by spaces
+struct Line<'a>(&'a Value);
+
+impl fmt::Display for Line<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let join = |f: &mut fmt::Formatter, vs: &mut dyn Iterator<Item = &Value>| {
+      for (i, v) in vs.enumerate() {
+        if i > 0 {
+          f.write_str(" ")?;
+        }
+        write!(f, "{}", Line(v))?;
+      }
+      Ok(())
+    };
+    match self.0 {
+      Value::Null => f.write_str("-"),
+      Value::String(s) => f.write_str(s),
+      Value::Array(a) => join(f, &mut a.iter()),
+      Value::Object(m) => join(f, &mut m.values()),
+      v => write!(f, "{}", v),
+    }
+  }
+}
+
+/// USB devices, from rlib
+pub fn usb_devices() -> Result<Data> {
+  Data::of(&sys::usb_devices(None).map_err(Error::other)?)
+}
+
+/// MIDI ports, from rlib
+pub fn midi_ports() -> Result<Data> {
+  Data::of(&midi::list_midi_ports().map_err(Error::other)?)
+}
+
+/// The public IP of this host and its rough location, from tenex's
+/// ipapi client
+pub async fn ip_info() -> Result<Data> {
+  Data::of(&ipapi::get_ip().await.map_err(Error::net)?)
+}
+
+/// The forecast for `lat`,`lon`, from tenex's NWS client
+pub async fn weather(lat: f64, lon: f64) -> Result<Data> {
+  Data::of(&nws::weather_report(lat, lon).await.map_err(Error::net)?)
+}
+
+/// A forecast saved by `weather_cached`
+#[derive(Serialize, Deserialize)]
+struct CachedWeather {
+  /// seconds since the epoch
+  fetched: u64,
+  weather: Data,
+}
+
+/// The forecast for `lat`,`lon` saved in `dir` when it is less than
+/// `ttl` old, or else the one from `fetch`. Forecasts are saved per
+/// location, a cache which can't be read or written is skipped.
+pub async fn weather_cached<F>(
+  lat: f64,
+  lon: f64,
+  dir: &Path,
+  ttl: Duration,
+  fetch: F,
+) -> Result<Data>
+where
+  F: Future<Output = Result<Data>>,
+{
+  let path = dir.join(format!("weather-{:.4},{:.4}.json", lat, lon));
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_secs();
+  let cached = fs::read(&path)
+    .ok()
+    .and_then(|b| serde_json::from_slice::<CachedWeather>(&b).ok())
+    .filter(|c| now.saturating_sub(c.fetched) < ttl.as_secs());
+  if let Some(c) = cached {
+    info!("weather from {}", path.display());
+    return Ok(c.weather);
+  }
+  let c = CachedWeather {
+    fetched: now,
+    weather: fetch.await?,
+  };
+  if let Err(e) = fs::create_dir_all(dir)
+    .and_then(|_| fs::write(&path, serde_json::to_vec(&c).unwrap_or_default()))
+  {
+    error!("can't cache weather in {}: {}", path.display(), e);
+  }
+  Ok(c.weather)
+}
+
+/// Summary of a single repository
+#[derive(Serialize, Debug, Clone)]
+pub struct Repo {
+  /// package or project name, for repos from the config
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub name: Option<String>,
+  pub path: PathBuf,
+  pub vc: Vc,
+  pub branch: Option<String>,
+  pub rev: Option<String>,
+  /// number of modified, added or removed files
+  pub dirty: Option<usize>,
+  /// changesets on the default remote missing locally, with `-r`
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub incoming: Option<usize>,
+  /// local changesets missing on the default remote, with `-r`
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub outgoing: Option<usize>,
+  /// time of the working copy parent commit, in seconds since the
+  /// epoch
+  pub last_commit: Option<u64>,
+}
+
+impl Repo {
+  /// Collect the state of the repo at `path`, querying its default
+  /// remote when `remote` is set
+  pub fn collect<P: AsRef<Path>>(path: P, remote: bool) -> Result<Repo> {
+    let path = path.as_ref();
+    let b = vc::detect(path)
+      .ok_or_else(|| Error::Vc(format!("{} is not a repository", path.display())))?;
+    let (incoming, outgoing) = if remote {
+      b.remote_counts(path)
+    } else {
+      (None, None)
+    };
+    Ok(Repo {
+      name: None,
+      path: path.to_path_buf(),
+      vc: b.vc(),
+      branch: b.branch(path),
+      rev: b.rev(path),
+      dirty: b.changes(path),
+      incoming,
+      outgoing,
+      last_commit: b.last_commit(path),
+    })
+  }
+
+  /// How long ago the working copy parent was committed
+  pub fn age(&self) -> Option<String> {
+    self
+      .last_commit
+      .map(|t| fmt_age(UNIX_EPOCH + Duration::from_secs(t)))
+  }
+
+  pub fn is_dirty(&self) -> bool {
+    self.dirty.unwrap_or(0) > 0
+  }
+
+  pub fn is_behind(&self) -> bool {
+    self.incoming.unwrap_or(0) > 0
+  }
+}
+
+/// Collect every `(name, path)` repo with at most `jobs` running at
+/// once, keeping their order. Paths which aren't repos are skipped.
+pub async fn collect_all(repos: Vec<(String, PathBuf)>, remote: bool, jobs: usize) -> Vec<Repo> {
+  stream::iter(repos)
+    .map(|(name, path)| {
+      task::spawn_blocking(move || {
+        Repo::collect(&path, remote).map(|r| Repo {
+          name: Some(name),
+          ..r
+        })
+      })
+    })
+    .buffered(jobs.max(1))
+    .filter_map(|r| async move {
+      match r {
+        Ok(Ok(r)) => Some(r),
+        Ok(Err(e)) => {
+          info!("{}", e);
+          None
+        }
+        Err(e) => {
+          error!("{}", e);
+          None
+        }
+      }
+    })
+    .collect()
+    .await
+}
+
+impl fmt::Display for Repo {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let opt = |o: &Option<String>| o.clone().unwrap_or_else(|| "?".to_owned());
+    write!(
+      f,
+      "{} {} {}@{}",
+      self.path.display(),
+      self.vc,
+      opt(&self.branch),
+      opt(&self.rev)
+    )?;
+    match self.dirty {
+      Some(0) => write!(f, " clean")?,
+      Some(n) => write!(f, " {} changed", n)?,
+      None => write!(f, " ?")?,
+    }
+    if let Some(i) = self.incoming {
+      write!(f, " {} incoming", i)?;
+    }
+    if let Some(o) = self.outgoing {
+      write!(f, " {} outgoing", o)?;
+    }
+    if let Some(a) = self.age() {
+      write!(f, ", last commit {}", a)?;
+    }
+    Ok(())
+  }
+}
+
+/// Format a number of seconds as `3d 4h`, `4h 12m` or `12m`
+pub fn fmt_duration(s: u64) -> String {
+  let (d, h, m) = (s / 86400, s % 86400 / 3600, s % 3600 / 60);
+  match (d, h) {
+    (0, 0) => format!("{}m", m),
+    (0, _) => format!("{}h {}m", h, m),
+    _ => format!("{}d {}h", d, h),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{self, TempDir};
+  use serde_json::json;
+  use std::process::Command;
+
+  #[test]
+  fn test_data_display() {
+    let d = |v: Value| Data(v).to_string();
+    assert_eq!(
+      d(json!([
+        {"bus": "001", "id": "8087:0024", "name": "Intel Corp."},
+        {"bus": "002", "id": "1d6b:0003", "name": null}
+      ])),
+      "001 8087:0024 Intel Corp.\n002 1d6b:0003 -"
+    );
+    assert_eq!(
+      d(json!({"ip": "1.2.3.4", "city": "Mystic", "latitude": 41.35})),
+      "city      Mystic\nip        1.2.3.4\nlatitude  41.35"
+    );
+    assert_eq!(d(json!(["nanoKEY2", ["a", 1]])), "nanoKEY2\na 1");
+    assert_eq!(d(Value::Null), "-");
+    assert_eq!(
+      Data(json!({"latitude": 41.35})).number("latitude"),
+      Some(41.35)
+    );
+    assert_eq!(Data(json!({"latitude": "x"})).number("latitude"), None);
+  }
+
+  #[test]
+  fn test_fmt_duration() {
+    assert_eq!(fmt_duration(59), "0m");
+    assert_eq!(fmt_duration(3600 + 120), "1h 2m");
+    assert_eq!(fmt_duration(3 * 86400 + 4 * 3600 + 59), "3d 4h");
+  }
+
+  #[tokio::test]
+  async fn test_collect_all() {
+    let root = TempDir::new("status");
+    let repo = root.join("repo");
+    fs::create_dir_all(&repo).unwrap();
+    fs::create_dir_all(root.join("plain")).unwrap();
+    let git = |args: &[&str]| {
+      Command::new("git")
+        .args(["-c", "user.name=t", "-c", "user.email=t@t"])
+        .args(args)
+        .current_dir(&repo)
+        .output()
+        .unwrap()
+    };
+    git(&["init", "-q"]);
+    fs::write(repo.join("a"), "a").unwrap();
+    git(&["add", "a"]);
+    git(&["commit", "-qm", "a"]);
+    fs::write(repo.join("a"), "b").unwrap();
+
+    let repos = collect_all(
+      vec![
+        ("plain".to_owned(), root.join("plain")),
+        ("repo".to_owned(), repo.clone()),
+      ],
+      false,
+      2,
+    )
+    .await;
+    assert_eq!(repos.len(), 1);
+    let r = &repos[0];
+    assert_eq!(r.name.as_deref(), Some("repo"));
+    assert_eq!(r.vc, Vc::Git);
+    assert_eq!(r.dirty, Some(1));
+    assert!(r.is_dirty() && !r.is_behind());
+    assert!(r.last_commit.is_some());
+  }
+
+  #[tokio::test]
+  async fn test_weather_cached() {
+    use hyper::{Body, Response, StatusCode};
+    use std::sync::{
+      atomic::{AtomicUsize, Ordering},
+      Arc,
+    };
+    // stands in for the NWS API, counting requests
+    let hits = Arc::new(AtomicUsize::new(0));
+    let h = hits.clone();
+    let addr = testing::serve(move |req| {
+      h.fetch_add(1, Ordering::SeqCst);
+      match req.uri().path() {
+        "/forecast" => Response::new(Body::from(r#"{"name": "Tonight", "temperature": 41}"#)),
+        _ => Response::builder()
+          .status(StatusCode::SERVICE_UNAVAILABLE)
+          .body(Body::empty())
+          .unwrap(),
+      }
+    });
+    let get = |path: &'static str| async move {
+      let (status, body) = testing::get(addr, path).await;
+      if status != StatusCode::OK {
+        return Err(Error::net(status));
+      }
+      Ok(Data(serde_json::from_str(&body)?))
+    };
+    let fetch = || get("/forecast");
+    let dir = TempDir::new("weather");
+    let ttl = Duration::from_secs(60);
+    let at = (41.3557, -72.0995);
+    let w = weather_cached(at.0, at.1, &dir, ttl, fetch())
+      .await
+      .unwrap();
+    assert_eq!(w.0["temperature"], 41);
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+    let w = weather_cached(at.0, at.1, &dir, ttl, fetch())
+      .await
+      .unwrap();
+    assert_eq!(w.to_string(), "name         Tonight\ntemperature  41");
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+    weather_cached(at.0, at.1, &dir, Duration::ZERO, fetch())
+      .await
+      .unwrap();
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+    // a failed fetch isn't cached over a good report
+    assert!(
+      weather_cached(at.0, at.1, &dir, Duration::ZERO, get("/down"))
+        .await
+        .is_err()
+    );
+    let w = weather_cached(at.0, at.1, &dir, ttl, fetch())
+      .await
+      .unwrap();
+    assert_eq!(w.0["name"], "Tonight");
+    assert_eq!(hits.load(Ordering::SeqCst), 3);
+  }
+}
@@ -0,0 +1,381 @@
+//! sync.rs --- push and pull across repos
+/*!
+`shc push` and `shc pull` run against one or more repos at once. The
+target is either a name from `MercurialConfig.paths`, whose value is
+used as a base URL with the repo name appended, or anything the VC
+program understands itself (a repo-local path name or a URL).
+
+Each repo runs on its own blocking task with a bounded number running
+at once. Output is captured and classified so the summary can tell a
+rejected push from a bad password. Pulls skip repos with uncommitted
+changes unless the target is forced, pushes only send what's
+committed and never check.
+*/
+use crate::{
+  vc::{self, Direction, Vc},
+  Config,
+};
+
+use futures::stream::{self, StreamExt};
+
+use std::{
+  fmt,
+  path::PathBuf,
+  process::Stdio,
+  time::{Duration, Instant},
+};
+use tokio::task;
+
+/// Result of syncing a single repo
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+  Ok,
+  /// nothing to push or pull
+  Unchanged,
+  /// rejected push, new heads or a merge is needed
+  Conflict,
+  /// the remote refused our credentials
+  Auth,
+  /// the working copy at this path has uncommitted changes
+  Dirty(PathBuf),
+  /// anything else, with the last line of output
+  Failed(String),
+}
+
+impl Outcome {
+  pub fn failed(&self) -> bool {
+    !matches!(self, Outcome::Ok | Outcome::Unchanged)
+  }
+}
+
+impl fmt::Display for Outcome {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Outcome::Ok => f.write_str("ok"),
+      Outcome::Unchanged => f.write_str("no changes"),
+      Outcome::Conflict => f.write_str("CONFLICT"),
+      Outcome::Auth => f.write_str("AUTH FAILED"),
+      Outcome::Dirty(_) => f.write_str("DIRTY"),
+      Outcome::Failed(m) => write!(f, "FAILED ({})", m),
+    }
+  }
+}
+
+/// A repo to push or pull
+#[derive(Debug, Clone)]
+pub struct Target {
+  pub name: String,
+  pub path: PathBuf,
+  /// path name or URL, `None` for the repo's default
+  pub dest: Option<String>,
+  /// sync even with uncommitted changes
+  pub force: bool,
+}
+
+impl Target {
+  /// Sync `name` at `path`, resolving `dest` against the configured
+  /// `hg.paths`
+  pub fn new(cfg: &Config, name: &str, path: PathBuf, dest: Option<&str>) -> Self {
+    let dest = dest.map(|d| match cfg.hg.paths.as_ref().and_then(|p| p.get(d)) {
+      Some(base) => format!("{}/{}", base.trim_end_matches('/'), name),
+      None => d.to_owned(),
+    });
+    Target {
+      name: name.to_owned(),
+      path,
+      dest,
+      force: false,
+    }
+  }
+}
+
+/// The outcome of syncing a `Target`
+#[derive(Debug)]
+pub struct Report {
+  pub name: String,
+  pub outcome: Outcome,
+  pub duration: Duration,
+}
+
+impl fmt::Display for Report {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "{:<24} {:>8.2}s {}",
+      self.name,
+      self.duration.as_secs_f64(),
+      self.outcome
+    )
+  }
+}
+
+/// Push or pull a single repo
+pub fn sync(t: &Target, dir: Direction) -> Report {
+  let start = Instant::now();
+  let outcome = match vc::detect(&t.path) {
+    None => Outcome::Failed("not a repository".to_owned()),
+    Some(b) if dir == Direction::Pull && !t.force && b.is_dirty(&t.path) == Some(true) => {
+      Outcome::Dirty(t.path.clone())
+    }
+    Some(b) => {
+      let out = b
+        .sync(&t.path, dir, t.dest.as_deref())
+        // never block on a password prompt
+        .stdin(Stdio::null())
+        .output();
+      match out {
+        Ok(o) => {
+          let text = format!(
+            "{}{}",
+            String::from_utf8_lossy(&o.stdout),
+            String::from_utf8_lossy(&o.stderr)
+          );
+          classify(b.vc(), dir, o.status.code(), &text)
+        }
+        Err(e) => Outcome::Failed(e.to_string()),
+      }
+    }
+  };
+  Report {
+    name: t.name.clone(),
+    outcome,
+    duration: start.elapsed(),
+  }
+}
+
+/// Classify the exit code and output of a push or pull. A zero exit
+/// is always a success, the output only explains the other codes.
+pub fn classify(vc: Vc, dir: Direction, code: Option<i32>, text: &str) -> Outcome {
+  let lower = text.to_lowercase();
+  let has = |pats: &[&str]| pats.iter().any(|p| lower.contains(p));
+  // hg pull -u exits 0 when it pulled new heads but couldn't update
+  if has(&["not updating: not a linear update", "'hg merge' to merge"]) {
+    return Outcome::Conflict;
+  }
+  if code == Some(0) {
+    return if has(&["no changes found", "up-to-date", "up to date"]) {
+      Outcome::Unchanged
+    } else {
+      Outcome::Ok
+    };
+  }
+  if has(&[
+    "authorization failed",
+    "authentication failed",
+    "permission denied",
+    "could not read username",
+    "http error 401",
+    "http error 403",
+  ]) {
+    return Outcome::Auth;
+  }
+  if has(&[
+    "creates new remote head",
+    "rejected",
+    "non-fast-forward",
+    "not possible to fast-forward",
+    "diverging branches",
+    "conflict",
+  ]) {
+    return Outcome::Conflict;
+  }
+  match (vc, dir, code) {
+    // hg push exits with 1 when there is nothing to push
+    (Vc::Hg, Direction::Push, Some(1)) if has(&["no changes found"]) => Outcome::Unchanged,
+    (_, _, c) => Outcome::Failed(
+      text
+        .lines()
+        .map(str::trim)
+        .rfind(|l| !l.is_empty())
+        .map(str::to_owned)
+        .unwrap_or_else(|| match c {
+          Some(c) => format!("exit {}", c),
+          None => "killed".to_owned(),
+        }),
+    ),
+  }
+}
+
+/// Sync every target with at most `jobs` running at once, keeping
+/// their order
+pub async fn sync_all(targets: Vec<Target>, dir: Direction, jobs: usize) -> Vec<Report> {
+  stream::iter(targets)
+    .map(|t| async move {
+      let name = t.name.clone();
+      task::spawn_blocking(move || sync(&t, dir))
+        .await
+        .unwrap_or_else(|e| Report {
+          name,
+          outcome: Outcome::Failed(e.to_string()),
+          duration: Duration::default(),
+        })
+    })
+    .buffered(jobs.max(1))
+    .collect()
+    .await
+}
+
+/// Print a summary table of `reports` to stdout
+pub fn summary(reports: &[Report]) {
+  println!("{:-<48}", "");
+  for r in reports {
+    println!("{}", r);
+  }
+  let count = |f: fn(&Outcome) -> bool| reports.iter().filter(|r| f(&r.outcome)).count();
+  println!(
+    "{} ok, {} unchanged, {} conflicts, {} auth failures, {} dirty, {} failed",
+    count(|o| *o == Outcome::Ok),
+    count(|o| *o == Outcome::Unchanged),
+    count(|o| *o == Outcome::Conflict),
+    count(|o| *o == Outcome::Auth),
+    count(|o| matches!(o, Outcome::Dirty(_))),
+    count(|o| matches!(o, Outcome::Failed(_))),
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::TempDir;
+  use std::path::Path;
+
+  #[test]
+  fn test_classify() {
+    use Direction::*;
+    assert_eq!(
+      classify(
+        Vc::Hg,
+        Push,
+        Some(1),
+        "pushing to x\nsearching for changes\nno changes found\n"
+      ),
+      Outcome::Unchanged
+    );
+    assert_eq!(
+      classify(
+        Vc::Hg,
+        Push,
+        Some(255),
+        "abort: push creates new remote head 1a2b3c!"
+      ),
+      Outcome::Conflict
+    );
+    assert_eq!(
+      classify(
+        Vc::Hg,
+        Pull,
+        Some(0),
+        "added 1 changesets with 1 changes to 1 files (+1 heads)\n\
+         not updating: not a linear update\n\
+         (merge or update --check to force update)\n\
+         (run 'hg heads' to see heads, 'hg merge' to merge)"
+      ),
+      Outcome::Conflict
+    );
+    assert_eq!(
+      classify(Vc::Hg, Pull, Some(255), "abort: authorization failed"),
+      Outcome::Auth
+    );
+    assert_eq!(
+      classify(
+        Vc::Git,
+        Push,
+        Some(1),
+        " ! [rejected]        main -> main (fetch first)"
+      ),
+      Outcome::Conflict
+    );
+    assert_eq!(
+      classify(
+        Vc::Git,
+        Pull,
+        Some(128),
+        "fatal: could not read Username for 'https://x'"
+      ),
+      Outcome::Auth
+    );
+    assert_eq!(
+      classify(Vc::Git, Pull, Some(0), "Already up to date.\n"),
+      Outcome::Unchanged
+    );
+    assert_eq!(
+      classify(Vc::Git, Push, Some(0), "To x\n   1..2  main -> main\n"),
+      Outcome::Ok
+    );
+    // file names and commit messages can say anything
+    assert_eq!(
+      classify(
+        Vc::Git,
+        Pull,
+        Some(0),
+        "Updating 1..2\nFast-forward\n src/conflict.rs | 2 +-\n"
+      ),
+      Outcome::Ok
+    );
+    assert_eq!(
+      classify(
+        Vc::Hg,
+        Push,
+        Some(0),
+        "pushing to x\nremote: fix rejected permission denied handling\n"
+      ),
+      Outcome::Ok
+    );
+    assert_eq!(
+      classify(Vc::Git, Pull, Some(1), "fatal: no remote\n\n"),
+      Outcome::Failed("fatal: no remote".to_owned())
+    );
+  }
+
+  #[tokio::test]
+  async fn test_sync_git() {
+    let root = TempDir::new("sync");
+    use std::process::Command;
+    let git = |dir: &Path, args: &[&str]| {
+      Command::new("git")
+        .args(["-c", "user.name=t", "-c", "user.email=t@t"])
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap()
+    };
+    git(&root, &["init", "-q", "--bare", "origin"]);
+    git(&root, &["clone", "-q", "origin", "work"]);
+    let work = root.join("work");
+    std::fs::write(work.join("a"), "a").unwrap();
+    git(&work, &["add", "a"]);
+    git(&work, &["commit", "-qm", "a"]);
+    let target = Target {
+      name: "work".to_owned(),
+      path: work.clone(),
+      dest: Some("origin".to_owned()),
+      force: false,
+    };
+    let head = String::from_utf8(git(&work, &["symbolic-ref", "--short", "HEAD"]).stdout).unwrap();
+    git(&work, &["push", "-q", "-u", "origin", head.trim()]);
+    std::fs::write(work.join("a"), "b").unwrap();
+    git(&work, &["commit", "-qam", "b"]);
+
+    let reports = sync_all(vec![target.clone(), target.clone()], Direction::Push, 1).await;
+    assert_eq!(reports[0].outcome, Outcome::Ok);
+    assert_eq!(reports[1].outcome, Outcome::Unchanged);
+    std::fs::write(work.join("a"), "c").unwrap();
+    assert_eq!(sync(&target, Direction::Push).outcome, Outcome::Unchanged);
+    assert_eq!(
+      sync(&target, Direction::Pull).outcome,
+      Outcome::Dirty(work.clone())
+    );
+    let forced = Target {
+      force: true,
+      ..target.clone()
+    };
+    assert_eq!(sync(&forced, Direction::Pull).outcome, Outcome::Unchanged);
+    let missing = Target {
+      name: "missing".to_owned(),
+      path: root.join("missing"),
+      dest: None,
+      force: false,
+    };
+    assert!(sync(&missing, Direction::Pull).outcome.failed());
+  }
+}
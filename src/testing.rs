@@ -0,0 +1,101 @@
+//! testing.rs --- shared test fixtures
+/*!
+Scratch directories which are removed when dropped, even when an
+assertion fails, and a local HTTP server standing in for remote APIs.
+*/
+use hyper::{
+  body,
+  service::{make_service_fn, service_fn},
+  Body, Client, Request, Response, Server, StatusCode,
+};
+
+use std::{
+  convert::Infallible,
+  fs,
+  net::SocketAddr,
+  ops::Deref,
+  path::{Path, PathBuf},
+  process,
+  sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A fresh directory under the system temp dir, removed on drop
+#[derive(Debug)]
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+  /// Create an empty directory whose name starts with `shed-<name>`
+  pub fn new(name: &str) -> Self {
+    static N: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+      "shed-{}-{}-{}",
+      name,
+      process::id(),
+      N.fetch_add(1, Ordering::SeqCst)
+    ));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    TempDir(path)
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.0
+  }
+}
+
+impl Deref for TempDir {
+  type Target = Path;
+  fn deref(&self) -> &Path {
+    &self.0
+  }
+}
+
+impl AsRef<Path> for TempDir {
+  fn as_ref(&self) -> &Path {
+    &self.0
+  }
+}
+
+impl Drop for TempDir {
+  fn drop(&mut self) {
+    let _ = fs::remove_dir_all(&self.0);
+  }
+}
+
+/// Answer every request on a local socket with `handler`. Must be
+/// called inside a tokio runtime.
+pub fn serve<F>(handler: F) -> SocketAddr
+where
+  F: Fn(Request<Body>) -> Response<Body> + Clone + Send + Sync + 'static,
+{
+  let make = make_service_fn(move |_| {
+    let handler = handler.clone();
+    async move {
+      Ok::<_, Infallible>(service_fn(move |req| {
+        let res = handler(req);
+        async move { Ok::<_, Infallible>(res) }
+      }))
+    }
+  });
+  let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make);
+  let addr = server.local_addr();
+  tokio::spawn(server);
+  addr
+}
+
+/// GET `path` from `addr`, returning the response with its body read
+pub async fn fetch(addr: SocketAddr, path: &str) -> Response<Vec<u8>> {
+  let url = format!("http://{}{}", addr, path);
+  let res = Client::new().get(url.parse().unwrap()).await.unwrap();
+  let (parts, b) = res.into_parts();
+  Response::from_parts(parts, body::to_bytes(b).await.unwrap().to_vec())
+}
+
+/// GET `path` from `addr` as text
+pub async fn get(addr: SocketAddr, path: &str) -> (StatusCode, String) {
+  let res = fetch(addr, path).await;
+  (
+    res.status(),
+    String::from_utf8_lossy(res.body()).into_owned(),
+  )
+}
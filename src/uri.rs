@@ -0,0 +1,218 @@
+//! uri.rs --- shed resource URIs
+/*!
+Object URIs accepted by `shc download`. A URI is either a full URL
+(`http://`, `https://`, `ssh://`) or a short `scheme:resource` form:
+
+- `hg:<repo>` :: Mercurial repo on hg.rwest.io, `hg:.` pulls CWD
+- `a:<path>` :: file from rwest.io/a
+- `y:<path>` :: file from rwest.io/y
+- `drive:<id>` :: Google Drive object
+- `dm:<peer>` :: direct message
+*/
+use rlib::net::reqwest::Url;
+
+use std::{
+  error, fmt,
+  path::{Component, Path},
+  str::FromStr,
+};
+
+/// A parsed object URI
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResourceUri {
+  Hg(String),
+  A(String),
+  Y(String),
+  Http(Url),
+  Ssh(Url),
+  Drive(String),
+  Dm(String),
+}
+
+impl ResourceUri {
+  /// The remote URL of this resource, if it has one
+  pub fn url(&self) -> Option<Url> {
+    match self {
+      ResourceUri::Hg(r) if r != "." => Url::parse(&format!("https://hg.rwest.io/{}", r)).ok(),
+      ResourceUri::A(p) => Url::parse(&format!("https://rwest.io/a/{}", p)).ok(),
+      ResourceUri::Y(p) => Url::parse(&format!("https://rwest.io/y/{}", p)).ok(),
+      ResourceUri::Http(u) | ResourceUri::Ssh(u) => Some(u.clone()),
+      _ => None,
+    }
+  }
+}
+
+impl FromStr for ResourceUri {
+  type Err = UriError;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let s = s.trim();
+    if s.is_empty() {
+      return Err(UriError::Empty);
+    }
+    let (scheme, rest) = s
+      .split_once(':')
+      .ok_or_else(|| UriError::MissingScheme(s.to_owned()))?;
+    let url =
+      |s: &str| Url::parse(s).map_err(|e| UriError::InvalidUrl(s.to_owned(), e.to_string()));
+    let resource = |r: &str| {
+      if r.is_empty() {
+        Err(UriError::MissingResource(scheme.to_owned()))
+      } else {
+        Ok(r.to_owned())
+      }
+    };
+    match scheme {
+      "http" | "https" if rest.starts_with("//") => Ok(ResourceUri::Http(url(s)?)),
+      // accept the short form 'http:site.com'
+      "http" | "https" => Ok(ResourceUri::Http(url(&format!(
+        "{}://{}",
+        scheme,
+        resource(rest)?
+      ))?)),
+      "ssh" => {
+        let u = url(s)?;
+        match u.host_str() {
+          Some(h) if !h.is_empty() => Ok(ResourceUri::Ssh(u)),
+          _ => Err(UriError::InvalidUrl(
+            s.to_owned(),
+            "missing host".to_owned(),
+          )),
+        }
+      }
+      "hg" => {
+        // the name becomes a directory under stash/tmp
+        let r = resource(rest)?;
+        if Path::new(&r)
+          .components()
+          .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+          Ok(ResourceUri::Hg(r))
+        } else {
+          Err(UriError::InvalidResource(s.to_owned()))
+        }
+      }
+      "a" => Ok(ResourceUri::A(resource(rest)?)),
+      "y" => Ok(ResourceUri::Y(resource(rest)?)),
+      "drive" => Ok(ResourceUri::Drive(resource(rest)?)),
+      "dm" => Ok(ResourceUri::Dm(resource(rest)?)),
+      s => Err(UriError::UnknownScheme(s.to_owned())),
+    }
+  }
+}
+
+impl fmt::Display for ResourceUri {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ResourceUri::Hg(r) => write!(f, "hg:{}", r),
+      ResourceUri::A(p) => write!(f, "a:{}", p),
+      ResourceUri::Y(p) => write!(f, "y:{}", p),
+      ResourceUri::Http(u) | ResourceUri::Ssh(u) => write!(f, "{}", u),
+      ResourceUri::Drive(i) => write!(f, "drive:{}", i),
+      ResourceUri::Dm(p) => write!(f, "dm:{}", p),
+    }
+  }
+}
+
+/// Errors produced while parsing a `ResourceUri`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UriError {
+  Empty,
+  MissingScheme(String),
+  UnknownScheme(String),
+  MissingResource(String),
+  InvalidResource(String),
+  InvalidUrl(String, String),
+}
+
+impl fmt::Display for UriError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      UriError::Empty => f.write_str("an object URI is required"),
+      UriError::MissingScheme(s) => {
+        write!(f, "'{}' has no scheme, expected <scheme>:<resource>", s)
+      }
+      UriError::UnknownScheme(s) => write!(f, "unknown URI scheme '{}'", s),
+      UriError::MissingResource(s) => write!(f, "'{}:' is missing a resource", s),
+      UriError::InvalidResource(s) => {
+        write!(f, "'{}' must be a relative name without '..'", s)
+      }
+      UriError::InvalidUrl(u, e) => write!(f, "invalid URL '{}': {}", u, e),
+    }
+  }
+}
+
+impl error::Error for UriError {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[test]
+  fn test_short_forms() {
+    assert_eq!("hg:shed".parse(), Ok(ResourceUri::Hg("shed".into())));
+    assert_eq!("hg:.".parse(), Ok(ResourceUri::Hg(".".into())));
+    assert_eq!("hg:x/shed".parse(), Ok(ResourceUri::Hg("x/shed".into())));
+    assert_eq!("a:x.org".parse(), Ok(ResourceUri::A("x.org".into())));
+    assert_eq!("y:img.png".parse(), Ok(ResourceUri::Y("img.png".into())));
+    assert_eq!(
+      "drive:abc123".parse(),
+      Ok(ResourceUri::Drive("abc123".into()))
+    );
+    assert_eq!("dm:ellis".parse(), Ok(ResourceUri::Dm("ellis".into())));
+  }
+  #[test]
+  fn test_urls() {
+    let u: ResourceUri = "https://rwest.io/a/x.org".parse().unwrap();
+    assert_eq!(
+      u,
+      ResourceUri::Http(Url::parse("https://rwest.io/a/x.org").unwrap())
+    );
+    let u: ResourceUri = "http:site.com/f".parse().unwrap();
+    assert_eq!(u.url().unwrap().as_str(), "http://site.com/f");
+    let u: ResourceUri = "ssh://ellis@hg.rwest.io/shed".parse().unwrap();
+    assert!(matches!(u, ResourceUri::Ssh(ref u) if u.username() == "ellis"));
+    assert_eq!(
+      "a:x.org"
+        .parse::<ResourceUri>()
+        .unwrap()
+        .url()
+        .unwrap()
+        .as_str(),
+      "https://rwest.io/a/x.org"
+    );
+    assert_eq!("hg:.".parse::<ResourceUri>().unwrap().url(), None);
+  }
+  #[test]
+  fn test_invalid() {
+    assert_eq!("".parse::<ResourceUri>(), Err(UriError::Empty));
+    assert_eq!(
+      "shed".parse::<ResourceUri>(),
+      Err(UriError::MissingScheme("shed".into()))
+    );
+    assert_eq!(
+      "ftp://x".parse::<ResourceUri>(),
+      Err(UriError::UnknownScheme("ftp".into()))
+    );
+    assert_eq!(
+      "a:".parse::<ResourceUri>(),
+      Err(UriError::MissingResource("a".into()))
+    );
+    assert_eq!(
+      "h:".parse::<ResourceUri>(),
+      Err(UriError::UnknownScheme("h".into()))
+    );
+    for s in ["hg:/etc", "hg:..", "hg:foo/../../bar"] {
+      assert_eq!(
+        s.parse::<ResourceUri>(),
+        Err(UriError::InvalidResource(s.into()))
+      );
+    }
+    assert!(matches!(
+      "https://".parse::<ResourceUri>(),
+      Err(UriError::InvalidUrl(..))
+    ));
+    assert!(matches!(
+      "ssh:".parse::<ResourceUri>(),
+      Err(UriError::InvalidUrl(..))
+    ));
+  }
+}
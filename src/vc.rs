@@ -0,0 +1,390 @@
+//! vc.rs --- version control backends
+/*!
+shed manages both Mercurial and git repos. Everything that shells out
+to a VC program goes through a `VcBackend`, chosen per repo by
+`detect`, so callers never build `hg` or `git` command lines
+themselves.
+
+Backends are stateless and run their programs synchronously, with
+stdin closed so a password prompt can't hang a batch of repos. Async
+callers run them on a blocking task.
+*/
+use crate::{Error, Result};
+
+use serde::Serialize;
+
+use std::{
+  fmt,
+  path::Path,
+  process::{Command, Stdio},
+};
+
+/// Version control system of a directory
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Vc {
+  Hg,
+  Git,
+}
+
+impl Vc {
+  /// Detect the VC system managing `path`
+  pub fn detect<P: AsRef<Path>>(path: P) -> Option<Vc> {
+    let path = path.as_ref();
+    if path.join(".hg").is_dir() {
+      Some(Vc::Hg)
+    } else if path.join(".git").exists() {
+      Some(Vc::Git)
+    } else {
+      None
+    }
+  }
+
+  /// The backend for this system
+  pub fn backend(self) -> &'static dyn VcBackend {
+    match self {
+      Vc::Hg => &Mercurial,
+      Vc::Git => &Git,
+    }
+  }
+}
+
+impl fmt::Display for Vc {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(match self {
+      Vc::Hg => "hg",
+      Vc::Git => "git",
+    })
+  }
+}
+
+/// Which way changesets travel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+  Push,
+  Pull,
+}
+
+impl fmt::Display for Direction {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(match self {
+      Direction::Push => "push",
+      Direction::Pull => "pull",
+    })
+  }
+}
+
+/// Detect the backend for the repo at `path`
+pub fn detect<P: AsRef<Path>>(path: P) -> Option<&'static dyn VcBackend> {
+  Vc::detect(path).map(Vc::backend)
+}
+
+/// Operations shed needs from a VC system. Queries return `None`
+/// when the program fails, so one broken repo doesn't hide the rest
+/// of a report.
+pub trait VcBackend: Send + Sync {
+  /// Which system this is
+  fn vc(&self) -> Vc;
+
+  /// Name of the current branch
+  fn branch(&self, dir: &Path) -> Option<String>;
+
+  /// Short id of the working copy parent
+  fn rev(&self, dir: &Path) -> Option<String>;
+
+  /// Number of modified, added or removed files
+  fn changes(&self, dir: &Path) -> Option<usize>;
+
+  /// Time of the working copy parent commit, in seconds since the
+  /// epoch
+  fn last_commit(&self, dir: &Path) -> Option<u64>;
+
+  /// Changesets on the default remote missing locally and local
+  /// changesets missing on it. Talks to the remote.
+  fn remote_counts(&self, dir: &Path) -> (Option<usize>, Option<usize>);
+
+  /// Command pushing or pulling the repo at `dir`, to or from `dest`
+  /// or the default remote. The working copy is updated after a pull
+  /// when that doesn't need a merge.
+  fn sync(&self, dir: &Path, d: Direction, dest: Option<&str>) -> Command;
+
+  /// Clone `url` into `dst`, showing the program's progress
+  fn clone_repo(&self, url: &str, dst: &Path) -> Result<()>;
+
+  /// The last `n` commits reachable from the working copy, newest
+  /// first
+  fn log(&self, dir: &Path, n: usize) -> Option<Vec<Commit>>;
+
+  /// Check for uncommitted changes
+  fn is_dirty(&self, dir: &Path) -> Option<bool> {
+    self.changes(dir).map(|n| n > 0)
+  }
+}
+
+/// A single commit as shown by `VcBackend::log`
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Commit {
+  pub rev: String,
+  pub author: String,
+  /// seconds since the epoch
+  pub time: u64,
+  /// first line of the message
+  pub summary: String,
+}
+
+impl Commit {
+  /// Parse the tab separated `rev author time summary` lines both
+  /// backends are asked to print
+  fn parse_log(out: &str) -> Vec<Commit> {
+    out
+      .lines()
+      .filter_map(|l| {
+        let mut f = l.splitn(4, '\t');
+        Some(Commit {
+          rev: f.next()?.to_owned(),
+          author: f.next()?.to_owned(),
+          // hg prints '<seconds> <tz offset>'
+          time: f.next()?.split_whitespace().next()?.parse().ok()?,
+          summary: f.next().unwrap_or_default().to_owned(),
+        })
+      })
+      .collect()
+  }
+}
+
+/// Mercurial
+pub struct Mercurial;
+
+impl VcBackend for Mercurial {
+  fn vc(&self) -> Vc {
+    Vc::Hg
+  }
+
+  fn branch(&self, dir: &Path) -> Option<String> {
+    output("hg", &["branch"], Some(dir))
+  }
+
+  fn rev(&self, dir: &Path) -> Option<String> {
+    output("hg", &["id", "-i"], Some(dir)).map(|r| r.trim_end_matches('+').to_owned())
+  }
+
+  fn changes(&self, dir: &Path) -> Option<usize> {
+    output("hg", &["status", "-mard"], Some(dir)).map(|s| s.lines().count())
+  }
+
+  fn last_commit(&self, dir: &Path) -> Option<u64> {
+    // '<seconds> <tz offset>'
+    output("hg", &["log", "-r", ".", "-T", "{date|hgdate}"], Some(dir))?
+      .split_whitespace()
+      .next()?
+      .parse()
+      .ok()
+  }
+
+  fn remote_counts(&self, dir: &Path) -> (Option<usize>, Option<usize>) {
+    (
+      count("hg", &["incoming", "-q"], dir),
+      count("hg", &["outgoing", "-q"], dir),
+    )
+  }
+
+  fn sync(&self, dir: &Path, d: Direction, dest: Option<&str>) -> Command {
+    let mut c = Command::new("hg");
+    match d {
+      Direction::Push => c.arg("push"),
+      Direction::Pull => c.args(["pull", "--update"]),
+    };
+    c.args(dest).current_dir(dir).env("HGPLAIN", "1");
+    c
+  }
+
+  fn clone_repo(&self, url: &str, dst: &Path) -> Result<()> {
+    run(Command::new("hg").arg("clone").arg(url).arg(dst))
+  }
+
+  fn log(&self, dir: &Path, n: usize) -> Option<Vec<Commit>> {
+    let n = n.to_string();
+    let tmpl = "{node|short}\t{author|person}\t{date|hgdate}\t{desc|firstline}\n";
+    output(
+      "hg",
+      &["log", "-r", "reverse(::.)", "-l", &n, "-T", tmpl],
+      Some(dir),
+    )
+    .map(|o| Commit::parse_log(&o))
+  }
+}
+
+/// git
+pub struct Git;
+
+impl VcBackend for Git {
+  fn vc(&self) -> Vc {
+    Vc::Git
+  }
+
+  fn branch(&self, dir: &Path) -> Option<String> {
+    output("git", &["rev-parse", "--abbrev-ref", "HEAD"], Some(dir))
+  }
+
+  fn rev(&self, dir: &Path) -> Option<String> {
+    output("git", &["rev-parse", "--short", "HEAD"], Some(dir))
+  }
+
+  fn changes(&self, dir: &Path) -> Option<usize> {
+    output("git", &["status", "--porcelain", "-uno"], Some(dir)).map(|s| s.lines().count())
+  }
+
+  fn last_commit(&self, dir: &Path) -> Option<u64> {
+    output("git", &["log", "-1", "--format=%ct"], Some(dir))?
+      .parse()
+      .ok()
+  }
+
+  fn remote_counts(&self, dir: &Path) -> (Option<usize>, Option<usize>) {
+    let dir = Some(dir);
+    output("git", &["fetch", "-q"], dir);
+    let n = |range| {
+      output("git", &["rev-list", "--count", range], dir)?
+        .parse()
+        .ok()
+    };
+    (n("HEAD..@{u}"), n("@{u}..HEAD"))
+  }
+
+  fn sync(&self, dir: &Path, d: Direction, dest: Option<&str>) -> Command {
+    let mut c = Command::new("git");
+    match d {
+      Direction::Push => c.arg("push"),
+      Direction::Pull => c.args(["pull", "--ff-only"]),
+    };
+    c.args(dest)
+      .current_dir(dir)
+      .env("GIT_TERMINAL_PROMPT", "0");
+    c
+  }
+
+  fn clone_repo(&self, url: &str, dst: &Path) -> Result<()> {
+    run(Command::new("git").arg("clone").arg(url).arg(dst))
+  }
+
+  fn log(&self, dir: &Path, n: usize) -> Option<Vec<Commit>> {
+    let n = format!("-n{}", n);
+    output(
+      "git",
+      &["log", &n, "--format=%h%x09%an%x09%ct%x09%s"],
+      Some(dir),
+    )
+    .map(|o| Commit::parse_log(&o))
+  }
+}
+
+/// Run `cmd` in `dir` and return its trimmed stdout on success
+pub(crate) fn output(cmd: &str, args: &[&str], dir: Option<&Path>) -> Option<String> {
+  let mut c = Command::new(cmd);
+  c.args(args).stdin(Stdio::null()).stderr(Stdio::null());
+  if let Some(d) = dir {
+    c.current_dir(d);
+  }
+  let out = c.output().ok()?;
+  if out.status.success() {
+    Some(String::from_utf8_lossy(&out.stdout).trim().to_owned())
+  } else {
+    None
+  }
+}
+
+/// Count the lines printed by a command which exits with 1 when it
+/// has nothing to print
+fn count(cmd: &str, args: &[&str], dir: &Path) -> Option<usize> {
+  let out = Command::new(cmd)
+    .args(args)
+    .current_dir(dir)
+    .stdin(Stdio::null())
+    .stderr(Stdio::null())
+    .output()
+    .ok()?;
+  match out.status.code() {
+    Some(0) => Some(String::from_utf8_lossy(&out.stdout).lines().count()),
+    Some(1) => Some(0),
+    _ => None,
+  }
+}
+
+/// Run `cmd` with inherited output, failing on a non-zero exit
+fn run(cmd: &mut Command) -> Result<()> {
+  let status = cmd.stdin(Stdio::null()).status()?;
+  if status.success() {
+    Ok(())
+  } else {
+    Err(Error::Vc(format!(
+      "{} failed with {}",
+      cmd.get_program().to_string_lossy(),
+      status
+    )))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::TempDir;
+  use std::fs;
+
+  #[test]
+  fn test_parse_log() {
+    let log = Commit::parse_log("1a2b3c\tellis\t1600000000 14400\tfix: a\tb\nbad\n");
+    assert_eq!(
+      log,
+      vec![Commit {
+        rev: "1a2b3c".to_owned(),
+        author: "ellis".to_owned(),
+        time: 1600000000,
+        summary: "fix: a\tb".to_owned(),
+      }]
+    );
+  }
+
+  #[test]
+  fn test_git_backend() {
+    let root = TempDir::new("vc");
+    let work = root.join("work");
+    fs::create_dir_all(&work).unwrap();
+    let git = |args: &[&str]| {
+      Command::new("git")
+        .args(["-c", "user.name=t", "-c", "user.email=t@t"])
+        .args(args)
+        .current_dir(&work)
+        .output()
+        .unwrap()
+    };
+    assert!(detect(&work).is_none());
+    git(&["init", "-q"]);
+    fs::write(work.join("a"), "a").unwrap();
+    git(&["add", "a"]);
+    git(&["commit", "-qm", "a"]);
+
+    let b = detect(&work).unwrap();
+    assert_eq!(b.vc(), Vc::Git);
+    assert_eq!(b.changes(&work), Some(0));
+    assert!(b.rev(&work).is_some() && b.last_commit(&work).is_some());
+    git(&["commit", "-qm", "second line\n\nbody", "--allow-empty"]);
+    let log = b.log(&work, 5).unwrap();
+    assert_eq!(log.len(), 2);
+    assert_eq!(
+      (log[0].author.as_str(), log[0].summary.as_str()),
+      ("t", "second line")
+    );
+    assert_eq!(log[0].rev, b.rev(&work).unwrap());
+    fs::write(work.join("a"), "b").unwrap();
+    assert_eq!(b.is_dirty(&work), Some(true));
+
+    let copy = root.join("copy");
+    b.clone_repo(&work.to_string_lossy(), &copy).unwrap();
+    assert_eq!(Vc::detect(&copy), Some(Vc::Git));
+    assert_eq!(b.branch(&copy), b.branch(&work));
+    assert!(matches!(
+      b.clone_repo(&root.join("missing").to_string_lossy(), &root.join("x")),
+      Err(Error::Vc(_))
+    ));
+  }
+}
@@ -13,6 +13,8 @@ use tokio_stream::StreamExt;
 use tokio_util::udp::UdpFramed;
 
 mod client;
+pub mod files;
+pub mod packages;
 mod server;
 
 /// Configuration for the Web transport
@@ -0,0 +1,555 @@
+//! web/files.rs --- file sharing over HTTP
+/*!
+`shc serve --engine ftp` shares `stash/` and `store/` with the LAN. It
+isn't FTP: any browser, `curl` or `wget` can fetch files and walk the
+directory listings, which is all we used FTP for.
+
+Everything is read-only unless `--upload` is given, in which case
+`PUT /inbox/<name>` writes a new file of at most `UPLOAD_LIMIT`
+bytes into `stash/inbox`. Existing files are never replaced, not even
+by two uploads of the same name racing each other.
+
+Requests can't leave a root: `..` is refused, symlinks pointing
+outside a root aren't followed and dotfiles are neither listed nor
+served.
+*/
+use crate::{list::fmt_size, Error, Result};
+
+use futures::executor::block_on;
+use hyper::{
+  body::HttpBody,
+  header,
+  service::{make_service_fn, service_fn},
+  Body, Method, Request, Response, Server, StatusCode,
+};
+use rlib::logger::log::{error, info};
+
+use std::{
+  convert::Infallible,
+  fmt::Write as _,
+  fs::{self, OpenOptions},
+  future::Future,
+  io::{self, Read, Write},
+  net::SocketAddr,
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+use tokio::task;
+
+/// Size of the chunks files are streamed in
+const CHUNK: usize = 64 * 1024;
+
+/// Default size limit of an upload, 1GiB
+pub const UPLOAD_LIMIT: u64 = 1 << 30;
+
+/// A read-only HTTP file server over a set of named roots
+#[derive(Debug, Clone)]
+pub struct FileServer {
+  roots: Vec<(String, PathBuf)>,
+  inbox: Option<PathBuf>,
+  limit: u64,
+}
+
+impl FileServer {
+  /// Share `stash/` and `store/` of the shed at `shed`
+  pub fn new<P: AsRef<Path>>(shed: P) -> Self {
+    let shed = shed.as_ref();
+    FileServer {
+      roots: ["stash", "store"]
+        .iter()
+        .map(|r| (r.to_string(), shed.join(r)))
+        .collect(),
+      inbox: None,
+      limit: UPLOAD_LIMIT,
+    }
+  }
+
+  /// Accept uploads into `dir` with `PUT /inbox/<name>`
+  pub fn upload<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+    self.inbox = Some(dir.into());
+    self
+  }
+
+  /// Refuse uploads larger than `bytes`
+  pub fn limit(mut self, bytes: u64) -> Self {
+    self.limit = bytes;
+    self
+  }
+
+  /// Serve on `addr` until the process is stopped
+  pub async fn serve(self, addr: SocketAddr) -> Result<()> {
+    let (addr, server) = self.bind(addr)?;
+    println!("serving files on http://{}", addr);
+    server.await.map_err(Error::net)
+  }
+
+  /// Bind to `addr`, returning the bound address and the server
+  /// future
+  pub fn bind(
+    self,
+    addr: SocketAddr,
+  ) -> Result<(SocketAddr, impl Future<Output = hyper::Result<()>>)> {
+    let this = Arc::new(self);
+    let make = make_service_fn(move |_| {
+      let this = this.clone();
+      async move {
+        Ok::<_, Infallible>(service_fn(move |req| {
+          let this = this.clone();
+          async move { Ok::<_, Infallible>(this.handle(req).await) }
+        }))
+      }
+    });
+    let server = Server::try_bind(&addr).map_err(Error::net)?.serve(make);
+    Ok((server.local_addr(), server))
+  }
+
+  async fn handle(&self, req: Request<Body>) -> Response<Body> {
+    let (method, path) = (req.method().clone(), req.uri().path().to_owned());
+    let res = match (&method, self.inbox.as_ref()) {
+      (&Method::GET, _) => self.get(&path).await,
+      (&Method::PUT, Some(inbox)) if path.starts_with("/inbox/") => {
+        put(inbox, &path["/inbox/".len()..], req.into_body(), self.limit).await
+      }
+      (&Method::PUT, _) => Err(StatusCode::FORBIDDEN),
+      _ => Err(StatusCode::METHOD_NOT_ALLOWED),
+    };
+    let res = res.unwrap_or_else(|s| {
+      Response::builder()
+        .status(s)
+        .body(Body::from(format!("{}\n", s)))
+        .unwrap_or_default()
+    });
+    info!("{} {} {}", res.status().as_u16(), method, path);
+    res
+  }
+
+  async fn get(&self, path: &str) -> Result<Response<Body>, StatusCode> {
+    let segs = segments(path)?;
+    let (root, rest) = match segs.split_first() {
+      None => {
+        let names: Vec<&str> = self.roots.iter().map(|(n, _)| n.as_str()).collect();
+        return Ok(html(index(path, &names)));
+      }
+      Some(x) => x,
+    };
+    let base = self
+      .roots
+      .iter()
+      .find(|(n, _)| n == root)
+      .map(|(_, p)| p)
+      .ok_or(StatusCode::NOT_FOUND)?;
+    let target = resolve(base, rest)?;
+    if target.is_dir() {
+      Ok(html(listing(path, &target)?))
+    } else {
+      file(target)
+    }
+  }
+}
+
+/// Split a request path into decoded segments, refusing anything
+/// which could escape a root
+pub(crate) fn segments(path: &str) -> Result<Vec<String>, StatusCode> {
+  path
+    .split('/')
+    .filter(|s| !s.is_empty())
+    .map(|s| {
+      let s = decode(s).ok_or(StatusCode::BAD_REQUEST)?;
+      if s.starts_with('.') || s.contains('/') || s.contains('\\') || s.contains('\0') {
+        Err(StatusCode::NOT_FOUND)
+      } else {
+        Ok(s)
+      }
+    })
+    .collect()
+}
+
+/// Join `rest` onto `base`, making sure symlinks don't lead outside
+pub(crate) fn resolve(base: &Path, rest: &[String]) -> Result<PathBuf, StatusCode> {
+  let target = rest.iter().fold(base.to_path_buf(), |p, s| p.join(s));
+  let (base, target) = match (base.canonicalize(), target.canonicalize()) {
+    (Ok(b), Ok(t)) => (b, t),
+    _ => return Err(StatusCode::NOT_FOUND),
+  };
+  if target.starts_with(&base) {
+    Ok(target)
+  } else {
+    Err(StatusCode::NOT_FOUND)
+  }
+}
+
+/// Stream the file at `path` in `CHUNK` sized pieces
+pub(crate) fn file(path: PathBuf) -> Result<Response<Body>, StatusCode> {
+  let mut f = fs::File::open(&path).map_err(|_| StatusCode::NOT_FOUND)?;
+  let len = f.metadata().map(|m| m.len()).unwrap_or_default();
+  let mime = mime(&path);
+  let (mut tx, body) = Body::channel();
+  task::spawn_blocking(move || {
+    let mut buf = vec![0; CHUNK];
+    loop {
+      match f.read(&mut buf) {
+        Ok(0) => break,
+        Ok(n) => {
+          // the client went away
+          if block_on(tx.send_data(buf[..n].to_vec().into())).is_err() {
+            break;
+          }
+        }
+        Err(e) => {
+          error!("reading {}: {}", path.display(), e);
+          tx.abort();
+          break;
+        }
+      }
+    }
+  });
+  Response::builder()
+    .header(header::CONTENT_TYPE, mime)
+    .header(header::CONTENT_LENGTH, len)
+    .body(body)
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Write an uploaded file of at most `limit` bytes into `inbox`. The
+/// file is written under a hidden name, created exclusively so two
+/// uploads can't share it, and linked to its real name once complete,
+/// which fails rather than replace a file that showed up meanwhile. A
+/// half finished upload is never listed.
+async fn put(
+  inbox: &Path,
+  name: &str,
+  mut body: Body,
+  limit: u64,
+) -> Result<Response<Body>, StatusCode> {
+  let name = match segments(name)?.as_slice() {
+    [n] => n.clone(),
+    _ => return Err(StatusCode::BAD_REQUEST),
+  };
+  if body.size_hint().lower() > limit {
+    return Err(StatusCode::PAYLOAD_TOO_LARGE);
+  }
+  let dst = inbox.join(&name);
+  let part = inbox.join(format!(".{}.part", name));
+  if dst.exists() {
+    return Err(StatusCode::CONFLICT);
+  }
+  let failed = |e: io::Error| match e.kind() {
+    io::ErrorKind::AlreadyExists => StatusCode::CONFLICT,
+    _ => {
+      error!("upload to {}: {}", dst.display(), e);
+      StatusCode::INTERNAL_SERVER_ERROR
+    }
+  };
+  let (dir, p) = (inbox.to_path_buf(), part.clone());
+  let mut f = blocking(move || {
+    fs::create_dir_all(&dir)?;
+    OpenOptions::new().write(true).create_new(true).open(&p)
+  })
+  .await
+  .map_err(failed)?;
+  // the part file is ours from here on
+  let write = async {
+    let mut len = 0;
+    while let Some(chunk) = body.data().await {
+      // the client hung up mid-upload
+      let chunk = chunk
+        .map_err(|_| io::Error::from(io::ErrorKind::ConnectionAborted))
+        .map_err(failed)?;
+      len += chunk.len() as u64;
+      if len > limit {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+      }
+      f = blocking(move || f.write_all(&chunk).map(|_| f))
+        .await
+        .map_err(failed)?;
+    }
+    let (p, d) = (part.clone(), dst.clone());
+    blocking(move || {
+      f.sync_all()?;
+      fs::hard_link(&p, &d)
+    })
+    .await
+    .map_err(failed)
+  };
+  let res = write.await;
+  let _ = blocking(move || fs::remove_file(&part)).await;
+  res?;
+  Response::builder()
+    .status(StatusCode::CREATED)
+    .body(Body::from(format!("/stash/inbox/{}\n", encode(&name))))
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Run the file IO in `f` on the blocking pool
+async fn blocking<T, F>(f: F) -> io::Result<T>
+where
+  T: Send + 'static,
+  F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+  task::spawn_blocking(f).await?
+}
+
+pub(crate) fn html(page: String) -> Response<Body> {
+  Response::builder()
+    .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+    .body(Body::from(page))
+    .unwrap_or_default()
+}
+
+/// A page of links to `names` below `path`
+pub(crate) fn index(path: &str, names: &[&str]) -> String {
+  let mut rows = String::new();
+  for n in names {
+    let _ = writeln!(
+      rows,
+      "<li><a href=\"{}\">{}/</a></li>",
+      href(path, n),
+      escape(n)
+    );
+  }
+  page(path, &format!("<ul>\n{}</ul>", rows))
+}
+
+/// A page listing the directory `dir`, directories first
+pub(crate) fn listing(path: &str, dir: &Path) -> Result<String, StatusCode> {
+  let mut entries: Vec<(bool, String, u64)> = fs::read_dir(dir)
+    .map_err(|_| StatusCode::NOT_FOUND)?
+    .filter_map(|e| {
+      let e = e.ok()?;
+      let name = e.file_name().into_string().ok()?;
+      if name.starts_with('.') {
+        return None;
+      }
+      let m = fs::metadata(e.path()).ok()?;
+      Some((!m.is_dir(), name, m.len()))
+    })
+    .collect();
+  entries.sort();
+  let mut rows = String::new();
+  let here = path.trim_end_matches('/');
+  if let Some(i) = here.rfind('/') {
+    let up = if i == 0 { "/" } else { &here[..i] };
+    let _ = writeln!(
+      rows,
+      "<tr><td><a href=\"{}\">../</a></td><td></td></tr>",
+      escape(up)
+    );
+  }
+  for (file, name, len) in entries {
+    let (slash, size) = if file {
+      ("", fmt_size(len))
+    } else {
+      ("/", String::new())
+    };
+    let _ = writeln!(
+      rows,
+      "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td></tr>",
+      href(path, &name),
+      slash,
+      escape(&name),
+      slash,
+      size
+    );
+  }
+  Ok(page(path, &format!("<table>\n{}</table>", rows)))
+}
+
+pub(crate) fn page(title: &str, body: &str) -> String {
+  format!(
+    "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body><h1>{0}</h1>\n{1}\n</body></html>\n",
+    escape(title),
+    body
+  )
+}
+
+/// Absolute link to `name` inside the directory at `path`
+pub(crate) fn href(path: &str, name: &str) -> String {
+  format!("{}/{}", escape(path.trim_end_matches('/')), encode(name))
+}
+
+pub(crate) fn escape(s: &str) -> String {
+  let mut out = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '&' => out.push_str("&amp;"),
+      '<' => out.push_str("&lt;"),
+      '>' => out.push_str("&gt;"),
+      '"' => out.push_str("&quot;"),
+      '\'' => out.push_str("&#39;"),
+      c => out.push(c),
+    }
+  }
+  out
+}
+
+/// Percent-encode everything but unreserved characters
+pub(crate) fn encode(s: &str) -> String {
+  let mut out = String::with_capacity(s.len());
+  for b in s.bytes() {
+    match b {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(b as char),
+      b => {
+        let _ = write!(out, "%{:02X}", b);
+      }
+    }
+  }
+  out
+}
+
+/// Percent-decode `s`, `None` when it isn't valid UTF-8 afterwards
+pub(crate) fn decode(s: &str) -> Option<String> {
+  let b = s.as_bytes();
+  let mut out = Vec::with_capacity(b.len());
+  let mut i = 0;
+  while i < b.len() {
+    if b[i] == b'%' && i + 2 < b.len() {
+      let hex = std::str::from_utf8(&b[i + 1..i + 3]).ok()?;
+      out.push(u8::from_str_radix(hex, 16).ok()?);
+      i += 3;
+    } else {
+      out.push(b[i]);
+      i += 1;
+    }
+  }
+  String::from_utf8(out).ok()
+}
+
+/// Content type from the file extension
+pub(crate) fn mime(path: &Path) -> &'static str {
+  match path
+    .extension()
+    .and_then(|e| e.to_str())
+    .map(|e| e.to_ascii_lowercase())
+    .as_deref()
+  {
+    Some("html") | Some("htm") => "text/html; charset=utf-8",
+    Some("txt") | Some("org") | Some("md") | Some("rs") | Some("el") | Some("ron") => {
+      "text/plain; charset=utf-8"
+    }
+    Some("css") => "text/css",
+    Some("js") => "text/javascript",
+    Some("json") => "application/json",
+    Some("pdf") => "application/pdf",
+    Some("png") => "image/png",
+    Some("jpg") | Some("jpeg") => "image/jpeg",
+    Some("gif") => "image/gif",
+    Some("svg") => "image/svg+xml",
+    Some("mp3") => "audio/mpeg",
+    Some("wav") => "audio/wav",
+    Some("flac") => "audio/flac",
+    _ => "application/octet-stream",
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{get, TempDir};
+  use hyper::Client;
+
+  #[test]
+  fn test_segments() {
+    assert_eq!(segments("/stash/a%20b/c").unwrap(), ["stash", "a b", "c"]);
+    assert!(segments("/stash/../etc").is_err());
+    assert!(segments("/stash/%2e%2e/etc").is_err());
+    assert!(segments("/stash/a%2fb").is_err());
+    assert!(segments("/stash/.hg").is_err());
+    assert_eq!(encode("a b/ü"), "a%20b%2F%C3%BC");
+    assert_eq!(decode(&encode("a b/ü")).unwrap(), "a b/ü");
+    assert_eq!(escape("<a href='x'>"), "&lt;a href=&#39;x&#39;&gt;");
+  }
+
+  #[tokio::test]
+  async fn test_file_server() {
+    let root = TempDir::new("files");
+    fs::create_dir_all(root.join("stash/docs")).unwrap();
+    fs::create_dir_all(root.join("store")).unwrap();
+    fs::write(root.join("stash/docs/a <b>.txt"), "hello").unwrap();
+    fs::write(root.join("stash/.secret"), "no").unwrap();
+    fs::write(root.join("outside"), "no").unwrap();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(root.join("outside"), root.join("store/link")).unwrap();
+
+    let put = |srv: SocketAddr, path: &str, body: &'static str| {
+      let req = Request::put(format!("http://{}{}", srv, path))
+        .body(Body::from(body))
+        .unwrap();
+      async move { Client::new().request(req).await.unwrap().status() }
+    };
+
+    let (ro, server) = FileServer::new(&root)
+      .bind(([127, 0, 0, 1], 0).into())
+      .unwrap();
+    tokio::spawn(server);
+    let (s, body) = get(ro, "/").await;
+    assert_eq!(s, StatusCode::OK);
+    assert!(body.contains("href=\"/stash\"") && body.contains("href=\"/store\""));
+    let (_, body) = get(ro, "/stash/docs/").await;
+    assert!(body.contains("href=\"/stash/docs/a%20%3Cb%3E.txt\">a &lt;b&gt;.txt</a>"));
+    assert!(body.contains("href=\"/stash\">../</a>"));
+    assert_eq!(
+      get(ro, "/stash/docs/a%20%3Cb%3E.txt").await,
+      (StatusCode::OK, "hello".to_owned())
+    );
+    assert!(!get(ro, "/stash").await.1.contains("secret"));
+    assert_eq!(get(ro, "/stash/.secret").await.0, StatusCode::NOT_FOUND);
+    assert_eq!(get(ro, "/store/link").await.0, StatusCode::NOT_FOUND);
+    assert_eq!(get(ro, "/etc/passwd").await.0, StatusCode::NOT_FOUND);
+    assert_eq!(put(ro, "/inbox/x", "x").await, StatusCode::FORBIDDEN);
+
+    let inbox = root.join("stash/inbox");
+    let (rw, server) = FileServer::new(&root)
+      .upload(&inbox)
+      .bind(([127, 0, 0, 1], 0).into())
+      .unwrap();
+    tokio::spawn(server);
+    assert_eq!(put(rw, "/inbox/up.txt", "up").await, StatusCode::CREATED);
+    assert_eq!(fs::read_to_string(inbox.join("up.txt")).unwrap(), "up");
+    assert_eq!(
+      put(rw, "/inbox/up.txt", "again").await,
+      StatusCode::CONFLICT
+    );
+    assert_eq!(put(rw, "/inbox/..%2Fx", "x").await, StatusCode::NOT_FOUND);
+    assert_eq!(put(rw, "/stash/x", "x").await, StatusCode::FORBIDDEN);
+    // an upload of the same name is still running
+    fs::write(inbox.join(".busy.txt.part"), "").unwrap();
+    assert_eq!(put(rw, "/inbox/busy.txt", "x").await, StatusCode::CONFLICT);
+    assert!(inbox.join(".busy.txt.part").exists());
+    assert!(!inbox.join("busy.txt").exists());
+
+    let (small, server) = FileServer::new(&root)
+      .upload(&inbox)
+      .limit(4)
+      .bind(([127, 0, 0, 1], 0).into())
+      .unwrap();
+    tokio::spawn(server);
+    assert_eq!(
+      put(small, "/inbox/4.txt", "four").await,
+      StatusCode::CREATED
+    );
+    assert_eq!(
+      put(small, "/inbox/5.txt", "fives").await,
+      StatusCode::PAYLOAD_TOO_LARGE
+    );
+    // without a length the limit applies while streaming
+    let (mut tx, body) = Body::channel();
+    let req = Request::put(format!("http://{}/inbox/big.txt", small))
+      .body(body)
+      .unwrap();
+    let res = tokio::spawn(Client::new().request(req));
+    tx.send_data("abc".into()).await.unwrap();
+    let _ = tx.send_data("def".into()).await;
+    drop(tx);
+    assert_eq!(
+      res.await.unwrap().unwrap().status(),
+      StatusCode::PAYLOAD_TOO_LARGE
+    );
+    let names: Vec<_> = fs::read_dir(&inbox)
+      .unwrap()
+      .map(|e| e.unwrap().file_name().into_string().unwrap())
+      .filter(|n| !n.starts_with(".busy"))
+      .collect();
+    assert_eq!(names.len(), 2);
+    assert!(!inbox.join("5.txt").exists() && !inbox.join("big.txt").exists());
+  }
+}
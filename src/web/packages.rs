@@ -0,0 +1,471 @@
+//! web/packages.rs --- share a few packages over HTTP
+/*!
+`shc serve -p foo bar` exposes only the named `Config.src` packages.
+Each package gets a page with its rendered readme, recent history and
+a `.tz` snapshot of its directory packed on request, leaving out what
+its VC ignores along with `.hg` and `.git`. Mercurial repos
+also link to their hgweb view, which `App::serve` runs next to this
+server; git repos make do with the history page.
+
+Readmes in org or markdown are rendered from a small subset of
+either: headings, lists, paragraphs and code blocks. Anything else is
+shown as plain text.
+*/
+use crate::{
+  archive::{self, Filter, Format},
+  vc::{self, Commit, Vc},
+  web::files::{encode, escape, file, html, index, page, segments},
+  Error, Result,
+};
+
+use hyper::{
+  header,
+  service::{make_service_fn, service_fn},
+  Body, Method, Request, Response, Server, StatusCode,
+};
+use rlib::logger::log::{error, info};
+
+use std::{
+  convert::Infallible,
+  fmt::Write as _,
+  fs,
+  future::Future,
+  net::SocketAddr,
+  path::{Path, PathBuf},
+  process,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+};
+use tokio::task;
+
+/// Number of commits on a package's history page
+const LOG_LEN: usize = 50;
+
+/// A package to share
+#[derive(Debug, Clone)]
+pub struct Package {
+  pub name: String,
+  pub dir: PathBuf,
+}
+
+/// Serves a fixed set of packages
+#[derive(Debug)]
+pub struct PackageServer {
+  pkgs: Vec<Package>,
+  /// where snapshots are packed before they are sent
+  tmp: PathBuf,
+  /// port hgweb listens on, on the same host as this server
+  hgweb: Option<u16>,
+  snapshots: AtomicUsize,
+}
+
+impl PackageServer {
+  /// Serve `pkgs`, packing snapshots in `tmp`
+  pub fn new<P: Into<PathBuf>>(pkgs: Vec<Package>, tmp: P) -> Self {
+    PackageServer {
+      pkgs,
+      tmp: tmp.into(),
+      hgweb: None,
+      snapshots: AtomicUsize::new(0),
+    }
+  }
+
+  /// Link Mercurial packages to hgweb on `port`
+  pub fn hgweb(mut self, port: u16) -> Self {
+    self.hgweb = Some(port);
+    self
+  }
+
+  /// Serve on `addr` until the process is stopped
+  pub async fn serve(self, addr: SocketAddr) -> Result<()> {
+    let (addr, server) = self.bind(addr)?;
+    println!("serving packages on http://{}", addr);
+    server.await.map_err(Error::net)
+  }
+
+  /// Bind to `addr`, returning the bound address and the server
+  /// future
+  pub fn bind(
+    self,
+    addr: SocketAddr,
+  ) -> Result<(SocketAddr, impl Future<Output = hyper::Result<()>>)> {
+    let this = Arc::new(self);
+    let make = make_service_fn(move |_| {
+      let this = this.clone();
+      async move {
+        Ok::<_, Infallible>(service_fn(move |req| {
+          let this = this.clone();
+          async move { Ok::<_, Infallible>(this.handle(req).await) }
+        }))
+      }
+    });
+    let server = Server::try_bind(&addr).map_err(Error::net)?.serve(make);
+    Ok((server.local_addr(), server))
+  }
+
+  async fn handle(&self, req: Request<Body>) -> Response<Body> {
+    let path = req.uri().path().to_owned();
+    let res = if req.method() == Method::GET {
+      self.get(&req, &path).await
+    } else {
+      Err(StatusCode::METHOD_NOT_ALLOWED)
+    };
+    let res = res.unwrap_or_else(|s| {
+      Response::builder()
+        .status(s)
+        .body(Body::from(format!("{}\n", s)))
+        .unwrap_or_default()
+    });
+    info!("{} GET {}", res.status().as_u16(), path);
+    res
+  }
+
+  async fn get(&self, req: &Request<Body>, path: &str) -> Result<Response<Body>, StatusCode> {
+    let segs = segments(path)?;
+    let segs: Vec<&str> = segs.iter().map(String::as_str).collect();
+    let find = |name: &str| {
+      self
+        .pkgs
+        .iter()
+        .find(|p| p.name == name)
+        .ok_or(StatusCode::NOT_FOUND)
+    };
+    match segs.as_slice() {
+      [] => {
+        let names: Vec<&str> = self.pkgs.iter().map(|p| p.name.as_str()).collect();
+        Ok(html(index(path, &names)))
+      }
+      [n] if n.ends_with(".tz") => self.snapshot(find(&n[..n.len() - 3])?).await,
+      [n] => {
+        let host = req
+          .headers()
+          .get(header::HOST)
+          .and_then(|h| h.to_str().ok())
+          .map(|h| h.rsplit_once(':').map_or(h, |(h, _)| h))
+          .unwrap_or("localhost");
+        Ok(html(self.package(find(n)?, host)))
+      }
+      [n, "log"] => Ok(html(history(find(n)?))),
+      _ => Err(StatusCode::NOT_FOUND),
+    }
+  }
+
+  fn package(&self, p: &Package, host: &str) -> String {
+    let b = vc::detect(&p.dir);
+    let name = encode(&p.name);
+    let mut links = vec![
+      format!("<a href=\"/{}.tz\">snapshot</a>", name),
+      format!("<a href=\"/{}/log\">history</a>", name),
+    ];
+    if let (Some(b), Some(port)) = (b, self.hgweb) {
+      if b.vc() == Vc::Hg {
+        links.push(format!(
+          "<a href=\"http://{}:{}/{}/\">hgweb</a>",
+          escape(host),
+          port,
+          name
+        ));
+      }
+    }
+    let about = match b {
+      Some(b) => format!(
+        "<p>{} {} {}</p>\n",
+        b.vc(),
+        escape(&b.branch(&p.dir).unwrap_or_default()),
+        escape(&b.rev(&p.dir).unwrap_or_default())
+      ),
+      None => String::new(),
+    };
+    let readme = readme(&p.dir).unwrap_or_default();
+    page(
+      &p.name,
+      &format!("{}<p>{}</p>\n{}", about, links.join(" | "), readme),
+    )
+  }
+
+  /// Pack the package directory and send it. The file is removed as
+  /// soon as it is open, so nothing is left behind in `tmp`.
+  async fn snapshot(&self, p: &Package) -> Result<Response<Body>, StatusCode> {
+    let n = self.snapshots.fetch_add(1, Ordering::SeqCst);
+    let out = self
+      .tmp
+      .join(format!(".{}-{}-{}.tz", p.name, process::id(), n));
+    fs::create_dir_all(&self.tmp).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let (i, o) = (p.dir.clone(), out.clone());
+    let packed =
+      task::spawn_blocking(move || archive::pack(&i, &o, Format::Tz, &Filter::default()))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let Err(e) = packed {
+      error!("packing {}: {}", p.dir.display(), e);
+      let _ = fs::remove_file(&out);
+      return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    let res = file(out.clone());
+    if let Err(e) = fs::remove_file(&out) {
+      error!("removing {}: {}", out.display(), e);
+    }
+    let mut res = res?;
+    if let Ok(v) = format!("attachment; filename=\"{}.tz\"", name_safe(&p.name)).parse() {
+      res.headers_mut().insert(header::CONTENT_DISPOSITION, v);
+    }
+    Ok(res)
+  }
+}
+
+/// The history page of `p`
+fn history(p: &Package) -> String {
+  let log: Vec<Commit> = vc::detect(&p.dir)
+    .and_then(|b| b.log(&p.dir, LOG_LEN))
+    .unwrap_or_default();
+  let mut rows = String::new();
+  for c in log {
+    let _ = writeln!(
+      rows,
+      "<tr><td><code>{}</code></td><td>{}</td><td>{}</td><td>{}</td></tr>",
+      escape(&c.rev),
+      date(c.time),
+      escape(&c.author),
+      escape(&c.summary)
+    );
+  }
+  page(
+    &format!("{} history", p.name),
+    &format!(
+      "<p><a href=\"/{}/\">back</a></p>\n<table>\n{}</table>",
+      encode(&p.name),
+      rows
+    ),
+  )
+}
+
+/// `secs` since the epoch as a UTC `YYYY-MM-DD HH:MM`
+fn date(secs: u64) -> String {
+  // civil from days, http://howardhinnant.github.io/date_algorithms.html
+  let z = (secs / 86400) as i64 + 719468;
+  let era = z / 146097;
+  let doe = z - era * 146097;
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let d = doy - (153 * mp + 2) / 5 + 1;
+  let m = if mp < 10 { mp + 3 } else { mp - 9 };
+  let y = yoe + era * 400 + i64::from(m <= 2);
+  let s = secs % 86400;
+  format!(
+    "{}-{:02}-{:02} {:02}:{:02}",
+    y,
+    m,
+    d,
+    s / 3600,
+    s % 3600 / 60
+  )
+}
+
+/// `name` with anything which would break a quoted header value
+/// replaced
+fn name_safe(name: &str) -> String {
+  name
+    .chars()
+    .map(|c| {
+      if c.is_ascii_graphic() && c != '"' {
+        c
+      } else {
+        '_'
+      }
+    })
+    .collect()
+}
+
+/// Markup understood by `render`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Markup {
+  Org,
+  Markdown,
+  Plain,
+}
+
+/// Find and render the readme in `dir`
+pub fn readme(dir: &Path) -> Option<String> {
+  let path = fs::read_dir(dir)
+    .ok()?
+    .filter_map(|e| e.ok().map(|e| e.path()))
+    .filter(|p| p.is_file())
+    .find(|p| matches!(p.file_stem().and_then(|s| s.to_str()), Some(s) if s.eq_ignore_ascii_case("readme")))?;
+  let markup = match path
+    .extension()
+    .and_then(|e| e.to_str())
+    .map(|e| e.to_ascii_lowercase())
+    .as_deref()
+  {
+    Some("org") => Markup::Org,
+    Some("md") | Some("markdown") => Markup::Markdown,
+    _ => Markup::Plain,
+  };
+  Some(render(&fs::read_to_string(path).ok()?, markup))
+}
+
+/// Render `text` as HTML
+pub fn render(text: &str, markup: Markup) -> String {
+  if markup == Markup::Plain {
+    return format!("<pre>{}</pre>\n", escape(text));
+  }
+  let mut out = String::new();
+  let mut para: Vec<&str> = vec![];
+  let mut list = false;
+  let mut code = false;
+  let flush = |out: &mut String, para: &mut Vec<&str>, list: &mut bool| {
+    if !para.is_empty() {
+      let _ = writeln!(out, "<p>{}</p>", escape(&para.join(" ")));
+      para.clear();
+    }
+    if *list {
+      out.push_str("</ul>\n");
+      *list = false;
+    }
+  };
+  for line in text.lines() {
+    let t = line.trim();
+    let lower = t.to_ascii_lowercase();
+    let fence = match markup {
+      Markup::Org => lower.starts_with("#+begin_") || lower.starts_with("#+end_"),
+      _ => t.starts_with("```"),
+    };
+    if fence {
+      if code {
+        out.push_str("</pre>\n");
+      } else {
+        flush(&mut out, &mut para, &mut list);
+        out.push_str("<pre>");
+      }
+      code = !code;
+      continue;
+    }
+    if code {
+      let _ = writeln!(out, "{}", escape(line));
+      continue;
+    }
+    let marker = if markup == Markup::Org { '*' } else { '#' };
+    let level = line.chars().take_while(|c| *c == marker).count();
+    let item = t
+      .strip_prefix("- ")
+      .or_else(|| t.strip_prefix("+ "))
+      .or_else(|| t.strip_prefix("* ").filter(|_| level == 0));
+    if level > 0 && line[level..].starts_with(' ') {
+      flush(&mut out, &mut para, &mut list);
+      let _ = writeln!(
+        out,
+        "<h{0}>{1}</h{0}>",
+        (level + 1).min(6),
+        escape(line[level..].trim())
+      );
+    } else if let Some(i) = item {
+      if !para.is_empty() {
+        let _ = writeln!(out, "<p>{}</p>", escape(&para.join(" ")));
+        para.clear();
+      }
+      if !list {
+        out.push_str("<ul>\n");
+        list = true;
+      }
+      let _ = writeln!(out, "<li>{}</li>", escape(i));
+    } else if t.is_empty() || (markup == Markup::Org && t.starts_with("#+")) {
+      flush(&mut out, &mut para, &mut list);
+    } else if list && line.starts_with(' ') {
+      // continuation of a list item
+      let _ = writeln!(out, "{}", escape(t));
+    } else {
+      if list {
+        out.push_str("</ul>\n");
+        list = false;
+      }
+      para.push(t);
+    }
+  }
+  if code {
+    out.push_str("</pre>\n");
+  }
+  flush(&mut out, &mut para, &mut list);
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    archive::Overwrite,
+    testing::{fetch, get, TempDir},
+  };
+
+  #[test]
+  fn test_render() {
+    let org = "#+title: shed\n* shed\nA shed\nfor tools.\n- one\n- two\n** code\n#+begin_src rust\nfn a<T>() {}\n#+end_src\n";
+    assert_eq!(
+      render(org, Markup::Org),
+      "<h2>shed</h2>\n<p>A shed for tools.</p>\n<ul>\n<li>one</li>\n<li>two</li>\n</ul>\n<h3>code</h3>\n<pre>fn a&lt;T&gt;() {}\n</pre>\n"
+    );
+    let md = "# shed\n\n* one\n\n```\n# not a heading\n```\n";
+    assert_eq!(
+      render(md, Markup::Markdown),
+      "<h2>shed</h2>\n<ul>\n<li>one</li>\n</ul>\n<pre># not a heading\n</pre>\n"
+    );
+    assert_eq!(render("<b>", Markup::Plain), "<pre>&lt;b&gt;</pre>\n");
+  }
+
+  #[test]
+  fn test_date() {
+    assert_eq!(date(0), "1970-01-01 00:00");
+    assert_eq!(date(951_827_696), "2000-02-29 12:34");
+    assert_eq!(date(1_600_000_000), "2020-09-13 12:26");
+  }
+
+  #[tokio::test]
+  async fn test_package_server() {
+    let root = TempDir::new("pkgs");
+    let dir = root.join("src/foo");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("readme.org"), "* foo\nhello").unwrap();
+    fs::create_dir_all(dir.join(".git")).unwrap();
+    fs::create_dir_all(dir.join("target")).unwrap();
+    fs::write(dir.join(".git/HEAD"), "").unwrap();
+    fs::write(dir.join(".gitignore"), "target\n").unwrap();
+    fs::write(dir.join("target/foo"), "").unwrap();
+    let pkgs = vec![Package {
+      name: "foo".to_owned(),
+      dir,
+    }];
+    let (addr, server) = PackageServer::new(pkgs, root.join("stash/tmp"))
+      .bind(([127, 0, 0, 1], 0).into())
+      .unwrap();
+    tokio::spawn(server);
+    assert!(get(addr, "/").await.1.contains("<a href=\"/foo\">foo/</a>"));
+    let (s, body) = get(addr, "/foo/").await;
+    assert_eq!(s, StatusCode::OK);
+    assert!(body.contains("<h2>foo</h2>\n<p>hello</p>"));
+    assert!(body.contains("href=\"/foo.tz\""));
+    assert_eq!(get(addr, "/foo/log").await.0, StatusCode::OK);
+    assert_eq!(get(addr, "/bar/").await.0, StatusCode::NOT_FOUND);
+    assert_eq!(get(addr, "/bar.tz").await.0, StatusCode::NOT_FOUND);
+
+    let res = fetch(addr, "/foo.tz").await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+      res.headers()[header::CONTENT_DISPOSITION],
+      "attachment; filename=\"foo.tz\""
+    );
+    let tz = root.join("foo.tz");
+    fs::write(&tz, res.body()).unwrap();
+    assert!(archive::verify(&tz, Format::Tz).unwrap() > 0);
+    let out = root.join("out");
+    archive::unpack(&tz, &out, Format::Tz, Overwrite::Never).unwrap();
+    assert_eq!(
+      fs::read_to_string(out.join("foo/readme.org")).unwrap(),
+      "* foo\nhello"
+    );
+    assert!(!out.join("foo/.git").exists());
+    assert!(!out.join("foo/target").exists());
+    assert_eq!(fs::read_dir(root.join("stash/tmp")).unwrap().count(), 0);
+  }
+}